@@ -1,8 +1,14 @@
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
 use std::rc::Rc;
-use std::time::Duration;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 mod toy;
 
+use toy::prelude::*;
+
 async fn test_single_sleep(rt: Rc<toy::Runtime>) {
     println!("\ntest_single_sleep: single sleep event 1sec");
     toy::sleep(&rt, Duration::from_millis(1000)).await;
@@ -17,48 +23,52 @@ async fn test_single_nested(rt: Rc<toy::Runtime>) {
 
 async fn test_join_tree(rt: Rc<toy::Runtime>) {
     println!("\ntest_join_tree: run several task with nested loops");
+    // the tasks take the runtime from toy::current() rather than from the parameters
+    assert!(Rc::ptr_eq(toy::current().runtime(), &rt));
 
-    async fn task_tree1(rt: Rc<toy::Runtime>) {
+    async fn task_tree1() {
         println!("task_tree1 started");
-        toy::make_rt_join2(&rt, task_1(rt.clone()), task_2(rt.clone())).await;
+        toy::current().make_rt_join2(task_1(), task_2()).await;
         println!("task_tree1 done");
     }
 
-    async fn task_tree2(rt: Rc<toy::Runtime>) {
+    async fn task_tree2() {
         println!("task_tree2 started");
-        toy::make_rt_join2(&rt, task_3(rt.clone()), task_4(rt.clone())).await;
+        toy::current().make_rt_join2(task_3(), task_4()).await;
         println!("task_tree2 done");
     }
 
-    async fn task_1(rt: Rc<toy::Runtime>) {
+    async fn task_1() {
         println!("task_1 started");
-        toy::sleep(&rt, Duration::from_millis(1000)).await;
+        toy::current().sleep(Duration::from_millis(1000)).await;
         println!("task_1 sleep done, starting nested loop");
-        rt.nested_loop(toy::sleep(&rt, Duration::from_millis(1000)));
+        let rt = toy::current();
+        rt.nested_loop(rt.sleep(Duration::from_millis(1000)));
         println!("task_1 nested loop done");
     }
 
-    async fn task_2(rt: Rc<toy::Runtime>) {
+    async fn task_2() {
         println!("task_2 started");
-        toy::sleep(&rt, Duration::from_millis(2000)).await;
+        toy::current().sleep(Duration::from_millis(2000)).await;
         println!("task_2 done");
     }
 
-    async fn task_3(rt: Rc<toy::Runtime>) {
+    async fn task_3() {
         println!("task_3 started");
-        toy::sleep(&rt, Duration::from_millis(3000)).await;
+        toy::current().sleep(Duration::from_millis(3000)).await;
         println!("task_3 done");
     }
 
-    async fn task_4(rt: Rc<toy::Runtime>) {
+    async fn task_4() {
         println!("task_4 started");
-        toy::sleep(&rt, Duration::from_millis(4000)).await;
+        toy::current().sleep(Duration::from_millis(4000)).await;
         println!("task_4 sleep done, starting nested loop");
-        rt.nested_loop(toy::sleep(&rt, Duration::from_millis(1000)));
+        let rt = toy::current();
+        rt.nested_loop(rt.sleep(Duration::from_millis(1000)));
         println!("task_4 nested loop done");
     }
 
-    toy::make_rt_join2(&rt, task_tree1(rt.clone()), task_tree2(rt.clone())).await;
+    toy::make_rt_join2(&rt, task_tree1(), task_tree2()).await;
 }
 
 async fn test_nested_loop_tree(rt: Rc<toy::Runtime>) {
@@ -68,8 +78,28 @@ async fn test_nested_loop_tree(rt: Rc<toy::Runtime>) {
     println!("test_nested_loop_tree: done");
 }
 
+async fn test_deep_task_chain(rt: Rc<toy::Runtime>) {
+    println!("\ntest_deep_task_chain: the event of the task with 5000 ancestors");
+
+    // Every level is the parent task of the next one, the leaf sleeps
+    fn chain(rt: Rc<toy::Runtime>, depth: u32) -> Pin<Box<dyn Future<Output = u32>>> {
+        Box::pin(async move {
+            if depth == 0 {
+                toy::sleep(&rt, Duration::from_millis(10)).await;
+                return 0;
+            }
+            let (below, ()) = toy::make_rt_join2(&rt, chain(rt.clone(), depth - 1), async {}).await;
+            below + 1
+        })
+    }
+
+    assert_eq!(chain(rt.clone(), 5000).await, 5000);
+    println!("test_deep_task_chain: done");
+}
+
 async fn test_frozen_events(rt: Rc<toy::Runtime>) {
     println!("\ntest_frozen_events: nested loops and frozen events");
+    rt.set_observer(Box::new(toy::StdoutObserver));
     async fn task_a(rt: Rc<toy::Runtime>) {
         println!("task_a started");
         toy::sleep(&rt, Duration::from_millis(2000)).await;
@@ -88,10 +118,3157 @@ async fn test_frozen_events(rt: Rc<toy::Runtime>) {
     println!("test_frozen_events: done");
 }
 
+async fn test_analyze_frozen(rt: Rc<toy::Runtime>) {
+    println!("\ntest_analyze_frozen: report the events parked for a frozen task");
+    let alerts: Rc<RefCell<Vec<Vec<toy::FrozenReport>>>> = Rc::new(RefCell::new(Vec::new()));
+    let alerts_clone = alerts.clone();
+    rt.set_frozen_alert(1, Duration::from_secs(60), move |reports| {
+        alerts_clone.borrow_mut().push(reports.to_vec());
+    });
+
+    async fn short_sleep(rt: Rc<toy::Runtime>) {
+        toy::sleep(&rt, Duration::from_millis(50)).await;
+    }
+
+    // Returns the event that has been parked
+    async fn long_cleanup(rt: Rc<toy::Runtime>) -> Option<toy::EventId> {
+        let mut parked = None;
+        rt.nested_loop(async {
+            toy::sleep(&rt, Duration::from_millis(150)).await;
+            // the event of short_sleep() is parked since 50ms
+            let reports = rt.analyze_frozen();
+            println!("{:#?}", reports);
+            assert_eq!(reports.len(), 1);
+            assert!(reports[0].task().contains("test_analyze_frozen"));
+            assert!(reports[0].frozen_by().contains("test_analyze_frozen"));
+            assert!(reports[0].parked_for() >= Duration::from_millis(90));
+            assert_eq!(reports[0].depth(), 1);
+            assert!(reports[0].replay_attempts() >= 1);
+            parked = Some(reports[0].event_id());
+        });
+        parked
+    }
+
+    let ((), parked) = toy::make_join2(short_sleep(rt.clone()), long_cleanup(rt.clone())).await;
+
+    assert_eq!(rt.frozen_event_count(), 0);
+    let alerts = alerts.borrow();
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0].len(), 1);
+    assert_eq!(Some(alerts[0][0].event_id()), parked);
+    assert_eq!(alerts[0][0].replay_attempts(), 0);
+    println!("test_analyze_frozen: done");
+}
+
+async fn test_timeout(rt: Rc<toy::Runtime>) {
+    println!("\ntest_timeout: 200ms sleep with 50ms budget");
+    let started_on = Instant::now();
+    let result = toy::timeout(&rt, Duration::from_millis(50), async {
+        toy::sleep(&rt, Duration::from_millis(200)).await;
+        "slept"
+    })
+    .await;
+    assert_eq!(result, Err(toy::Elapsed));
+    assert!(started_on.elapsed() < Duration::from_millis(150));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    let result = toy::timeout(&rt, Duration::from_millis(200), async { "ready" }).await;
+    assert_eq!(result, Ok("ready"));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // The inner future is ready by the time the timer fires, though its own timer is later: the
+    // inner one wins the tie.
+    let deadline = Instant::now() + Duration::from_millis(100);
+    let mut own_timer = Box::pin(toy::sleep_until(&rt, deadline + Duration::from_millis(50)));
+    let inner = std::future::poll_fn(|ctx| match Instant::now() >= deadline {
+        true => Poll::Ready("inner"),
+        false => own_timer.as_mut().poll(ctx).map(|()| "own timer"),
+    });
+    let result = toy::timeout(&rt, Duration::from_millis(100), inner).await;
+    assert_eq!(result, Ok("inner"));
+    drop(own_timer);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_timeout: done");
+}
+
+async fn test_zero_sleep(rt: Rc<toy::Runtime>) {
+    println!("\ntest_zero_sleep: zero sleep yields once with a timer that is due right away");
+    let mut sleep = Box::pin(toy::sleep(&rt, Duration::ZERO));
+    let poll = std::future::poll_fn(|ctx| Poll::Ready(sleep.as_mut().poll(ctx))).await;
+    assert!(poll.is_pending());
+    assert_eq!(rt.reactor().timer_count(), 1);
+    sleep.await;
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    let started_on = Instant::now();
+    for _ in 0..1000 {
+        toy::sleep(&rt, Duration::ZERO).await;
+    }
+    assert!(started_on.elapsed() < Duration::from_millis(100));
+    assert_eq!(rt.reactor().peek_next_deadline(), None);
+
+    // two tasks in the loops of zero sleeps take turns, neither starves the other
+    let log = RefCell::new(Vec::new());
+    let worker = |name| {
+        let rt = &rt;
+        let log = &log;
+        async move {
+            for step in 0..3 {
+                log.borrow_mut().push(format!("{}{}", name, step));
+                toy::sleep(rt, Duration::ZERO).await;
+            }
+        }
+    };
+    toy::make_rt_join2(&rt, worker("a"), worker("b")).await;
+    let log = log.into_inner();
+    println!("{:?}", log);
+    assert_eq!(log, ["a0", "b0", "a1", "b1", "a2", "b2"]);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_zero_sleep: done");
+}
+
+async fn test_yield_now(rt: Rc<toy::Runtime>) {
+    println!("\ntest_yield_now: two tasks yielding in turns interleave");
+    let log = RefCell::new(Vec::new());
+    let worker = |name| {
+        let rt = &rt;
+        let log = &log;
+        async move {
+            for step in 0..3 {
+                log.borrow_mut().push(format!("{}{}", name, step));
+                toy::yield_now(rt).await;
+            }
+        }
+    };
+    toy::make_rt_join2(&rt, worker("a"), worker("b")).await;
+    let log = log.into_inner();
+    println!("{:?}", log);
+    assert_eq!(log, ["a0", "b0", "a1", "b1", "a2", "b2"]);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_yield_now: done");
+}
+
+async fn test_sleep_reset(rt: Rc<toy::Runtime>) {
+    println!("\ntest_sleep_reset: the pending sleep is extended twice, the done one is re-armed");
+    let started_on = Instant::now();
+    let mut sleep = toy::Sleep::new(&rt, Duration::from_millis(100));
+    assert_eq!(sleep.deadline(), None);
+    let poll = std::future::poll_fn(|ctx| Poll::Ready(Pin::new(&mut sleep).poll(ctx))).await;
+    assert!(poll.is_pending());
+    let first = sleep.deadline().unwrap();
+    assert!(first - started_on >= Duration::from_millis(100));
+
+    sleep.reset(first + Duration::from_millis(100));
+    sleep.reset(first + Duration::from_millis(200));
+    assert_eq!(rt.reactor().timer_count(), 1);
+    assert!(!sleep.is_elapsed());
+    (&mut sleep).await;
+    let elapsed = started_on.elapsed();
+    println!("extended sleep is done in {:?}", elapsed);
+    assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_millis(350));
+    assert!(sleep.is_elapsed());
+
+    let rearmed_on = Instant::now();
+    sleep.reset(rearmed_on + Duration::from_millis(50));
+    assert!(!sleep.is_elapsed());
+    (&mut sleep).await;
+    assert!(rearmed_on.elapsed() >= Duration::from_millis(50));
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_sleep_reset: done");
+}
+
+async fn test_sleep_until(rt: Rc<toy::Runtime>) {
+    println!("\ntest_sleep_until: two sleeps share one deadline, the past one fires right away");
+    let deadline = Instant::now() + Duration::from_millis(300);
+    let fired = Cell::new(0);
+    let sleep = || async {
+        toy::sleep_until(&rt, deadline).await;
+        fired.set(fired.get() + 1);
+        Instant::now()
+    };
+    let (first, second) = toy::make_join2(sleep(), sleep()).await;
+    println!(
+        "fired {:?} and {:?} after the deadline",
+        first - deadline,
+        second - deadline
+    );
+    assert_eq!(fired.get(), 2);
+    assert!(first >= deadline && second >= deadline);
+    assert!(first.max(second) - deadline < Duration::from_millis(50));
+
+    // the past deadline yields once with a timer that is due right away
+    let started_on = Instant::now();
+    let mut sleep = Box::pin(toy::sleep_until(
+        &rt,
+        started_on - Duration::from_millis(100),
+    ));
+    let poll = std::future::poll_fn(|ctx| Poll::Ready(sleep.as_mut().poll(ctx))).await;
+    assert!(poll.is_pending());
+    assert_eq!(rt.reactor().timer_count(), 1);
+    sleep.await;
+    assert!(started_on.elapsed() < Duration::from_millis(50));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // the deadlines computed before the earlier sleep, one has passed while sleeping
+    let started_on = Instant::now();
+    let passed = started_on + Duration::from_millis(100);
+    let ahead = started_on + Duration::from_millis(300);
+    toy::sleep(&rt, Duration::from_millis(150)).await;
+    toy::sleep_until(&rt, passed).await;
+    assert!(started_on.elapsed() < Duration::from_millis(200));
+    toy::sleep_until(&rt, ahead).await;
+    let elapsed = started_on.elapsed();
+    assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_millis(350));
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_sleep_until: done");
+}
+
+async fn test_timeout_at(rt: Rc<toy::Runtime>) {
+    println!("\ntest_timeout_at: two operations started at different time share one deadline");
+
+    async fn operation(rt: Rc<toy::Runtime>, name: &str, start: Instant, deadline: Instant) {
+        toy::sleep_until(&rt, start).await;
+        println!("{} started", name);
+        let result = toy::sleep(&rt, Duration::from_millis(2000))
+            .timeout_at(&rt, deadline)
+            .await;
+        let late = Instant::now().duration_since(deadline);
+        println!("{} expired {:?} after the deadline", name, late);
+        assert_eq!(result, Err(toy::Elapsed));
+        assert!(late < Duration::from_millis(50));
+    }
+
+    let now = Instant::now();
+    let deadline = now + Duration::from_millis(500);
+    toy::make_rt_join2(
+        &rt,
+        operation(rt.clone(), "operation_1", now, deadline),
+        operation(
+            rt.clone(),
+            "operation_2",
+            now + Duration::from_millis(200),
+            deadline,
+        ),
+    )
+    .await;
+
+    let result = toy::sleep(&rt, Duration::from_millis(100))
+        .timeout(&rt, Duration::from_millis(1000))
+        .await;
+    assert_eq!(result, Ok(()));
+    println!("test_timeout_at: done");
+}
+
+async fn test_deadline(rt: Rc<toy::Runtime>) {
+    println!("\ntest_deadline: two operations share one budget");
+    let started_on = Instant::now();
+    let deadline = toy::Deadline::at(started_on + Duration::from_millis(300));
+
+    async fn operation(rt: &Rc<toy::Runtime>, deadline: toy::Deadline) -> Result<(), toy::Elapsed> {
+        let work = toy::sleep(rt, Duration::from_millis(200));
+        toy::timeout_at(rt, deadline.instant(), work).await
+    }
+
+    assert_eq!(operation(&rt, deadline).await, Ok(()));
+    let remaining = deadline.remaining().unwrap();
+    println!("the second operation has {:?} left", remaining);
+    assert!(remaining <= Duration::from_millis(100));
+    assert_eq!(operation(&rt, deadline).await, Err(toy::Elapsed));
+    let elapsed = started_on.elapsed();
+    assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_millis(350));
+    assert!(deadline.has_passed());
+    assert_eq!(deadline.remaining(), None);
+
+    // with the deadline passed the inner future is still polled till the timer that is due right
+    // away fires: once for the ready one and twice for the pending one
+    let polls = Cell::new(0);
+    let polls = &polls;
+    let counted = |ready: bool| {
+        std::future::poll_fn(move |_| {
+            polls.set(polls.get() + 1);
+            match ready {
+                true => Poll::Ready(5),
+                false => Poll::Pending,
+            }
+        })
+    };
+    let passed = toy::Deadline::after(Duration::ZERO).instant();
+    assert_eq!(toy::timeout_at(&rt, passed, counted(true)).await, Ok(5));
+    assert_eq!(
+        toy::timeout_at(&rt, passed, counted(false)).await,
+        Err(toy::Elapsed)
+    );
+    assert_eq!(polls.get(), 3);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_deadline: done");
+}
+
+// Compare the numbers of `cargo run --release` and `cargo run --release --no-default-features`
+// to see what the instrumentation of the "trace" feature costs.
+async fn test_dispatch_cost(rt: Rc<toy::Runtime>) {
+    println!("\ntest_dispatch_cost: the time the runtime takes to dispatch an event");
+    const DISPATCHES: u32 = 50_000;
+
+    let started_on = Instant::now();
+    for _ in 0..DISPATCHES {
+        toy::yield_now(&rt).await;
+    }
+    let dispatch = started_on.elapsed() / DISPATCHES;
+
+    // the same timers fired by the bare reactor, the share of the dispatch the runtime cannot
+    // avoid
+    let reactor = toy::Reactor::new();
+    let started_on = Instant::now();
+    for _ in 0..DISPATCHES {
+        reactor.add_timer_at(std::task::Waker::noop(), Instant::now());
+        reactor.wait().expect("the timer that is due right away");
+    }
+    let fire = started_on.elapsed() / DISPATCHES;
+
+    let trace = if cfg!(feature = "trace") { "on" } else { "off" };
+    println!(
+        "trace {}: {:?} per dispatch, {:?} of it is the reactor",
+        trace, dispatch, fire
+    );
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_dispatch_cost: done");
+}
+
+async fn test_metrics(rt: Rc<toy::Runtime>) {
+    println!("\ntest_metrics: histograms of cleanup durations and timer latencies");
+    rt.nested_loop(toy::sleep(&rt, Duration::from_millis(100)));
+    rt.nested_loop(toy::sleep(&rt, Duration::from_millis(300)));
+
+    let histograms: toy::Histograms = rt.metrics().histograms();
+    let cleanup = histograms.cleanup().buckets();
+    let bucket_100ms = toy::Histogram::bucket_index(Duration::from_millis(100));
+    let bucket_300ms = toy::Histogram::bucket_index(Duration::from_millis(300));
+    println!("cleanup buckets: {:?}", cleanup);
+    if cfg!(feature = "trace") {
+        assert_eq!(histograms.cleanup().count(), 2);
+        assert_eq!(cleanup[bucket_100ms], 1);
+        assert_eq!(cleanup[bucket_300ms], 1);
+        assert_eq!(histograms.timer_latency().count(), 2);
+    } else {
+        assert_eq!(histograms.cleanup().count(), 0);
+    }
+    println!("test_metrics: done");
+}
+
+async fn test_suspend(rt: Rc<toy::Runtime>) {
+    println!("\ntest_suspend: suspend the runtime while timers are pending");
+    let started_on = Instant::now();
+    let fired = Rc::new(RefCell::new(Vec::new()));
+
+    type Fired = Rc<RefCell<Vec<&'static str>>>;
+
+    async fn timer(rt: Rc<toy::Runtime>, fired: Fired, name: &'static str, ms: u64) {
+        toy::sleep(&rt, Duration::from_millis(ms)).await;
+        println!("{} fired", name);
+        fired.borrow_mut().push(name);
+    }
+
+    async fn inspector(rt: Rc<toy::Runtime>) {
+        toy::sleep(&rt, Duration::from_millis(50)).await;
+        println!("inspector suspends the runtime for 500ms");
+        rt.suspend();
+        std::thread::sleep(Duration::from_millis(500));
+        rt.resume();
+    }
+
+    toy::make_rt_join2(
+        &rt,
+        toy::make_rt_join2(
+            &rt,
+            timer(rt.clone(), fired.clone(), "timer_2", 300),
+            timer(rt.clone(), fired.clone(), "timer_1", 100),
+        ),
+        inspector(rt.clone()),
+    )
+    .await;
+
+    assert_eq!(*fired.borrow(), ["timer_1", "timer_2"]);
+    assert!(started_on.elapsed() >= Duration::from_millis(800));
+    println!("test_suspend: done");
+}
+
+async fn test_race_join(rt: Rc<toy::Runtime>) {
+    println!("\ntest_race_join: chain join() and race() combinators");
+    let started_on = Instant::now();
+    let winner = toy::sleep(&rt, Duration::from_millis(100))
+        .join(toy::sleep(&rt, Duration::from_millis(200)))
+        .race(toy::sleep(&rt, Duration::from_millis(500)))
+        .await;
+    println!("join won the race in {:?}", started_on.elapsed());
+    assert_eq!(winner, toy::Either::Left(((), ())));
+    assert!(started_on.elapsed() < Duration::from_millis(500));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    let winner = toy::make_select2(
+        toy::sleep(&rt, Duration::from_millis(300)),
+        toy::sleep(&rt, Duration::from_millis(100))
+            .join(toy::sleep(&rt, Duration::from_millis(50))),
+    )
+    .await;
+    assert_eq!(winner, toy::Either::Right(((), ())));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // the loser is dropped and its timer is cancelled
+    let started_on = Instant::now();
+    let winner = toy::make_select2(
+        toy::sleep(&rt, Duration::from_millis(100)),
+        toy::sleep(&rt, Duration::from_millis(10)),
+    )
+    .await;
+    assert_eq!(winner, toy::Either::Right(()));
+    assert!(started_on.elapsed() < Duration::from_millis(100));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // both are ready on the first poll, it is left first
+    let winner = toy::race2(async { 1 }, async { "x" }).await;
+    assert_eq!(winner.map_left(|n| n * 2), toy::Either::Left(2));
+
+    // both get ready on the second poll, it is right first
+    let ready = Rc::new(Cell::new(false));
+    let gate = |value| {
+        let ready = ready.clone();
+        std::future::poll_fn(move |_| match ready.get() {
+            true => Poll::Ready(value),
+            false => Poll::Pending,
+        })
+    };
+    let mut race = toy::race2(gate(1), gate(2));
+    let first = std::future::poll_fn(|ctx| Poll::Ready(Pin::new(&mut race).poll(ctx))).await;
+    assert!(first.is_pending());
+    ready.set(true);
+    let winner = race.await.map_right(|n| n * 10);
+    assert_eq!(winner, toy::Either::Right(20));
+    println!("test_race_join: done");
+}
+
+async fn test_retire_frozen_event(rt: Rc<toy::Runtime>) {
+    println!("\ntest_retire_frozen_event: drop a sleep which timer fired while frozen");
+
+    async fn freezer(rt: Rc<toy::Runtime>) {
+        toy::sleep(&rt, Duration::from_millis(50)).await;
+        println!("freezer starts nested loop");
+        rt.nested_loop(toy::sleep(&rt, Duration::from_millis(200)));
+        assert_eq!(rt.frozen_event_count(), 1);
+        println!("freezer nested loop done");
+    }
+
+    let winner = toy::make_select2(
+        toy::sleep(&rt, Duration::from_millis(100)),
+        freezer(rt.clone()),
+    )
+    .await;
+
+    assert_eq!(winner, toy::Either::Right(()));
+    assert_eq!(rt.frozen_event_count(), 0);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_retire_frozen_event: done");
+}
+
+async fn test_retire_event_stages(rt: Rc<toy::Runtime>) {
+    println!("\ntest_retire_event_stages: the retired event is gone from every stage");
+    let ms = Duration::from_millis;
+
+    // reactor: the timer has not fired yet
+    std::future::poll_fn(|ctx| {
+        let event_id = rt
+            .reactor()
+            .add_timer_at(ctx.waker(), Instant::now() + ms(50));
+        assert_eq!(rt.reactor().timer_count(), 1);
+        rt.retire_event(event_id);
+        assert_eq!(rt.reactor().timer_count(), 0);
+        assert_eq!(rt.reactor().peek_next_deadline(), None);
+        Poll::Ready(())
+    })
+    .await;
+
+    // batch: the interval has fired in a batch and is re-armed in reactor
+    std::future::poll_fn(|ctx| {
+        let event_id = rt.reactor().add_interval(ctx.waker(), ms(10));
+        let batch = rt.reactor().wait_batch();
+        assert_eq!(batch[0].event_id, event_id);
+        assert_eq!(rt.reactor().timer_count(), 1);
+        rt.retire_event(event_id);
+        assert_eq!(rt.reactor().timer_count(), 0);
+        assert!(rt.reactor().wait_batch().is_empty());
+        assert!(!rt.is_awoken(event_id));
+        Poll::Ready(())
+    })
+    .await;
+
+    // frozen_events: the timer has fired while its task was frozen by a nested loop
+    std::future::poll_fn(|ctx| {
+        let event_id = rt
+            .reactor()
+            .add_timer_at(ctx.waker(), Instant::now() + ms(20));
+        rt.nested_loop(toy::sleep(&rt, ms(50)));
+        assert_eq!(rt.frozen_event_count(), 1);
+        rt.retire_event(event_id);
+        assert_eq!(rt.frozen_event_count(), 0);
+        assert!(rt.analyze_frozen().is_empty());
+        Poll::Ready(())
+    })
+    .await;
+
+    // awoken_event: the task is polled for the event that has just fired
+    let mut armed = None;
+    std::future::poll_fn(|ctx| match armed {
+        None => {
+            armed = Some(
+                rt.reactor()
+                    .add_timer_at(ctx.waker(), Instant::now() + ms(20)),
+            );
+            Poll::Pending
+        }
+        Some(event_id) => {
+            assert!(rt.is_awoken(event_id));
+            rt.retire_event(event_id);
+            assert!(!rt.is_awoken(event_id));
+            Poll::Ready(())
+        }
+    })
+    .await;
+
+    assert_eq!(rt.reactor().timer_count(), 0);
+    assert_eq!(rt.frozen_event_count(), 0);
+    println!("test_retire_event_stages: done");
+}
+
+async fn test_drop_fired_sleep(rt: Rc<toy::Runtime>) {
+    println!("\ntest_drop_fired_sleep: the sleep is dropped after its timer fired via wait()");
+    std::future::poll_fn(|ctx| {
+        let mut sleep = Box::pin(toy::Sleep::new(&rt, Duration::from_millis(10)));
+        assert!(sleep.as_mut().poll(ctx).is_pending());
+
+        // the timer is taken from the reactor, but the event is never dispatched
+        let wait = rt.reactor().wait().expect("the timer of the sleep");
+        assert_eq!(Some(wait.awake_on), sleep.deadline());
+        assert_eq!(rt.reactor().timer_count(), 0);
+
+        let dropped = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(sleep)));
+        assert!(dropped.is_ok());
+        assert_eq!(rt.reactor().timer_count(), 0);
+        Poll::Ready(())
+    })
+    .await;
+    println!("test_drop_fired_sleep: done");
+}
+
+async fn test_cancel_timer(rt: Rc<toy::Runtime>) {
+    println!("\ntest_cancel_timer: the timer cancelled via rt.reactor() is gone");
+    std::future::poll_fn(|ctx| {
+        let now = Instant::now();
+        let cancelled = rt
+            .reactor()
+            .add_timer_at(ctx.waker(), now + Duration::from_millis(10));
+        let kept = rt
+            .reactor()
+            .add_timer_at(ctx.waker(), now + Duration::from_millis(20));
+        assert_eq!(rt.reactor().timer_count(), 2);
+
+        assert!(rt.reactor().cancel_timer(cancelled));
+        assert_eq!(rt.reactor().timer_count(), 1);
+        assert!(!rt.reactor().cancel_timer(cancelled));
+
+        // the heap entry of the cancelled timer is skipped, the kept one fires
+        let wait = rt.reactor().wait().expect("the kept timer");
+        assert_eq!(wait.event_id, kept);
+        assert_eq!(rt.reactor().timer_count(), 0);
+        assert!(rt.reactor().wait().is_none());
+        Poll::Ready(())
+    })
+    .await;
+    println!("test_cancel_timer: done");
+}
+
+async fn test_retire_from_frozen_alert(rt: Rc<toy::Runtime>) {
+    println!("\ntest_retire_from_frozen_alert: the alert callback retires the parked event");
+    let weak_rt = Rc::downgrade(&rt);
+    let retired = Rc::new(RefCell::new(Vec::new()));
+    let retired_clone = retired.clone();
+    rt.set_frozen_alert(1, Duration::from_secs(60), move |reports| {
+        let rt = weak_rt.upgrade().unwrap();
+        for report in reports {
+            rt.retire_event(report.event_id());
+            retired_clone.borrow_mut().push(report.event_id());
+        }
+    });
+
+    async fn freezer(rt: Rc<toy::Runtime>) {
+        toy::sleep(&rt, Duration::from_millis(50)).await;
+        rt.nested_loop(toy::sleep(&rt, Duration::from_millis(200)));
+        // the event of the other branch has been parked and retired by the alert
+        assert_eq!(rt.frozen_event_count(), 0);
+    }
+
+    // the sleep never fires for select, its event is retired
+    let winner = toy::make_select2(
+        toy::sleep(&rt, Duration::from_millis(100)),
+        freezer(rt.clone()),
+    )
+    .await;
+
+    assert_eq!(winner, toy::Either::Right(()));
+    assert_eq!(retired.borrow().len(), 1);
+    assert!(rt.analyze_frozen().is_empty());
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_retire_from_frozen_alert: done");
+}
+
+fn test_polled_after_completion() {
+    println!("\ntest_polled_after_completion: polling a completed Timeout is reported");
+
+    // Breaks the Future contract: polls the inner future once more after it is completed.
+    struct PollAfterReady<FutT>(Pin<Box<FutT>>);
+
+    impl<FutT: Future> Future for PollAfterReady<FutT> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+            match self.0.as_mut().poll(ctx) {
+                Poll::Ready(_) => {
+                    let _ = self.0.as_mut().poll(ctx);
+                    Poll::Ready(())
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(|| {
+        toy::run(|rt| async move {
+            let timeout = toy::sleep(&rt, Duration::from_millis(1000))
+                .timeout(&rt, Duration::from_millis(10));
+            PollAfterReady(Box::pin(timeout)).await;
+        })
+    });
+    std::panic::set_hook(default_hook);
+
+    let message = result.unwrap_err().downcast::<String>().unwrap();
+    println!("{}", message);
+    assert!(message.starts_with("FuturePolledAfterCompletion"));
+    assert!(message.contains("Timeout"));
+    assert!(message.contains("in task `") && message.contains("(TaskId("));
+    println!("test_polled_after_completion: done");
+}
+
+async fn test_sleep_polled_after_completion(rt: Rc<toy::Runtime>) {
+    println!("\ntest_sleep_polled_after_completion: the completed Sleep stays Ready");
+
+    // Sloppy combinator: polls the sleep twice more once it is Ready.
+    struct PollTwiceAfterReady<'s>(&'s mut toy::Sleep);
+
+    impl Future for PollTwiceAfterReady<'_> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+            let sleep = Pin::new(&mut *self.0);
+            if sleep.poll(ctx).is_pending() {
+                return Poll::Pending;
+            }
+            assert!(Pin::new(&mut *self.0).poll(ctx).is_ready());
+            assert!(Pin::new(&mut *self.0).poll(ctx).is_ready());
+            Poll::Ready(())
+        }
+    }
+
+    let mut sleep = toy::Sleep::new(&rt, Duration::from_millis(50));
+    PollTwiceAfterReady(&mut sleep).await;
+    assert!(sleep.is_elapsed());
+    assert_eq!(sleep.polls_after_completion(), 2);
+
+    sleep.reset(Instant::now() + Duration::from_millis(10));
+    assert_eq!(sleep.polls_after_completion(), 0);
+    (&mut sleep).await;
+    assert_eq!(sleep.polls_after_completion(), 0);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_sleep_polled_after_completion: done");
+}
+
+async fn test_flatten(rt: Rc<toy::Runtime>) {
+    println!("\ntest_flatten: future that resolves to a sleep future");
+    let started_on = Instant::now();
+    #[allow(clippy::async_yields_async)] // returning the future is the point of the test
+    let lookup = async {
+        toy::sleep(&rt, Duration::from_millis(100)).await;
+        println!("lookup done, returning a sleep future");
+        toy::sleep(&rt, Duration::from_millis(200))
+    };
+    lookup.flatten().await;
+    println!("flatten done in {:?}", started_on.elapsed());
+    assert!(started_on.elapsed() >= Duration::from_millis(300));
+    println!("test_flatten: done");
+}
+
+async fn test_borrowing_cleanup(rt: Rc<toy::Runtime>) {
+    println!("\ntest_borrowing_cleanup: async drop that borrows fields of the dropped value");
+
+    struct Writer {
+        rt: Rc<toy::Runtime>,
+        buffer: Vec<u8>,
+        flushed: Rc<Cell<usize>>,
+    }
+
+    impl Writer {
+        async fn flush(&mut self) {
+            toy::sleep(&self.rt, Duration::from_millis(100)).await;
+            self.flushed.set(self.flushed.get() + self.buffer.len());
+            self.buffer.clear();
+        }
+    }
+
+    impl Drop for Writer {
+        fn drop(&mut self) {
+            println!("Writer::drop flushes {} bytes", self.buffer.len());
+            let rt = self.rt.clone();
+            rt.nested_loop(self.flush());
+            assert!(self.buffer.is_empty());
+        }
+    }
+
+    let flushed = Rc::new(Cell::new(0));
+    let writer = Writer {
+        rt: rt.clone(),
+        buffer: b"hello".to_vec(),
+        flushed: flushed.clone(),
+    };
+    drop(writer);
+    assert_eq!(flushed.get(), 5);
+    println!("test_borrowing_cleanup: done");
+}
+
+fn test_blocking_detection() {
+    println!("\ntest_blocking_detection: blocking call inside poll is detected");
+
+    async fn blocking_task(rt: Rc<toy::Runtime>, action: toy::BlockingAction) {
+        rt.set_blocking_detection(Duration::from_millis(50), action);
+        // nested loop is not a blocking call
+        rt.nested_loop(toy::sleep(&rt, Duration::from_millis(100)));
+        toy::sleep(&rt, Duration::from_millis(10)).await;
+        println!("blocking_task calls thread::sleep()");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    toy::run(|rt| blocking_task(rt, toy::BlockingAction::Warn));
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(|| {
+        toy::run(|rt| blocking_task(rt, toy::BlockingAction::Panic));
+    });
+    std::panic::set_hook(default_hook);
+
+    let message = result.unwrap_err().downcast::<String>().unwrap();
+    println!("panic: {}", message);
+    assert!(message.starts_with("blocking call inside poll"));
+    println!("test_blocking_detection: done");
+}
+
+async fn test_watchdog(rt: Rc<toy::Runtime>) {
+    println!("\ntest_watchdog: cancel the nested loop that is stuck");
+    let reports = Rc::new(RefCell::new(Vec::new()));
+    let reports_clone = reports.clone();
+    rt.set_watchdog(5, Duration::from_secs(60), move |report| {
+        reports_clone.borrow_mut().push(report.clone());
+        toy::WatchdogVerdict::Cancel
+    });
+
+    async fn ticker(rt: Rc<toy::Runtime>) {
+        for _ in 0..10 {
+            toy::sleep(&rt, Duration::from_millis(20)).await;
+        }
+        println!("ticker done");
+    }
+
+    async fn stuck(rt: Rc<toy::Runtime>) {
+        toy::sleep(&rt, Duration::from_millis(10)).await;
+        println!("stuck starts nested loop that waits 10s in two child tasks");
+        let ms = Duration::from_millis;
+        let cleanup = toy::make_rt_join2(
+            &rt,
+            toy::sleep(&rt, ms(10_000)),
+            toy::sleep(&rt, ms(20_000)),
+        );
+        rt.nested_loop(cleanup.map(|_| ()));
+        println!("stuck nested loop cancelled");
+    }
+
+    toy::make_rt_join2(&rt, ticker(rt.clone()), stuck(rt.clone())).await;
+
+    let reports = reports.borrow();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].wakes(), 5);
+    assert!(reports[0].elapsed() < Duration::from_secs(1));
+    assert_eq!(reports[0].depth(), 1);
+    assert!(reports[0].history().contains("LoopEntered(1)"));
+    assert!(reports[0].task_name().contains("Map<"));
+    // the events of the child tasks of the cleanup
+    assert_eq!(reports[0].waiting_on().len(), 2);
+    println!("{}", reports[0].task_dump());
+    assert!(reports[0].task_dump().contains("[cleanup]"));
+    assert!(reports[0]
+        .task_dump()
+        .contains("stuck::{{closure}}` (TaskId(3))"));
+    assert!(reports[0].task_dump().contains("[frozen]"));
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_watchdog: done");
+}
+
+async fn test_chain(rt: Rc<toy::Runtime>) {
+    println!("\ntest_chain: run two sleeps one after another");
+    let started_on = Instant::now();
+
+    let probe = async {
+        toy::sleep(&rt, Duration::from_millis(50)).await;
+        // only the first sleep of the chain has its timer in reactor
+        assert_eq!(rt.reactor().timer_count(), 1);
+    };
+
+    toy::sleep(&rt, Duration::from_millis(100))
+        .chain(toy::sleep(&rt, Duration::from_millis(100)))
+        .join(probe)
+        .await;
+
+    println!("chain done in {:?}", started_on.elapsed());
+    assert!(started_on.elapsed() >= Duration::from_millis(200));
+    println!("test_chain: done");
+}
+
+async fn test_sleep_remaining(rt: Rc<toy::Runtime>) {
+    println!("\ntest_sleep_remaining: sleep respects the cleanup deadline");
+    let ms = Duration::from_millis;
+
+    // no cleanup deadline
+    let slept = toy::sleep_remaining(&rt, ms(50)).await;
+    assert_eq!(slept, toy::Slept::Full);
+
+    let started_on = Instant::now();
+    rt.nested_loop_with_deadline(started_on + ms(1000), async {
+        assert_eq!(toy::sleep_remaining(&rt, ms(50)).await, toy::Slept::Full);
+    });
+    rt.nested_loop_with_deadline(started_on + ms(150), async {
+        assert_eq!(
+            toy::sleep_remaining(&rt, ms(500)).await,
+            toy::Slept::Truncated
+        );
+    });
+    println!("truncated at {:?}", started_on.elapsed());
+    assert!(started_on.elapsed() < ms(300));
+
+    // the outer deadline is tighter than the inner one
+    let started_on = Instant::now();
+    rt.nested_loop_with_deadline(started_on + ms(100), async {
+        rt.nested_loop_with_deadline(started_on + ms(1000), async {
+            assert_eq!(
+                toy::sleep_remaining(&rt, ms(500)).await,
+                toy::Slept::Truncated
+            );
+        });
+    });
+    println!("nested truncated at {:?}", started_on.elapsed());
+    assert!(started_on.elapsed() < ms(300));
+    println!("test_sleep_remaining: done");
+}
+
+async fn test_timeout_with_cleanup(rt: Rc<toy::Runtime>) {
+    println!("\ntest_timeout_with_cleanup: timed out future gets async drop");
+
+    // The upload that takes too long but has to close the connection gracefully
+    struct Upload {
+        rt: Rc<toy::Runtime>,
+        transfer: Pin<Box<dyn Future<Output = ()>>>,
+        closed: Rc<Cell<bool>>,
+    }
+
+    impl Future for Upload {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<()> {
+            self.transfer.as_mut().poll(ctx)
+        }
+    }
+
+    impl AsyncDrop for Upload {
+        fn async_drop(self: Pin<&mut Self>) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+            let this = self.get_mut();
+            Box::pin(async move {
+                println!("Upload: closing the connection");
+                toy::sleep(&this.rt, Duration::from_millis(50)).await;
+                this.closed.set(true);
+            })
+        }
+    }
+
+    let closed = Rc::new(Cell::new(false));
+    let transfer_rt = rt.clone();
+    let upload = Upload {
+        rt: rt.clone(),
+        transfer: Box::pin(
+            async move { toy::sleep(&transfer_rt, Duration::from_millis(1000)).await },
+        ),
+        closed: closed.clone(),
+    };
+
+    let result = upload
+        .timeout_with_cleanup(&rt, Duration::from_millis(100))
+        .await;
+    assert_eq!(result, Err(toy::Elapsed));
+    assert!(
+        closed.get(),
+        "the cleanup must complete before Err(Elapsed)"
+    );
+    println!("test_timeout_with_cleanup: done");
+}
+
+async fn test_async_drop_guard(rt: Rc<toy::Runtime>) {
+    println!("\ntest_async_drop_guard: the scope waits for the async drop of the guard");
+
+    struct Session {
+        rt: Rc<toy::Runtime>,
+        name: &'static str,
+        log: Rc<RefCell<Vec<String>>>,
+        // dropped after the async drop of the session
+        _inner: Option<toy::AsyncDropGuard<Session>>,
+    }
+
+    impl AsyncDrop for Session {
+        fn async_drop(self: Pin<&mut Self>) -> Pin<Box<dyn Future<Output = ()> + '_>> {
+            let this = self.get_mut();
+            Box::pin(async move {
+                this.log.borrow_mut().push(format!("{} closing", this.name));
+                toy::sleep(&this.rt, Duration::from_millis(50)).await;
+                this.log.borrow_mut().push(format!("{} closed", this.name));
+            })
+        }
+    }
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let session = |name, inner| Session {
+        rt: rt.clone(),
+        name,
+        log: log.clone(),
+        _inner: inner,
+    };
+
+    let started_on = Instant::now();
+    {
+        let guard = toy::AsyncDropGuard::new(&rt, session("single", None));
+        assert_eq!(guard.name, "single");
+    }
+    assert!(started_on.elapsed() >= Duration::from_millis(50));
+    assert_eq!(*log.borrow(), ["single closing", "single closed"]);
+
+    // the guard of the inner session is dropped with the outer session
+    log.borrow_mut().clear();
+    let inner = toy::AsyncDropGuard::new(&rt, session("inner", None));
+    drop(toy::AsyncDropGuard::new(&rt, session("outer", Some(inner))));
+    let expected = [
+        "outer closing",
+        "outer closed",
+        "inner closing",
+        "inner closed",
+    ];
+    assert_eq!(*log.borrow(), expected);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_async_drop_guard: done");
+}
+
+async fn test_timeout_rt(rt: Rc<toy::Runtime>) {
+    println!("\ntest_timeout_rt: the timed out task is destroyed with its async cleanup");
+
+    struct Connection {
+        rt: Rc<toy::Runtime>,
+        log: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            let rt = self.rt.clone();
+            rt.nested_loop(async {
+                toy::sleep(&self.rt, Duration::from_millis(500)).await;
+                println!("Connection: closed");
+                self.log.borrow_mut().push("closed");
+            });
+        }
+    }
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let transfer = |freeze: bool| {
+        let connection = Connection {
+            rt: rt.clone(),
+            log: log.clone(),
+        };
+        let rt = rt.clone();
+        async move {
+            let _connection = connection;
+            if freeze {
+                // the deadline passes while the task runs its nested loop
+                rt.nested_loop(toy::sleep(&rt, Duration::from_millis(200)));
+            }
+            toy::sleep(&rt, Duration::from_millis(1000)).await;
+        }
+    };
+
+    let started_on = Instant::now();
+    let result = toy::timeout_rt(&rt, Duration::from_millis(100), transfer(false)).await;
+    log.borrow_mut().push("elapsed");
+    let elapsed = started_on.elapsed();
+    println!("timed out in {:?}", elapsed);
+    assert_eq!(result, Err(toy::Elapsed));
+    assert_eq!(*log.borrow(), ["closed", "elapsed"]);
+    assert!(elapsed >= Duration::from_millis(600) && elapsed < Duration::from_millis(700));
+
+    let started_on = Instant::now();
+    let result = toy::timeout_rt(&rt, Duration::from_millis(100), transfer(true)).await;
+    let elapsed = started_on.elapsed();
+    println!(
+        "timed out in {:?} after the nested loop of the task",
+        elapsed
+    );
+    assert_eq!(result, Err(toy::Elapsed));
+    assert!(elapsed >= Duration::from_millis(700) && elapsed < Duration::from_millis(800));
+
+    let result = toy::timeout_rt(&rt, Duration::from_millis(100), async { 5 }).await;
+    assert_eq!(result, Ok(5));
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_timeout_rt: done");
+}
+
+async fn test_async_scope(rt: Rc<toy::Runtime>) {
+    println!("\ntest_async_scope: the cleanup runs after the body and when the scope is dropped");
+    let log = RefCell::new(Vec::new());
+    let cleanup = |name| {
+        let rt = &rt;
+        let log = &log;
+        async move {
+            toy::sleep(rt, Duration::from_millis(50)).await;
+            log.borrow_mut().push(name);
+        }
+    };
+
+    let started_on = Instant::now();
+    let body = async {
+        toy::sleep(&rt, Duration::from_millis(50)).await;
+        log.borrow_mut().push("body");
+        7
+    };
+    let output = toy::async_scope(&rt, body, cleanup("completed")).await;
+    assert_eq!(output, 7);
+    assert_eq!(*log.borrow(), ["body", "completed"]);
+    assert!(started_on.elapsed() >= Duration::from_millis(100));
+
+    // the scope loses the race while its body is pending
+    log.borrow_mut().clear();
+    let scope = toy::async_scope(
+        &rt,
+        toy::sleep(&rt, Duration::from_millis(1000)),
+        cleanup("dropped"),
+    );
+    let winner = toy::race2(scope, toy::sleep(&rt, Duration::from_millis(30))).await;
+    assert!(matches!(winner, toy::Either::Right(())));
+    assert_eq!(*log.borrow(), ["dropped"]);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_async_scope: done");
+}
+
+async fn test_cancellation_token(rt: Rc<toy::Runtime>) {
+    println!("\ntest_cancellation_token: the worker sees the cancel and cleans up on its own");
+    let token = toy::CancellationToken::new(&rt);
+    let log = RefCell::new(Vec::new());
+
+    let worker = async {
+        let mut steps = 0;
+        loop {
+            let step = toy::sleep(&rt, Duration::from_millis(40));
+            match toy::race2(step, token.cancelled()).await {
+                toy::Either::Left(()) => steps += 1,
+                toy::Either::Right(()) => break,
+            }
+        }
+        // the async cleanup of its own
+        toy::sleep(&rt, Duration::from_millis(20)).await;
+        log.borrow_mut().push("worker cleaned up");
+        steps
+    };
+    let canceller = async {
+        toy::sleep(&rt, Duration::from_millis(100)).await;
+        log.borrow_mut().push("cancel");
+        token.cancel();
+    };
+
+    let started_on = Instant::now();
+    let (steps, ()) = toy::make_join2(worker, canceller).await;
+    let elapsed = started_on.elapsed();
+    println!("worker made {} steps in {:?}", steps, elapsed);
+    assert_eq!(steps, 2);
+    assert_eq!(*log.borrow(), ["cancel", "worker cleaned up"]);
+    assert!(elapsed >= Duration::from_millis(120) && elapsed < Duration::from_millis(160));
+
+    // the token is cancelled already
+    assert!(token.is_cancelled());
+    token.clone().cancelled().await;
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_cancellation_token: done");
+}
+
+async fn test_join_many(rt: Rc<toy::Runtime>) {
+    println!("\ntest_join_many: join 3, 4 and N futures");
+
+    async fn sleep_ms(rt: Rc<toy::Runtime>, ms: u64) -> u64 {
+        toy::sleep(&rt, Duration::from_millis(ms)).await;
+        ms
+    }
+
+    let started_on = Instant::now();
+    let outputs = toy::make_join3(
+        sleep_ms(rt.clone(), 100),
+        sleep_ms(rt.clone(), 300),
+        sleep_ms(rt.clone(), 200),
+    )
+    .await;
+    assert_eq!(outputs, (100, 300, 200));
+
+    let outputs = toy::make_join4(
+        sleep_ms(rt.clone(), 40),
+        sleep_ms(rt.clone(), 30),
+        sleep_ms(rt.clone(), 20),
+        sleep_ms(rt.clone(), 10),
+    )
+    .await;
+    assert_eq!(outputs, (40, 30, 20, 10));
+    println!("join3 and join4 done in {:?}", started_on.elapsed());
+
+    // total time is the longest sleep, not the sum
+    let started_on = Instant::now();
+    let sleeps = [500, 100, 400, 200, 300].map(|ms| sleep_ms(rt.clone(), ms));
+    let outputs = toy::join_all(sleeps.into()).await;
+    let elapsed = started_on.elapsed();
+    println!("join_all done in {:?}", elapsed);
+    assert_eq!(outputs, vec![500, 100, 400, 200, 300]);
+    assert!(elapsed >= Duration::from_millis(500) && elapsed < Duration::from_millis(700));
+    println!("test_join_many: done");
+}
+
+async fn test_join_outputs(rt: Rc<toy::Runtime>) {
+    println!("\ntest_join_outputs: join resolves to the outputs of both futures");
+
+    async fn fetch_name(rt: Rc<toy::Runtime>) -> String {
+        toy::sleep(&rt, Duration::from_millis(100)).await;
+        "adrop".to_string()
+    }
+
+    async fn fetch_count(rt: Rc<toy::Runtime>) -> u32 {
+        toy::sleep(&rt, Duration::from_millis(50)).await;
+        42
+    }
+
+    let (name, count) = toy::make_join2(fetch_name(rt.clone()), fetch_count(rt.clone())).await;
+    assert_eq!(name, "adrop");
+    assert_eq!(count, 42);
+
+    let (count, name) = fetch_count(rt.clone()).join(fetch_name(rt.clone())).await;
+    assert_eq!((count, name.as_str()), (42, "adrop"));
+
+    // the futures that are ready on the first poll
+    assert_eq!(toy::make_join2(async { 1 }, async { "x" }).await, (1, "x"));
+    println!("test_join_outputs: done");
+}
+
+// Polls the future once from the test task, without awaiting it.
+async fn poll_once<FutT: Future + Unpin>(fut: &mut FutT) -> Poll<FutT::Output> {
+    std::future::poll_fn(|ctx| Poll::Ready(Pin::new(&mut *fut).poll(ctx))).await
+}
+
+async fn test_join_fairness(rt: Rc<toy::Runtime>) {
+    println!("\ntest_join_fairness: join changes the order of polls on every poll");
+    let ready = Rc::new(Cell::new(true));
+    let log = Rc::new(RefCell::new(Vec::new()));
+
+    // Completes on the poll after `ready` is set and logs the order of completion
+    let gate = |name| {
+        let (ready, log) = (ready.clone(), log.clone());
+        std::future::poll_fn(move |_| {
+            if !ready.get() {
+                return Poll::Pending;
+            }
+            log.borrow_mut().push(name);
+            Poll::Ready(())
+        })
+    };
+
+    toy::make_join2(gate("a"), gate("b")).await;
+    assert_eq!(log.take(), ["a", "b"]);
+
+    ready.set(false);
+    let mut join = Box::pin(toy::make_join2(gate("a"), gate("b")));
+    assert!(poll_once(&mut join).await.is_pending());
+    ready.set(true);
+    join.await;
+    assert_eq!(log.take(), ["b", "a"]);
+
+    ready.set(false);
+    let mut join = Box::pin(toy::make_rt_join2(&rt, gate("a"), gate("b")));
+    assert!(poll_once(&mut join).await.is_pending());
+    ready.set(true);
+    join.await;
+    assert_eq!(log.take(), ["b", "a"]);
+    println!("test_join_fairness: done");
+}
+
+async fn test_join_terminated(rt: Rc<toy::Runtime>) {
+    println!("\ntest_join_terminated: the completed join child is not polled again");
+    let ready = Rc::new(Cell::new(false));
+    let gate = || {
+        let ready = ready.clone();
+        std::future::poll_fn(move |_| match ready.get() {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        })
+    };
+
+    // checked() panics if the first child is polled after it has completed
+    let mut join = Box::pin(toy::make_join2(async {}.checked(), gate()));
+    for _ in 0..10 {
+        assert!(poll_once(&mut join).await.is_pending());
+    }
+    assert!(!join.is_terminated());
+    ready.set(true);
+    assert!(poll_once(&mut join).await.is_ready());
+    assert!(join.is_terminated());
+
+    ready.set(false);
+    let mut join = Box::pin(toy::make_rt_join2(&rt, async {}.checked(), gate()));
+    for _ in 0..10 {
+        assert!(poll_once(&mut join).await.is_pending());
+    }
+    ready.set(true);
+    assert!(poll_once(&mut join).await.is_ready());
+    assert!(join.is_terminated());
+
+    // Counts the polls of the child that completes on the first one
+    let polls = Rc::new(Cell::new(0));
+    let counted = || {
+        let polls = polls.clone();
+        std::future::poll_fn(move |_| {
+            polls.set(polls.get() + 1);
+            Poll::Ready(())
+        })
+    };
+
+    ready.set(false);
+    let mut join = Box::pin(toy::make_join2(counted(), gate()));
+    for _ in 0..5 {
+        assert!(poll_once(&mut join).await.is_pending());
+    }
+    ready.set(true);
+    join.await;
+    assert_eq!(polls.replace(0), 1);
+
+    ready.set(false);
+    let mut join = Box::pin(toy::make_rt_join2(&rt, gate(), counted()));
+    for _ in 0..5 {
+        assert!(poll_once(&mut join).await.is_pending());
+    }
+    ready.set(true);
+    join.await;
+    assert_eq!(polls.get(), 1);
+    println!("test_join_terminated: done");
+}
+
+async fn test_rt_join_awoken_child(rt: Rc<toy::Runtime>) {
+    println!("\ntest_rt_join_awoken_child: the timer only gets its own child polled");
+
+    // Counts the polls of the future
+    fn counted<FutT: Future>(polls: &Rc<Cell<usize>>, fut: FutT) -> impl Future<Output = ()> {
+        let polls = polls.clone();
+        let mut fut = Box::pin(fut);
+        std::future::poll_fn(move |ctx| {
+            polls.set(polls.get() + 1);
+            fut.as_mut().poll(ctx).map(|_| ())
+        })
+    }
+
+    let ms = Duration::from_millis;
+    let (polls1, polls2) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+    let twice = async {
+        toy::sleep(&rt, ms(50)).await;
+        toy::sleep(&rt, ms(50)).await;
+    };
+    toy::make_rt_join2(
+        &rt,
+        counted(&polls1, twice),
+        counted(&polls2, toy::sleep(&rt, ms(150))),
+    )
+    .await;
+    // the first poll of each and one poll per timer
+    assert_eq!((polls1.get(), polls2.get()), (3, 2));
+
+    // the events of the grandchildren are routed through the child tasks
+    let (polls1, polls2) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+    let inner = toy::make_rt_join2(&rt, toy::sleep(&rt, ms(50)), toy::sleep(&rt, ms(100)));
+    toy::make_rt_join2(
+        &rt,
+        counted(&polls1, inner),
+        counted(&polls2, toy::sleep(&rt, ms(150))),
+    )
+    .await;
+    assert_eq!((polls1.get(), polls2.get()), (3, 2));
+    println!("test_rt_join_awoken_child: done");
+}
+
+async fn test_join_macros(rt: Rc<toy::Runtime>) {
+    println!("\ntest_join_macros: join!() and rt_join!() for any number of futures");
+    let ms = Duration::from_millis;
+
+    let started_on = Instant::now();
+    let outputs = toy::join!(
+        toy::sleep(&rt, ms(100)),
+        toy::sleep(&rt, ms(300)),
+        toy::sleep(&rt, ms(200)),
+        toy::sleep(&rt, ms(50)),
+    )
+    .await;
+    let elapsed = started_on.elapsed();
+    assert_eq!(outputs, ((), (), (), ()));
+    assert!(elapsed >= ms(300) && elapsed < ms(400));
+
+    let (one, x, slept) = toy::join!(
+        async { 1 },
+        async { "x" },
+        toy::sleep_remaining(&rt, ms(10))
+    )
+    .await;
+    assert_eq!((one, x, slept), (1, "x", toy::Slept::Full));
+
+    let started_on = Instant::now();
+    let (one, x, ()) =
+        toy::rt_join!(&rt, async { 1 }, async { "x" }, toy::sleep(&rt, ms(100))).await;
+    assert_eq!((one, x), (1, "x"));
+    assert!(started_on.elapsed() >= ms(100));
+    assert_eq!(toy::rt_join!(&rt, async { 5 }).await, (5,));
+    println!("test_join_macros: done");
+}
+
+async fn test_try_join(rt: Rc<toy::Runtime>) {
+    println!("\ntest_try_join: try join stops on the first error");
+
+    async fn step(rt: Rc<toy::Runtime>, ms: u64, result: Result<u64, &str>) -> Result<u64, &str> {
+        toy::sleep(&rt, Duration::from_millis(ms)).await;
+        result
+    }
+
+    let both = toy::make_try_join2(step(rt.clone(), 50, Ok(1)), step(rt.clone(), 100, Ok(2))).await;
+    assert_eq!(both, Ok((1, 2)));
+
+    // the longer sleep is dropped with its timer as soon as the other step fails
+    let started_on = Instant::now();
+    let failed = toy::make_try_join2(
+        step(rt.clone(), 1000, Ok(1)),
+        step(rt.clone(), 50, Err("b")),
+    )
+    .await;
+    assert_eq!(failed, Err("b"));
+    assert!(started_on.elapsed() < Duration::from_millis(500));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // same when the first step fails, the second one is dropped halfway
+    let started_on = Instant::now();
+    let failed =
+        toy::make_try_join2(step(rt.clone(), 20, Err("a")), step(rt.clone(), 200, Ok(2))).await;
+    assert_eq!(failed, Err("a"));
+    assert!(started_on.elapsed() < Duration::from_millis(100));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    let first = toy::make_try_join2(async { Err::<(), _>("a") }, async { Err::<(), _>("b") }).await;
+    assert_eq!(first, Err("a"));
+    println!("test_try_join: done");
+}
+
+async fn test_first_ok(rt: Rc<toy::Runtime>) {
+    println!("\ntest_first_ok: the first success wins, the errors keep the order of futures");
+
+    async fn lookup(rt: Rc<toy::Runtime>, ms: u64, result: Result<u64, u64>) -> Result<u64, u64> {
+        toy::sleep(&rt, Duration::from_millis(ms)).await;
+        result
+    }
+
+    // the losers are dropped with their timers
+    let started_on = Instant::now();
+    let found = toy::first_ok(vec![
+        lookup(rt.clone(), 1000, Ok(1)),
+        lookup(rt.clone(), 10, Err(2)),
+        lookup(rt.clone(), 50, Ok(3)),
+    ])
+    .await;
+    assert_eq!(found, Ok(3));
+    assert!(started_on.elapsed() < Duration::from_millis(500));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    let failed = toy::first_ok(vec![
+        lookup(rt.clone(), 50, Err(1)),
+        lookup(rt.clone(), 10, Err(2)),
+        lookup(rt.clone(), 30, Err(3)),
+    ])
+    .await;
+    assert_eq!(failed, Err(vec![1, 2, 3]));
+    println!("test_first_ok: done");
+}
+
+async fn test_shared(rt: Rc<toy::Runtime>) {
+    println!("\ntest_shared: two tasks await the same computation");
+    let computed = Rc::new(Cell::new(0));
+    let compute = {
+        let (rt, computed) = (rt.clone(), computed.clone());
+        async move {
+            toy::sleep(&rt, Duration::from_millis(50)).await;
+            computed.set(computed.get() + 1);
+            String::from("answer")
+        }
+    };
+
+    let answer = toy::shared(&rt, compute);
+    let started_on = Instant::now();
+    let (a, (b, c)) = toy::make_rt_join2(
+        &rt,
+        answer.clone(),
+        toy::make_rt_join2(&rt, answer.clone(), answer.clone()),
+    )
+    .await;
+    assert_eq!(
+        (a.as_str(), b.as_str(), c.as_str()),
+        ("answer", "answer", "answer")
+    );
+    assert!(started_on.elapsed() < Duration::from_millis(100));
+    assert_eq!(computed.get(), 1);
+
+    // the clone awaited after completion resolves at once
+    assert_eq!(answer.await, "answer");
+    assert_eq!(computed.get(), 1);
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // the driver is dropped, the waiter takes over
+    let compute = {
+        let (rt, computed) = (rt.clone(), computed.clone());
+        async move {
+            toy::sleep(&rt, Duration::from_millis(50)).await;
+            computed.set(computed.get() + 1);
+            7
+        }
+    };
+    let value = toy::shared(&rt, compute);
+    let quick = toy::sleep(&rt, Duration::from_millis(10));
+    let (raced, waited) = toy::make_join2(toy::race2(value.clone(), quick), value).await;
+    assert!(matches!(raced, toy::Either::Right(())));
+    assert_eq!(waited, 7);
+    assert_eq!(computed.get(), 2);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_shared: done");
+}
+
+async fn test_select_builder(rt: Rc<toy::Runtime>) {
+    println!("\ntest_select_builder: the first of 3 branches wins, the rest are dropped");
+
+    struct Connection {
+        rt: Rc<toy::Runtime>,
+        closed: Rc<Cell<bool>>,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            let rt = self.rt.clone();
+            rt.nested_loop(async {
+                toy::sleep(&self.rt, Duration::from_millis(50)).await;
+                self.closed.set(true);
+            });
+        }
+    }
+
+    let closed = Rc::new(Cell::new(false));
+    let transfer = async {
+        let _connection = Connection {
+            rt: rt.clone(),
+            closed: closed.clone(),
+        };
+        toy::sleep(&rt, Duration::from_millis(1000)).await;
+    };
+
+    let started_on = Instant::now();
+    let winner = toy::Select::new()
+        .add(toy::sleep(&rt, Duration::from_millis(500)))
+        .add(async {
+            toy::make_rt_join2(&rt, transfer, async {}).await;
+        })
+        .add(toy::sleep(&rt, Duration::from_millis(30)))
+        .await;
+    assert_eq!(winner, Ok((2, ())));
+    assert!(
+        closed.get(),
+        "the task of the loser is destroyed with async cleanup"
+    );
+    assert!(started_on.elapsed() < Duration::from_millis(500));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    assert_eq!(toy::Select::<()>::new().await, Err(toy::NoBranches));
+    println!("test_select_builder: done");
+}
+
+async fn test_select_drop_order(rt: Rc<toy::Runtime>) {
+    println!("\ntest_select_drop_order: the losers are dropped last added first, one at a time");
+
+    struct Resource {
+        rt: Rc<toy::Runtime>,
+        name: &'static str,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            let rt = self.rt.clone();
+            rt.nested_loop(async {
+                self.log.borrow_mut().push(format!("{} closing", self.name));
+                toy::sleep(&self.rt, Duration::from_millis(20)).await;
+                self.log.borrow_mut().push(format!("{} closed", self.name));
+                println!("{} closed", self.name);
+            });
+        }
+    }
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let hold = |name| {
+        let resource = Resource {
+            rt: rt.clone(),
+            name,
+            log: log.clone(),
+        };
+        let rt = rt.clone();
+        async move {
+            let _resource = resource;
+            toy::sleep(&rt, Duration::from_millis(1000)).await;
+        }
+    };
+
+    let winner = toy::Select::new()
+        .add(hold("guard"))
+        .add(hold("connection"))
+        .add(toy::sleep(&rt, Duration::from_millis(30)))
+        .add(hold("stream"))
+        .await;
+    assert_eq!(winner, Ok((2, ())));
+    let expected = [
+        "stream closing",
+        "stream closed",
+        "connection closing",
+        "connection closed",
+        "guard closing",
+        "guard closed",
+    ];
+    assert_eq!(*log.borrow(), expected);
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // the pending futures of the two-way selects are dropped the second one first
+    let expected = [
+        "second closing",
+        "second closed",
+        "first closing",
+        "first closed",
+    ];
+    let timer = || toy::sleep(&rt, Duration::from_millis(30));
+
+    log.borrow_mut().clear();
+    let pair = toy::make_select2(hold("first"), hold("second"));
+    toy::make_select2(pair, timer()).await;
+    assert_eq!(*log.borrow(), expected);
+
+    log.borrow_mut().clear();
+    let pair = toy::race2(hold("first"), hold("second"));
+    toy::make_select2(pair, timer()).await;
+    assert_eq!(*log.borrow(), expected);
+
+    log.borrow_mut().clear();
+    let pair = toy::make_rt_select2(&rt, hold("first"), hold("second"));
+    toy::make_rt_select2(&rt, pair, timer()).await;
+    assert_eq!(*log.borrow(), expected);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_select_drop_order: done");
+}
+
+async fn test_join_adapters(rt: Rc<toy::Runtime>) {
+    println!("\ntest_join_adapters: map() and inspect() of the joins");
+    let sum = toy::make_join2(async { 40 }, async { 2 })
+        .map(|(a, b)| a + b)
+        .await;
+    assert_eq!(sum, 42);
+    let pair = toy::make_join2(async { 4 }, async { 2 })
+        .inspect(|pair| println!("joined {:?}", pair))
+        .await;
+    assert_eq!(pair, (4, 2));
+
+    let seen = Cell::new(None);
+    let pair = toy::make_rt_join2(&rt, async { 1 }, async { "one" })
+        .inspect(|&(number, _)| seen.set(Some(number)))
+        .await;
+    assert_eq!(pair, (1, "one"));
+    assert_eq!(seen.get(), Some(1));
+
+    // the mapped join loses the race, its tasks are destroyed with async cleanup
+    struct Connection {
+        rt: Rc<toy::Runtime>,
+        closed: Rc<Cell<bool>>,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            let rt = self.rt.clone();
+            rt.nested_loop(async {
+                toy::sleep(&self.rt, Duration::from_millis(50)).await;
+                self.closed.set(true);
+            });
+        }
+    }
+
+    let closed = Rc::new(Cell::new(false));
+    let transfer = async {
+        let _connection = Connection {
+            rt: rt.clone(),
+            closed: closed.clone(),
+        };
+        toy::sleep(&rt, Duration::from_millis(1000)).await;
+        1000
+    };
+    let mapped = toy::make_rt_join2(&rt, transfer, async { 1 }).map(|(a, b)| a + b);
+    let winner = toy::race2(mapped, toy::sleep(&rt, Duration::from_millis(30))).await;
+    assert!(matches!(winner, toy::Either::Right(())));
+    assert!(closed.get(), "the task of the mapped join is destroyed");
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_join_adapters: done");
+}
+
+async fn test_rt_chain(rt: Rc<toy::Runtime>) {
+    println!("\ntest_rt_chain: the chain loses the race and is destroyed with async cleanup");
+    let log = Rc::new(RefCell::new(Vec::new()));
+
+    struct Connection {
+        rt: Rc<toy::Runtime>,
+        name: &'static str,
+        log: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            let rt = self.rt.clone();
+            rt.nested_loop(async {
+                toy::sleep(&self.rt, Duration::from_millis(20)).await;
+                self.log.borrow_mut().push(self.name);
+            });
+        }
+    }
+
+    let connect = |name| Connection {
+        rt: rt.clone(),
+        name,
+        log: log.clone(),
+    };
+    let first = async {
+        toy::sleep(&rt, Duration::from_millis(10)).await;
+        connect("first")
+    };
+    let second = async {
+        let _connection = connect("second");
+        toy::sleep(&rt, Duration::from_millis(1000)).await;
+    };
+
+    let chain = toy::make_rt_chain2(&rt, first, second);
+    let winner = toy::race2(chain, toy::sleep(&rt, Duration::from_millis(50))).await;
+    assert!(matches!(winner, toy::Either::Right(())));
+    assert_eq!(log.take(), ["second", "first"]);
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    let ((), value) = toy::make_rt_chain2(&rt, async {}, async { 7 }).await;
+    assert_eq!(value, 7);
+    println!("test_rt_chain: done");
+}
+
+async fn test_join_state(rt: Rc<toy::Runtime>) {
+    println!("\ntest_join_state: which side of the join is done");
+    let nap = |ms| toy::sleep(&rt, Duration::from_millis(ms));
+
+    let mut join = Box::pin(toy::make_join2(nap(10), nap(30)));
+    assert_eq!(join.state(), toy::JoinState::default());
+    let mut states = Vec::new();
+    std::future::poll_fn(|ctx| {
+        let poll = join.as_mut().poll(ctx);
+        println!("{:?}", join);
+        states.push(join.state());
+        poll
+    })
+    .await;
+    let done = |first_done, second_done| toy::JoinState {
+        first_done,
+        second_done,
+        ..toy::JoinState::default()
+    };
+    assert_eq!(
+        states,
+        [done(false, false), done(true, false), done(true, true)]
+    );
+
+    // the first task completes while the second one is frozen in its nested loop
+    let task_b = async {
+        rt.nested_loop(nap(50));
+        nap(20).await;
+    };
+    let mut join = Box::pin(toy::make_rt_join2(&rt, nap(10), task_b));
+    let mut states = Vec::new();
+    std::future::poll_fn(|ctx| {
+        let poll = join.as_mut().poll(ctx);
+        println!("{:?}", join);
+        states.push(join.state());
+        poll
+    })
+    .await;
+    assert_eq!(states, [done(true, false), done(true, true)]);
+    println!("test_join_state: done");
+}
+
+async fn test_rt_join_outputs(rt: Rc<toy::Runtime>) {
+    println!("\ntest_rt_join_outputs: rt_join resolves to the outputs of the tasks");
+
+    async fn quick(rt: Rc<toy::Runtime>) -> String {
+        toy::sleep(&rt, Duration::from_millis(20)).await;
+        "quick".to_string()
+    }
+
+    // quick() completes while this one is in the nested loop
+    async fn slow(rt: Rc<toy::Runtime>) -> u32 {
+        rt.nested_loop(toy::sleep(&rt, Duration::from_millis(100)));
+        7
+    }
+
+    let (name, count) = toy::make_rt_join2(&rt, quick(rt.clone()), slow(rt.clone())).await;
+    assert_eq!(name, "quick");
+    assert_eq!(count, 7);
+    println!("test_rt_join_outputs: done");
+}
+
+async fn test_interval(rt: Rc<toy::Runtime>) {
+    println!("\ntest_interval: ticks are anchored to the start, the missed ticks");
+    let started_on = Instant::now();
+    let mut interval = toy::interval(&rt, Duration::from_millis(100));
+    let mut elapsed = Duration::ZERO;
+    for n in 1..=5 {
+        let deadline = interval.tick().await;
+        elapsed = started_on.elapsed();
+        assert!(deadline - started_on >= interval.period() * n);
+        // the work between ticks does not make them drift
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    println!("5 ticks in {:?}", elapsed);
+    assert!(elapsed >= Duration::from_millis(500) && elapsed < Duration::from_millis(550));
+
+    // Ticks, blocks for 130ms in the second period and returns the time of the next two ticks
+    async fn late_ticks(rt: &Rc<toy::Runtime>, missed_tick: toy::MissedTick) -> (u128, u128) {
+        let started_on = Instant::now();
+        let mut interval = toy::interval(rt, Duration::from_millis(50));
+        interval.set_missed_tick(missed_tick);
+        interval.tick().await;
+        std::thread::sleep(Duration::from_millis(130));
+        interval.tick().await;
+        let late = started_on.elapsed().as_millis();
+        interval.tick().await;
+        (late, started_on.elapsed().as_millis())
+    }
+
+    let (late, next) = late_ticks(&rt, toy::MissedTick::Delay).await;
+    println!(
+        "delay: the late tick at {}ms, the next one at {}ms",
+        late, next
+    );
+    assert!((180..200).contains(&late) && (230..250).contains(&next));
+    let (late, next) = late_ticks(&rt, toy::MissedTick::Skip).await;
+    println!(
+        "skip: the late tick at {}ms, the next one at {}ms",
+        late, next
+    );
+    assert!((180..200).contains(&late) && (200..220).contains(&next));
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_interval: done");
+}
+
+async fn test_reactor_interval(rt: Rc<toy::Runtime>) {
+    println!("\ntest_reactor_interval: ticker made on top of the reactor interval");
+
+    // Completes after given number of ticks of the interval timer, returns when they have fired
+    struct Ticks {
+        rt: Rc<toy::Runtime>,
+        period: Duration,
+        event_id: Option<toy::EventId>,
+        remaining: usize,
+        fired: Vec<Instant>,
+    }
+
+    impl Future for Ticks {
+        type Output = Vec<Instant>;
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+            let event_id = match self.event_id {
+                Some(event_id) => event_id,
+                None => {
+                    let event_id = self.rt.reactor().add_interval(ctx.waker(), self.period);
+                    self.event_id = Some(event_id);
+                    return Poll::Pending;
+                }
+            };
+
+            if self.rt.is_awoken(event_id) {
+                self.fired.push(Instant::now());
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    self.rt.retire_event(event_id);
+                    self.event_id = None;
+                    return Poll::Ready(std::mem::take(&mut self.fired));
+                }
+            }
+            Poll::Pending
+        }
+    }
+
+    impl Drop for Ticks {
+        fn drop(&mut self) {
+            if let Some(event_id) = self.event_id {
+                self.rt.retire_event(event_id);
+            }
+        }
+    }
+
+    let ticks = |period, count| Ticks {
+        rt: rt.clone(),
+        period,
+        event_id: None,
+        remaining: count,
+        fired: Vec::new(),
+    };
+
+    let started_on = Instant::now();
+    let fired = ticks(Duration::from_millis(50), 4).await;
+    println!(
+        "ticks: {:?}",
+        fired.iter().map(|at| *at - started_on).collect::<Vec<_>>()
+    );
+    // the ticks are anchored to the start, so there is no drift
+    for (n, at) in fired.iter().enumerate() {
+        let expected = Duration::from_millis(50) * (n as u32 + 1);
+        let drift = *at - started_on - expected;
+        assert!(
+            drift < Duration::from_millis(20),
+            "tick {} drifted {:?}",
+            n,
+            drift
+        );
+    }
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // zero period does not spin
+    let started_on = Instant::now();
+    ticks(Duration::ZERO, 10).await;
+    assert!(started_on.elapsed() >= toy::MIN_INTERVAL_PERIOD * 10);
+    println!("test_reactor_interval: done");
+}
+
+async fn test_peek_next_deadline(rt: Rc<toy::Runtime>) {
+    println!("\ntest_peek_next_deadline: the next deadline is known before wait");
+    assert_eq!(rt.reactor().peek_next_deadline(), None);
+
+    let started_on = Instant::now();
+    let ms = Duration::from_millis;
+    // polled after the sleeps have registered their timers
+    let probe = async {
+        let deadline = rt.reactor().peek_next_deadline().unwrap();
+        let expected = started_on + ms(100);
+        assert!(deadline >= expected && deadline < expected + ms(20));
+        assert_eq!(rt.reactor().timer_count(), 3);
+    };
+
+    toy::make_join4(
+        toy::sleep(&rt, ms(300)),
+        toy::sleep(&rt, ms(100)),
+        toy::sleep(&rt, ms(200)),
+        probe,
+    )
+    .await;
+    assert_eq!(rt.reactor().peek_next_deadline(), None);
+    println!("test_peek_next_deadline: done");
+}
+
+async fn test_endless_sleep(rt: Rc<toy::Runtime>) {
+    println!("\ntest_endless_sleep: sleep for Duration::MAX never fires");
+    let result = toy::sleep(&rt, Duration::MAX)
+        .timeout(&rt, Duration::from_millis(50))
+        .await;
+    assert_eq!(result, Err(toy::Elapsed));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    rt.nested_loop_with_deadline(Instant::now() + Duration::from_millis(50), async {
+        let slept = toy::sleep_remaining(&rt, Duration::MAX).await;
+        assert_eq!(slept, toy::Slept::Truncated);
+    });
+    println!("test_endless_sleep: done");
+}
+
+async fn test_rt_select(rt: Rc<toy::Runtime>) {
+    println!("\ntest_rt_select: the loser of the race is destroyed with async cleanup");
+
+    // Connection which async cleanup is done in drop
+    struct Connection {
+        rt: Rc<toy::Runtime>,
+        closed: Rc<Cell<bool>>,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            let rt = self.rt.clone();
+            rt.nested_loop(async {
+                toy::sleep(&self.rt, Duration::from_millis(50)).await;
+                self.closed.set(true);
+            });
+        }
+    }
+
+    async fn winner(rt: Rc<toy::Runtime>) -> u32 {
+        toy::sleep(&rt, Duration::from_millis(20)).await;
+        5
+    }
+
+    // Is frozen in its own nested loop when the winner completes
+    async fn loser(rt: Rc<toy::Runtime>, closed: Rc<Cell<bool>>) -> &'static str {
+        let _connection = Connection {
+            rt: rt.clone(),
+            closed,
+        };
+        rt.nested_loop(toy::sleep(&rt, Duration::from_millis(100)));
+        toy::sleep(&rt, Duration::from_millis(1000)).await;
+        "loser"
+    }
+
+    let started_on = Instant::now();
+    let closed = Rc::new(Cell::new(false));
+    let result =
+        toy::make_rt_select2(&rt, loser(rt.clone(), closed.clone()), winner(rt.clone())).await;
+    let elapsed = started_on.elapsed();
+    println!("select done in {:?}", elapsed);
+    assert_eq!(result, toy::Either::Right(5));
+    assert!(closed.get(), "the loser cleanup must be completed");
+    assert!(elapsed >= Duration::from_millis(150) && elapsed < Duration::from_millis(500));
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_rt_select: done");
+}
+
+async fn test_rt_join_catching(rt: Rc<toy::Runtime>) {
+    println!("\ntest_rt_join_catching: the panic of one task does not stop the other one");
+    let started_on = Instant::now();
+    let slept = Cell::new(false);
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = toy::make_rt_join2_catching(&rt, async { panic!("broken task") }, async {
+        toy::sleep(&rt, Duration::from_millis(1000)).await;
+        slept.set(true);
+    })
+    .await;
+    std::panic::set_hook(default_hook);
+
+    let panics = result.unwrap_err();
+    assert_eq!(panics.len(), 1);
+    assert_eq!(*panics[0].downcast_ref::<&str>().unwrap(), "broken task");
+    assert!(slept.get());
+    assert!(started_on.elapsed() >= Duration::from_millis(1000));
+
+    let result = toy::make_rt_join2_catching(&rt, async {}, async {}).await;
+    assert!(result.is_ok());
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // the cleanup panics in its nested loop: the depth of the loop is restored, so the next
+    // nested loop does not exceed the limit
+    rt.set_max_nested_depth(1);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let broken_cleanup = async {
+        toy::sleep(&rt, Duration::from_millis(10)).await;
+        rt.nested_loop(async {
+            toy::sleep(&rt, Duration::from_millis(10)).await;
+            panic!("broken cleanup");
+        });
+    };
+    let sibling = toy::sleep(&rt, Duration::from_millis(50));
+    let result = toy::make_rt_join2_catching(&rt, broken_cleanup, sibling).await;
+    std::panic::set_hook(default_hook);
+
+    let panics = result.unwrap_err();
+    assert_eq!(*panics[0].downcast_ref::<&str>().unwrap(), "broken cleanup");
+    rt.nested_loop(toy::sleep(&rt, Duration::from_millis(10)));
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_rt_join_catching: done");
+}
+
+async fn test_rt_try_join(rt: Rc<toy::Runtime>) {
+    println!("\ntest_rt_try_join: the sibling of the failed task is destroyed with async cleanup");
+
+    struct Connection {
+        rt: Rc<toy::Runtime>,
+        closed: Rc<Cell<bool>>,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            let rt = self.rt.clone();
+            rt.nested_loop(async {
+                toy::sleep(&self.rt, Duration::from_millis(50)).await;
+                self.closed.set(true);
+            });
+        }
+    }
+
+    async fn fail(rt: Rc<toy::Runtime>, ms: u64) -> Result<(), &'static str> {
+        toy::sleep(&rt, Duration::from_millis(ms)).await;
+        Err("failed")
+    }
+
+    // Runs its own nested loop when the other task fails, the error is handled after the loop
+    async fn transfer(rt: Rc<toy::Runtime>, closed: Rc<Cell<bool>>) -> Result<u32, String> {
+        let _connection = Connection {
+            rt: rt.clone(),
+            closed,
+        };
+        rt.nested_loop(toy::sleep(&rt, Duration::from_millis(100)));
+        toy::sleep(&rt, Duration::from_millis(1000)).await;
+        Ok(7)
+    }
+
+    let started_on = Instant::now();
+    let closed = Rc::new(Cell::new(false));
+    let result = toy::make_rt_try_join2(
+        &rt,
+        transfer(rt.clone(), closed.clone()),
+        fail(rt.clone(), 20),
+    )
+    .await;
+    assert_eq!(result, Err(toy::Either::Right("failed")));
+    assert!(closed.get(), "the sibling cleanup must be completed");
+    let elapsed = started_on.elapsed();
+    assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_millis(500));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    let ok = async { Ok::<_, ()>("ok") };
+    let result = toy::make_rt_try_join2(&rt, ok, async { Ok::<_, ()>(1) }).await;
+    assert_eq!(result, Ok(("ok", 1)));
+    println!("test_rt_try_join: done");
+}
+
+async fn test_rt_join_deadline(rt: Rc<toy::Runtime>) {
+    println!("\ntest_rt_join_deadline: the unfinished task is destroyed on deadline");
+
+    struct Connection {
+        rt: Rc<toy::Runtime>,
+        closed: Rc<Cell<bool>>,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            let rt = self.rt.clone();
+            rt.nested_loop(async {
+                toy::sleep(&self.rt, Duration::from_millis(50)).await;
+                self.closed.set(true);
+            });
+        }
+    }
+
+    async fn transfer(rt: Rc<toy::Runtime>, ms: u64, closed: Rc<Cell<bool>>) -> u64 {
+        let _connection = Connection {
+            rt: rt.clone(),
+            closed,
+        };
+        toy::sleep(&rt, Duration::from_millis(ms)).await;
+        ms
+    }
+
+    let started_on = Instant::now();
+    let closed1 = Rc::new(Cell::new(false));
+    let closed2 = Rc::new(Cell::new(false));
+    let result = toy::make_rt_join2_deadline(
+        &rt,
+        Duration::from_secs(2),
+        transfer(rt.clone(), 1000, closed1.clone()),
+        transfer(rt.clone(), 5000, closed2.clone()),
+    )
+    .await;
+    assert_eq!(result, Err((toy::Elapsed, Some(1000), None)));
+    assert!(closed1.get() && closed2.get());
+    let elapsed = started_on.elapsed();
+    assert!(elapsed >= Duration::from_secs(2) && elapsed < Duration::from_millis(2500));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // the deadline passes while the task runs its nested loop, it is destroyed once the loop is
+    // done
+    let started_on = Instant::now();
+    let frozen = async {
+        toy::sleep(&rt, Duration::from_millis(5)).await;
+        rt.nested_loop(toy::sleep(&rt, Duration::from_millis(100)));
+        toy::sleep(&rt, Duration::from_millis(1000)).await;
+    };
+    let result =
+        toy::make_rt_join2_deadline(&rt, Duration::from_millis(20), frozen, async { 1 }).await;
+    assert_eq!(result, Err((toy::Elapsed, None, Some(1))));
+    let elapsed = started_on.elapsed();
+    assert!(elapsed >= Duration::from_millis(105) && elapsed < Duration::from_millis(500));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    let result =
+        toy::make_rt_join2_deadline(&rt, Duration::from_secs(1), async { "ok" }, async { 1 }).await;
+    assert_eq!(result, Ok(("ok", 1)));
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_rt_join_deadline: done");
+}
+
+async fn test_rt_task_set(rt: Rc<toy::Runtime>) {
+    println!("\ntest_rt_task_set: tasks are pushed into the set while it runs");
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let worker = |name, ms, cleanup_ms| {
+        let (rt, log) = (rt.clone(), log.clone());
+        async move {
+            toy::sleep(&rt, Duration::from_millis(ms)).await;
+            if cleanup_ms > 0 {
+                rt.nested_loop(toy::sleep(&rt, Duration::from_millis(cleanup_ms)));
+            }
+            log.borrow_mut().push(name);
+        }
+    };
+
+    let mut set = toy::RtTaskSet::new();
+    set.push(&rt, worker("a", 10, 0));
+    assert!(poll_once(&mut set).await.is_pending());
+
+    // pushed after the first poll: "c" is delivered while "b" is frozen in its nested loop
+    set.push(&rt, worker("b", 10, 50));
+    set.push(&rt, worker("c", 30, 0));
+    assert_eq!(set.len(), 3);
+    (&mut set).await;
+    assert!(set.is_empty());
+    assert_eq!(log.take(), ["a", "c", "b"]);
+
+    // the set can be reused
+    set.push(&rt, worker("d", 10, 0));
+    set.await;
+    assert_eq!(log.take(), ["d"]);
+    println!("test_rt_task_set: done");
+}
+
+async fn test_spawn(rt: Rc<toy::Runtime>) {
+    println!("\ntest_spawn: the detached task runs while main sleeps");
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let handle = rt.spawn({
+        let (rt, log) = (rt.clone(), log.clone());
+        async move {
+            toy::sleep(&rt, Duration::from_millis(30)).await;
+            log.borrow_mut().push("spawned");
+            42
+        }
+    });
+    toy::sleep(&rt, Duration::from_millis(10)).await;
+    log.borrow_mut().push("main");
+    assert_eq!(handle.await, 42);
+    assert_eq!(log.take(), ["main", "spawned"]);
+
+    // dropping the handle cancels the task
+    let handle = rt.spawn({
+        let (rt, log) = (rt.clone(), log.clone());
+        async move {
+            toy::sleep(&rt, Duration::from_secs(10)).await;
+            log.borrow_mut().push("cancelled");
+        }
+    });
+    toy::sleep(&rt, Duration::from_millis(10)).await;
+    assert_eq!(rt.reactor().timer_count(), 1);
+    drop(handle);
+    assert_eq!(rt.reactor().timer_count(), 0);
+    assert!(log.borrow().is_empty());
+
+    // aborted mid-sleep, the handle is still alive
+    let handle = rt.spawn({
+        let (rt, log) = (rt.clone(), log.clone());
+        async move {
+            toy::sleep(&rt, Duration::from_secs(10)).await;
+            log.borrow_mut().push("aborted");
+        }
+    });
+    toy::sleep(&rt, Duration::from_millis(10)).await;
+    assert_eq!(rt.reactor().timer_count(), 1);
+    handle.abort();
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // the abort of the frozen task is completed once its nested loop is done
+    let frozen = rt.spawn({
+        let (rt, log) = (rt.clone(), log.clone());
+        async move {
+            rt.nested_loop(toy::sleep(&rt, Duration::from_millis(50)));
+            log.borrow_mut().push("unfrozen");
+            toy::sleep(&rt, Duration::from_secs(10)).await;
+            log.borrow_mut().push("aborted");
+        }
+    });
+    toy::sleep(&rt, Duration::from_millis(10)).await;
+    frozen.abort();
+    assert_eq!(rt.reactor().timer_count(), 1);
+    toy::sleep(&rt, Duration::from_millis(100)).await;
+    assert_eq!(rt.reactor().timer_count(), 0);
+    assert_eq!(log.take(), ["unfrozen"]);
+    println!("test_spawn: done");
+}
+
+async fn test_for_each_concurrent(rt: Rc<toy::Runtime>) {
+    println!("\ntest_for_each_concurrent: 100 sleeps, no more than 8 at a time");
+    let in_flight = Rc::new(Cell::new(0));
+    let max_in_flight = Rc::new(Cell::new(0));
+    let max_timers = Rc::new(Cell::new(0));
+    let worker = |n: u64| {
+        let (rt, in_flight) = (rt.clone(), in_flight.clone());
+        let (max_in_flight, max_timers) = (max_in_flight.clone(), max_timers.clone());
+        async move {
+            // the timers of the other workers
+            max_timers.set(max_timers.get().max(rt.reactor().timer_count()));
+            in_flight.set(in_flight.get() + 1);
+            max_in_flight.set(max_in_flight.get().max(in_flight.get()));
+            toy::sleep(&rt, Duration::from_millis(5 + n % 7)).await;
+            in_flight.set(in_flight.get() - 1);
+        }
+    };
+
+    let result = toy::for_each_concurrent(&rt, 8, (0..100).map(worker)).await;
+    assert_eq!(result, Ok(()));
+    assert_eq!(max_in_flight.get(), 8);
+    assert_eq!(max_timers.get(), 7);
+    assert_eq!(in_flight.get(), 0);
+
+    // all at once
+    let started_on = Instant::now();
+    toy::for_each_concurrent(&rt, 100, (0..10).map(worker))
+        .await
+        .unwrap();
+    assert!(started_on.elapsed() < Duration::from_millis(100));
+
+    let result = toy::for_each_concurrent(&rt, 0, (0..10).map(worker)).await;
+    assert_eq!(result, Err(toy::ZeroLimit));
+    println!("test_for_each_concurrent: done");
+}
+
+async fn test_join_stream(rt: Rc<toy::Runtime>) {
+    println!("\ntest_join_stream: a task per tick, the last one runs a nested loop");
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut count = 0;
+    let handler = |_tick: Instant| {
+        count += 1;
+        let (rt, log, n) = (rt.clone(), log.clone(), count);
+        async move {
+            if n == 5 {
+                // the stream has ended by now, the handlers 3 and 4 keep going while this one is
+                // frozen
+                rt.nested_loop(toy::sleep(&rt, Duration::from_millis(100)));
+                toy::sleep(&rt, Duration::from_millis(10)).await;
+            } else {
+                toy::sleep(&rt, Duration::from_millis(50)).await;
+            }
+            log.borrow_mut().push(n);
+        }
+    };
+
+    let started_on = Instant::now();
+    let ticks = toy::interval(&rt, Duration::from_millis(20)).take(5);
+    toy::join_stream(&rt, ticks, handler).await;
+    let elapsed = started_on.elapsed();
+    println!("join_stream done in {:?}", elapsed);
+    assert_eq!(*log.borrow(), vec![1, 2, 3, 4, 5]);
+    assert!(elapsed >= Duration::from_millis(210));
+    assert!(elapsed < Duration::from_millis(300));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // the stream that has ended at once
+    let ticks = toy::interval(&rt, Duration::from_millis(20)).take(0);
+    let started_on = Instant::now();
+    toy::join_stream(&rt, ticks, |_| async {}).await;
+    assert!(started_on.elapsed() < Duration::from_millis(20));
+    println!("test_join_stream: done");
+}
+
+async fn test_rt_join_all(rt: Rc<toy::Runtime>) {
+    println!("\ntest_rt_join_all: 50 tasks, some of them run nested loops");
+
+    async fn worker(rt: Rc<toy::Runtime>, n: u64) -> u64 {
+        toy::sleep(&rt, Duration::from_millis(n * 3)).await;
+        if n.is_multiple_of(10) {
+            // other workers keep going while this one is frozen
+            rt.nested_loop(toy::sleep(&rt, Duration::from_millis(100)));
+        }
+        toy::sleep(&rt, Duration::from_millis(10)).await;
+        n
+    }
+
+    let started_on = Instant::now();
+    let workers = (0..50).map(|n| worker(rt.clone(), n)).collect();
+    let outputs = toy::make_rt_join_all(&rt, workers).await;
+    let elapsed = started_on.elapsed();
+    println!("rt_join_all done in {:?}", elapsed);
+    assert_eq!(outputs, (0..50).collect::<Vec<_>>());
+    // the nested loops overlap with other workers
+    assert!(elapsed < Duration::from_millis(400));
+    println!("test_rt_join_all: done");
+}
+
+async fn test_many_timers(rt: Rc<toy::Runtime>) {
+    println!("\ntest_many_timers: 10000 timers fire in deadline order");
+
+    // Polls all the futures till they are completed
+    struct PollAll(Vec<Option<Pin<Box<dyn Future<Output = ()>>>>>);
+
+    impl Future for PollAll {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+            let mut pending = false;
+            for slot in self.0.iter_mut() {
+                if let Some(fut) = slot {
+                    match fut.as_mut().poll(ctx) {
+                        Poll::Ready(()) => *slot = None,
+                        Poll::Pending => pending = true,
+                    }
+                }
+            }
+            if pending {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        }
+    }
+
+    const TIMERS: u64 = 10000;
+    let started_on = Instant::now();
+    // the deadlines are not passed by the time all the sleeps are polled, the sleep with passed
+    // deadline completes right away
+    let base = started_on + Duration::from_millis(100);
+    let fired = Rc::new(RefCell::new(Vec::new()));
+    let sleeps = (0..TIMERS).map(|i| {
+        // deadlines are shuffled within 50ms
+        let deadline = base + Duration::from_micros(i * 7919 % 50000);
+        let rt = rt.clone();
+        let fired = fired.clone();
+        let fut: Pin<Box<dyn Future<Output = ()>>> = Box::pin(async move {
+            toy::sleep_until(&rt, deadline).await;
+            fired.borrow_mut().push(deadline);
+        });
+        Some(fut)
+    });
+    PollAll(sleeps.collect()).await;
+
+    let fired = fired.borrow();
+    println!("{} timers fired in {:?}", fired.len(), started_on.elapsed());
+    assert_eq!(fired.len(), TIMERS as usize);
+    assert!(fired.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert_eq!(rt.reactor().timer_count(), 0);
+    println!("test_many_timers: done");
+}
+
+async fn test_cpu_accounting(rt: Rc<toy::Runtime>) {
+    println!("\ntest_cpu_accounting: time of nested loop goes to the cleanup task");
+
+    async fn slow_cleanup(rt: &Rc<toy::Runtime>) {
+        toy::sleep(rt, Duration::from_millis(10)).await;
+        std::thread::sleep(Duration::from_millis(100)); // deliberately slow poll
+    }
+
+    toy::sleep(&rt, Duration::from_millis(10)).await;
+    rt.nested_loop(slow_cleanup(&rt));
+
+    let top: Vec<toy::TaskCpu> = rt.metrics().top_cpu_tasks(2);
+    println!("{:#?}", top);
+    if cfg!(feature = "trace") {
+        assert_eq!(top.len(), 2);
+        assert!(top[0].name().contains("slow_cleanup"));
+        assert!(top[0].busy() >= Duration::from_millis(100));
+        assert_eq!(top[0].polls(), 2);
+        // the frozen parent is not charged for the cleanup
+        assert!(top[1].busy() < Duration::from_millis(50));
+    }
+
+    // the statistics of the gone tasks are bounded, the slow cleanup is still among them
+    toy::make_rt_join_all(&rt, (0..1000).map(|_| async {}).collect()).await;
+    let all = rt.metrics().top_cpu_tasks(usize::MAX);
+    println!("{} tasks are accounted after 1000 are gone", all.len());
+    if cfg!(feature = "trace") {
+        assert!(all.len() < 100);
+        assert!(all[0].name().contains("slow_cleanup"));
+    }
+    println!("test_cpu_accounting: done");
+}
+
+async fn test_foreign_waker(rt: Rc<toy::Runtime>) {
+    println!("\ntest_foreign_waker: sleeps polled with the wakers of a combinator");
+
+    // The waker of a combinator that either forwards the wake to the task waker or only
+    // remembers it, like the ones that queue the woken children to poll them later.
+    struct WrapWaker {
+        task_waker: std::task::Waker,
+        forward: bool,
+        woken: std::sync::atomic::AtomicBool,
+    }
+
+    impl std::task::Wake for WrapWaker {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &std::sync::Arc<Self>) {
+            self.woken.store(true, std::sync::atomic::Ordering::Relaxed);
+            if self.forward {
+                self.task_waker.wake_by_ref();
+            }
+        }
+    }
+
+    struct Wrapped<FutT> {
+        fut: Pin<Box<FutT>>,
+        forward: bool,
+    }
+
+    impl<FutT: Future> Future for Wrapped<FutT> {
+        type Output = FutT::Output;
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+            let waker = std::task::Waker::from(std::sync::Arc::new(WrapWaker {
+                task_waker: ctx.waker().clone(),
+                forward: self.forward,
+                woken: Default::default(),
+            }));
+            self.fut.as_mut().poll(&mut Context::from_waker(&waker))
+        }
+    }
+
+    let wrap = |duration, forward| Wrapped {
+        fut: Box::pin(toy::sleep(&rt, Duration::from_millis(duration))),
+        forward,
+    };
+
+    let started_on = Instant::now();
+    wrap(100, true).join(wrap(200, false)).await;
+    assert!(started_on.elapsed() >= Duration::from_millis(200));
+
+    rt.nested_loop(wrap(100, false));
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // the task of a waker is told without waking it
+    std::future::poll_fn(|ctx| {
+        let wrap_waker = std::sync::Arc::new(WrapWaker {
+            task_waker: ctx.waker().clone(),
+            forward: true,
+            woken: Default::default(),
+        });
+        let waker = std::task::Waker::from(wrap_waker.clone());
+        assert_eq!(rt.task_id(&waker), None);
+        assert!(rt.task_id(ctx.waker()).is_some());
+        assert!(!wrap_waker.woken.load(std::sync::atomic::Ordering::Relaxed));
+        Poll::Ready(())
+    })
+    .await;
+
+    // the event of a foreign waker fires in the nested loop while the task waiting for it is
+    // frozen, it is delivered when the task unfreezes
+    let started_on = Instant::now();
+    let mut frozen = Box::pin(wrap(50, false));
+    let mut nested = false;
+    std::future::poll_fn(|ctx| {
+        if frozen.as_mut().poll(ctx).is_ready() {
+            return Poll::Ready(());
+        }
+        if !nested {
+            nested = true;
+            rt.nested_loop(toy::sleep(&rt, Duration::from_millis(100)));
+        }
+        Poll::Pending
+    })
+    .await;
+    assert!(started_on.elapsed() >= Duration::from_millis(100));
+    assert_eq!(rt.frozen_event_count(), 0);
+    println!("test_foreign_waker: done");
+}
+
+fn test_deadlock_history() {
+    println!("\ntest_deadlock_history: panic on a stuck task tells the last runtime events");
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(|| {
+        toy::run(|rt| async move {
+            toy::sleep(&rt, Duration::from_millis(10)).await;
+            toy::sleep(&rt, Duration::from_millis(10)).await;
+            // nothing is going to wake this cleanup up
+            rt.nested_loop(std::future::pending());
+        })
+    });
+    std::panic::set_hook(default_hook);
+
+    let message = result.unwrap_err().downcast::<String>().unwrap();
+    println!("{}", message);
+    // debug builds catch it earlier, as soon as the cleanup returns Poll::Pending
+    if cfg!(debug_assertions) {
+        assert!(message.starts_with("contract violation"));
+    } else {
+        assert!(message.starts_with("deadlock"));
+    }
+    let expected = [
+        "TimerAdded(EventId(1))",
+        "TimerFired(EventId(1))",
+        "TimerAdded(EventId(2))",
+        "TimerFired(EventId(2))",
+        "LoopEntered(1)",
+    ];
+    let positions: Vec<_> = expected
+        .iter()
+        .map(|record| message.find(record).expect(record))
+        .collect();
+    assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+    println!("test_deadlock_history: done");
+}
+
+fn test_contract_checks() {
+    println!("\ntest_contract_checks: futures that break the poll contract are reported");
+
+    // Breaks the Future contract: polls the inner future once more after it is completed.
+    struct PollAfterReady<FutT>(Pin<Box<FutT>>);
+
+    impl<FutT: Future> Future for PollAfterReady<FutT> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+            match self.0.as_mut().poll(ctx) {
+                Poll::Ready(_) => {
+                    let _ = self.0.as_mut().poll(ctx);
+                    Poll::Ready(())
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    // Naive yield: wakes itself and expects to be polled again.
+    struct SelfWake(bool);
+
+    impl Future for SelfWake {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            ctx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    fn panic_message<FutT: Future<Output = ()>>(fut: FutT) -> String {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(|_| {}));
+        let fut = std::cell::Cell::new(Some(fut));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            toy::run(|_| fut.take().unwrap())
+        }));
+        std::panic::set_hook(default_hook);
+        let message = result.unwrap_err().downcast::<String>().unwrap();
+        println!("{}", message);
+        *message
+    }
+
+    let message = panic_message(PollAfterReady(Box::pin(async {}.checked())));
+    assert!(message.starts_with("FuturePolledAfterCompletion"));
+
+    if cfg!(debug_assertions) {
+        let message = panic_message(std::future::pending());
+        assert!(message.starts_with("contract violation"));
+        assert!(message.contains(&format!("created at {}:", file!())));
+        assert!(message.contains("Poll::Pending without an event"));
+
+        let message = panic_message(SelfWake(false));
+        assert!(message.starts_with("contract violation"));
+        assert!(message.contains("SelfWake"));
+    }
+    println!("test_contract_checks: done");
+}
+
+async fn test_defer_dispatch(rt: Rc<toy::Runtime>) {
+    println!("\ntest_defer_dispatch: events of other tasks wait for the guarded sections");
+    let log = Rc::new(RefCell::new(Vec::new()));
+
+    let sections = {
+        let (rt, log) = (rt.clone(), log.clone());
+        async move {
+            toy::sleep(&rt, Duration::from_millis(10)).await;
+            let section = |name| {
+                let (rt, log) = (rt.clone(), log.clone());
+                async move {
+                    toy::sleep(&rt, Duration::from_millis(100)).await;
+                    log.borrow_mut().push(name);
+                }
+            };
+
+            // two back-to-back sections, the first guard is dropped when the second is alive
+            let guard1 = rt.defer_dispatch();
+            rt.nested_loop(section("section 1"));
+            let guard2 = rt.defer_dispatch();
+            drop(guard1);
+            rt.nested_loop(section("section 2"));
+            drop(guard2);
+            log.borrow_mut().push("guards dropped");
+        }
+    };
+
+    let timer = |ms, name| {
+        let (rt, log) = (rt.clone(), log.clone());
+        async move {
+            toy::sleep(&rt, Duration::from_millis(ms)).await;
+            log.borrow_mut().push(name);
+        }
+    };
+    // timer 1 fires in the first section, timer 2 in the second one
+    let timers = toy::make_rt_join2(&rt, timer(50, "timer 1"), timer(150, "timer 2"));
+    toy::make_rt_join2(&rt, sections, timers).await;
+
+    assert_eq!(
+        *log.borrow(),
+        [
+            "section 1",
+            "section 2",
+            "guards dropped",
+            "timer 1",
+            "timer 2"
+        ]
+    );
+    println!("test_defer_dispatch: done");
+}
+
+async fn test_observer(rt: Rc<toy::Runtime>) {
+    println!("\ntest_observer: the event of the destroyed task is observed");
+
+    struct GoneCounter(Rc<Cell<u32>>);
+
+    impl toy::RuntimeObserver for GoneCounter {
+        fn on_poll_gone(&self, _task: toy::TaskId) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    let gone = Rc::new(Cell::new(0));
+    rt.set_observer(Box::new(GoneCounter(gone.clone())));
+
+    // Fires while the task is frozen in its nested loop and it is not cancelled on drop, so it is
+    // delivered after the task has lost the race and has been destroyed.
+    let interval = Cell::new(None);
+    let leaky_interval = std::future::poll_fn(|ctx| {
+        let event_id = rt
+            .reactor()
+            .add_interval(ctx.waker(), Duration::from_millis(100));
+        interval.set(Some(event_id));
+        Poll::Ready(())
+    });
+    let loser = async {
+        leaky_interval.await;
+        rt.nested_loop(toy::sleep(&rt, Duration::from_millis(150)));
+        toy::sleep(&rt, Duration::from_millis(1000)).await;
+    };
+    let winner = toy::sleep(&rt, Duration::from_millis(120));
+    let result = toy::make_rt_select2(&rt, winner, loser).await;
+    assert!(matches!(result, toy::Either::Left(())));
+
+    // the frozen events are delivered by the loop after the poll
+    toy::sleep(&rt, Duration::from_millis(10)).await;
+    rt.retire_event(interval.get().unwrap());
+    assert_eq!(gone.get(), 1);
+    println!("test_observer: done");
+}
+
+async fn test_task_ids(rt: Rc<toy::Runtime>) {
+    println!("\ntest_task_ids: sibling tasks have distinct ids and the same parent");
+
+    type Ids = (Option<toy::TaskId>, Option<toy::TaskId>);
+    // The ids of the task that polls it and of its parent
+    let ids = || {
+        let rt = rt.clone();
+        std::future::poll_fn(move |ctx| -> Poll<Ids> {
+            Poll::Ready((rt.task_id(ctx.waker()), rt.parent_task_id(ctx.waker())))
+        })
+    };
+
+    let (root, no_parent) = ids().await;
+    assert!(root.is_some() && no_parent.is_none());
+
+    let ((id1, parent1), (id2, parent2)) = toy::make_rt_join2(&rt, ids(), ids()).await;
+    assert!(id1.is_some() && id2.is_some());
+    assert_ne!(id1, id2);
+    assert_ne!(id1, root);
+    assert_eq!(parent1, root);
+    assert_eq!(parent2, root);
+    println!("test_task_ids: done");
+}
+
+async fn test_task_locations(rt: Rc<toy::Runtime>) {
+    println!("\ntest_task_locations: tasks know where they have been created");
+
+    // Number of tasks created at the given line of this file
+    let created_at = |line: u32| {
+        let tasks = rt.metrics().top_cpu_tasks(usize::MAX);
+        let here = |task: &&toy::TaskCpu| {
+            task.created_at().file() == file!() && task.created_at().line() == line
+        };
+        tasks.iter().filter(here).count()
+    };
+    let nap = || toy::sleep(&rt, Duration::from_millis(10));
+    let ok = || async { Ok::<(), ()>(()) };
+
+    let join_line = line!() + 1;
+    toy::make_rt_join2(&rt, nap(), nap()).await;
+    let join_all_line = line!() + 1;
+    toy::make_rt_join_all(&rt, vec![nap(), nap(), nap()]).await;
+    let select_line = line!() + 1;
+    toy::make_rt_select2(&rt, nap(), nap()).await;
+    let try_join_line = line!() + 1;
+    toy::make_rt_try_join2(&rt, ok(), ok()).await.unwrap();
+    let nested_line = line!() + 1;
+    rt.nested_loop(nap());
+    let deadline_line = line!() + 1;
+    rt.nested_loop_with_deadline(Instant::now() + Duration::from_secs(1), nap());
+
+    if cfg!(feature = "trace") {
+        assert_eq!(created_at(join_line), 2);
+        assert_eq!(created_at(join_all_line), 3);
+        assert_eq!(created_at(select_line), 2);
+        assert_eq!(created_at(try_join_line), 2);
+        assert_eq!(created_at(nested_line), 1);
+        assert_eq!(created_at(deadline_line), 1);
+    }
+    println!("test_task_locations: done");
+}
+
+async fn test_async_drop_chain(rt: Rc<toy::Runtime>) {
+    println!(
+        "\ntest_async_drop_chain: async drop awaits the sibling that runs its own nested loop"
+    );
+    let log = Rc::new(RefCell::new(Vec::new()));
+
+    // The async drop waits for the value from the sibling task
+    struct Resource {
+        rt: Rc<toy::Runtime>,
+        rx: Option<toy::oneshot::Receiver<&'static str>>,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            let rx = self.rx.take().unwrap();
+            let rt = self.rt.clone();
+            rt.nested_loop(async {
+                toy::sleep(&self.rt, Duration::from_millis(10)).await;
+                let value = rx.await.unwrap();
+                self.log
+                    .borrow_mut()
+                    .push(format!("resource has got {}", value));
+            });
+        }
+    }
+
+    let (tx, rx) = toy::oneshot::channel(&rt);
+    let task_a = {
+        let resource = Resource {
+            rt: rt.clone(),
+            rx: Some(rx),
+            log: log.clone(),
+        };
+        let rt = rt.clone();
+        async move {
+            let _resource = resource;
+            toy::sleep(&rt, Duration::from_millis(20)).await;
+        }
+    };
+
+    // Sends only after its own nested loop that is waiting for a timer
+    let task_b = {
+        let (rt, log) = (rt.clone(), log.clone());
+        async move {
+            toy::sleep(&rt, Duration::from_millis(30)).await;
+            rt.nested_loop(async {
+                toy::sleep(&rt, Duration::from_millis(50)).await;
+                log.borrow_mut().push("task b cleanup".to_string());
+            });
+            tx.send("the value from b");
+        }
+    };
+
+    toy::make_rt_join2(&rt, task_a, task_b).await;
+    assert_eq!(
+        *log.borrow(),
+        ["task b cleanup", "resource has got the value from b"]
+    );
+    assert_eq!(rt.reactor().timer_count(), 0);
+
+    // the receiver is done when the sender is gone without sending
+    let (tx, rx) = toy::oneshot::channel::<()>(&rt);
+    drop(tx);
+    assert_eq!(rx.await, None);
+    println!("test_async_drop_chain: done");
+}
+
+fn test_reentrant_poll() {
+    println!("\ntest_reentrant_poll: the task is polled from its own poll");
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    // The child polls the join that polls it. There is no other way to poll a task reentrantly,
+    // it is a bug that breaks the aliasing rules on purpose.
+    type Join = Pin<Box<dyn Future<Output = ((), ())>>>;
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(|| {
+        toy::run(|rt| async move {
+            let slot = Rc::new(Cell::new(std::ptr::null_mut::<Join>()));
+            let reenter = {
+                let slot = slot.clone();
+                std::future::poll_fn(move |ctx| {
+                    let join = unsafe { &mut *slot.get() };
+                    let _ = join.as_mut().poll(ctx);
+                    Poll::Ready(())
+                })
+            };
+            let mut join: Join = Box::pin(toy::make_rt_join2(&rt, reenter, async {}));
+            slot.set(&mut join);
+            (&mut join).await;
+        })
+    });
+    std::panic::set_hook(default_hook);
+
+    let message = result.unwrap_err().downcast::<String>().unwrap();
+    println!("{}", message);
+    assert!(message.starts_with("reentrant poll"));
+    assert!(message.contains("created at src/main.rs:"));
+    println!("test_reentrant_poll: done");
+}
+
+fn test_nested_depth_limit() {
+    println!("\ntest_nested_depth_limit: runaway nested loops panic at the limit");
+
+    // Every level runs the next one in nested loop
+    fn dive(rt: Rc<toy::Runtime>, depth: Rc<Cell<u32>>) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(async move {
+            depth.set(depth.get() + 1);
+            rt.nested_loop(dive(rt.clone(), depth));
+        })
+    }
+
+    let depth = Rc::new(Cell::new(0));
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        toy::run(|rt| {
+            rt.set_max_nested_depth(16);
+            dive(rt, depth.clone())
+        })
+    }));
+    std::panic::set_hook(default_hook);
+
+    let message = result.unwrap_err().downcast::<String>().unwrap();
+    println!("{}", message.lines().next().unwrap());
+    assert!(message.starts_with("nested_loop() depth limit 16 is exceeded"));
+    // the top level future and 16 nested loops
+    assert_eq!(depth.get(), 17);
+    println!("test_nested_depth_limit: done");
+}
+
+fn test_deadline_check() {
+    println!("\ntest_deadline_check: the timer that fires before its deadline is caught");
+
+    // Broken timer source: hands out the timers right away, before their deadlines.
+    struct EarlyFire(RefCell<Vec<toy::Wait>>);
+
+    impl toy::TimerSource for EarlyFire {
+        fn wait(&self) -> Option<toy::Wait> {
+            self.0.borrow_mut().pop()
+        }
+
+        fn poll_ready(&self) -> Option<toy::Wait> {
+            self.0.borrow_mut().pop()
+        }
+    }
+
+    let reactor = toy::Reactor::new();
+    let waker = std::task::Waker::noop();
+    let deadline = Instant::now() + Duration::from_millis(100);
+    let early = toy::Wait {
+        event_id: reactor.add_timer_at(waker, deadline),
+        waker: waker.clone(),
+        awake_on: deadline,
+    };
+    let checked = toy::DeadlineCheck::new(EarlyFire(RefCell::new(vec![early])));
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| checked.wait_batch()));
+    std::panic::set_hook(default_hook);
+
+    let message = result.unwrap_err().downcast::<String>().unwrap();
+    println!("{}", message);
+    assert!(message.contains("before its deadline"));
+    assert!(checked.fires().is_empty());
+    println!("test_deadline_check: done");
+}
+
+fn test_wait_batch() {
+    println!("\ntest_wait_batch: the timers with the same deadline fire in one batch");
+    let reactor = toy::DeadlineCheck::new(toy::Reactor::new());
+    let waker = std::task::Waker::noop();
+    let started_on = Instant::now();
+    let deadline = started_on + Duration::from_millis(50);
+    let same: Vec<_> = (0..5)
+        .map(|_| reactor.add_timer_at(waker, deadline))
+        .collect();
+    let later = reactor.add_timer_at(waker, deadline + Duration::from_millis(100));
+
+    let batch = reactor.wait_batch();
+    let fired: Vec<_> = batch.iter().map(|wait| wait.event_id).collect();
+    assert_eq!(fired, same);
+    assert!(started_on.elapsed() < Duration::from_millis(100));
+    assert_eq!(reactor.timer_count(), 1);
+
+    let batch = reactor.wait_batch();
+    assert_eq!(batch.len(), 1);
+    assert_eq!(batch[0].event_id, later);
+    assert!(reactor.wait_batch().is_empty());
+    println!("test_wait_batch: done");
+}
+
+fn test_timer_resolution() {
+    println!("\ntest_timer_resolution: the timers within the resolution fire on one wakeup");
+    let reactor = toy::DeadlineCheck::new(toy::Reactor::new());
+    reactor.set_resolution(Duration::from_millis(10));
+    let waker = std::task::Waker::noop();
+    let first = Instant::now() + Duration::from_millis(50);
+    let spread: Vec<_> = (0..100)
+        .map(|i| reactor.add_timer_at(waker, first + Duration::from_micros(50 * i)))
+        .collect();
+    let later = reactor.add_timer_at(waker, first + Duration::from_millis(100));
+
+    let batch = reactor.wait_batch();
+    let fired: Vec<_> = batch.iter().map(|wait| wait.event_id).collect();
+    assert_eq!(fired, spread);
+    // the fires are recorded with their deadlines
+    let fires = reactor.fires();
+    let fired: Vec<_> = fires.iter().map(|fire| fire.event_id).collect();
+    assert_eq!(fired, spread);
+    assert!(fires.iter().all(|fire| fire.fired_on >= fire.deadline));
+    assert!(fires[0].lateness() >= Duration::from_micros(50 * 99));
+    // late for the earlier ones, but none of them is early
+    assert!(Instant::now() >= first + Duration::from_micros(50 * 99));
+    assert_eq!(reactor.timer_count(), 1);
+
+    let batch = reactor.wait_batch();
+    assert_eq!(batch.len(), 1);
+    assert_eq!(batch[0].event_id, later);
+    println!("test_timer_resolution: done");
+}
+
+fn test_poll_ready() {
+    println!("\ntest_poll_ready: the due timer is taken without sleeping");
+    let reactor = toy::DeadlineCheck::new(toy::Reactor::new());
+    let waker = std::task::Waker::noop();
+    let started_on = Instant::now();
+    let event_id = reactor.add_timer_at(waker, started_on + Duration::from_millis(100));
+    assert!(reactor.poll_ready().is_none());
+    assert!(started_on.elapsed() < Duration::from_millis(10));
+
+    let deadline = reactor.peek_next_deadline().unwrap();
+    std::thread::sleep(deadline - Instant::now());
+    let wait = reactor.poll_ready().unwrap();
+    assert_eq!(wait.event_id, event_id);
+    assert_eq!(reactor.timer_count(), 0);
+    assert!(reactor.poll_ready().is_none());
+    println!("test_poll_ready: done");
+}
+
+fn test_run_output() {
+    println!("\ntest_run_output: run() returns the output of the top level future");
+    assert_eq!(toy::run(|_| async { 42 }), 42);
+
+    // block_on() on the existing runtime, from the top level
+    let sum = toy::run(|rt| {
+        let first = rt.block_on(async { 40 });
+        async move { first + 2 }
+    });
+    assert_eq!(sum, 42);
+
+    // but not from the future the runtime runs
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(|| toy::run(|rt| async move { rt.block_on(async {}) }));
+    std::panic::set_hook(default_hook);
+    let message = result.unwrap_err().downcast::<&str>().unwrap();
+    println!("{}", message);
+    assert!(message.contains("use nested_loop() instead"));
+    assert_eq!(toy::run(|_| async { "hi".to_string() }), "hi");
+
+    // block_on() can be called again once the panic of the previous one is caught
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let sum = toy::run(|rt| {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            rt.block_on(async { panic!("the future has panicked") })
+        }));
+        assert!(result.is_err());
+        let first = rt.block_on(async { 40 });
+        async move { first + 2 }
+    });
+    std::panic::set_hook(default_hook);
+    assert_eq!(sum, 42);
+
+    // there is no current runtime outside of run()
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(|| drop(toy::current()));
+    std::panic::set_hook(default_hook);
+    let message = result.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.contains("outside of run()"));
+
+    let slept =
+        toy::run(|rt| async move { toy::sleep_remaining(&rt, Duration::from_millis(10)).await });
+    assert_eq!(slept, toy::Slept::Full);
+    println!("test_run_output: done");
+}
+
+fn test_detach_second() {
+    println!("\ntest_detach_second: run() waits for the detached 3s sleep");
+    let started_on = Instant::now();
+    let completed = Rc::new(Cell::new(false));
+    let second_completed = completed.clone();
+    let first = toy::run(|rt| async move {
+        let second_rt = rt.clone();
+        let set_completed = second_completed.clone();
+        let join = toy::make_rt_join2(
+            &rt,
+            async {
+                toy::sleep(&rt, Duration::from_millis(1000)).await;
+                "first"
+            },
+            async move {
+                toy::sleep(&second_rt, Duration::from_millis(3000)).await;
+                set_completed.set(true);
+            },
+        );
+        let first = join.detach_second(&rt).await;
+        println!("first is completed in {:?}", started_on.elapsed());
+        assert!(started_on.elapsed() < Duration::from_millis(2000));
+        assert!(!second_completed.get());
+        first
+    });
+    println!("run() has returned in {:?}", started_on.elapsed());
+    assert_eq!(first, "first");
+    assert!(completed.get());
+    assert!(started_on.elapsed() >= Duration::from_millis(3000));
+    println!("test_detach_second: done");
+}
+
 fn main() {
-    toy::run(|rt| test_single_sleep(rt));
-    toy::run(|rt| test_single_nested(rt));
-    toy::run(|rt| test_join_tree(rt));
-    toy::run(|rt| test_nested_loop_tree(rt));
-    toy::run(|rt| test_frozen_events(rt));
+    toy::run(test_single_sleep);
+    toy::run(test_single_nested);
+    toy::run(test_join_tree);
+    toy::run(test_nested_loop_tree);
+    // polls and drops of the deep chain are recursive, first_unfrozen_parent() is not
+    let deep = std::thread::Builder::new().stack_size(512 << 20);
+    let deep = deep.spawn(|| toy::run(test_deep_task_chain)).unwrap();
+    deep.join().unwrap();
+    toy::run(test_frozen_events);
+    toy::run(test_analyze_frozen);
+    toy::run(test_timeout);
+    toy::run(test_timeout_at);
+    toy::run(test_deadline);
+    toy::run(test_sleep_until);
+    toy::run(test_sleep_reset);
+    toy::run(test_zero_sleep);
+    toy::run(test_yield_now);
+    toy::run(test_dispatch_cost);
+    toy::run(test_metrics);
+    toy::run(test_suspend);
+    toy::run(test_race_join);
+    toy::run(test_retire_frozen_event);
+    toy::run(test_retire_event_stages);
+    toy::run(test_drop_fired_sleep);
+    toy::run(test_cancel_timer);
+    toy::run(test_retire_from_frozen_alert);
+    test_polled_after_completion();
+    toy::run(test_sleep_polled_after_completion);
+    toy::run(test_flatten);
+    toy::run(test_borrowing_cleanup);
+    test_blocking_detection();
+    toy::run(test_watchdog);
+    toy::run(test_chain);
+    toy::run(test_sleep_remaining);
+    toy::run(test_timeout_with_cleanup);
+    toy::run(test_async_drop_guard);
+    toy::run(test_timeout_rt);
+    toy::run(test_async_scope);
+    toy::run(test_cancellation_token);
+    toy::run(test_join_outputs);
+    toy::run(test_join_fairness);
+    toy::run(test_join_terminated);
+    toy::run(test_rt_join_awoken_child);
+    toy::run(test_join_state);
+    toy::run(test_join_macros);
+    toy::run(test_try_join);
+    toy::run(test_first_ok);
+    toy::run(test_shared);
+    toy::run(test_select_builder);
+    toy::run(test_select_drop_order);
+    toy::run(test_join_adapters);
+    toy::run(test_join_many);
+    toy::run(test_rt_join_outputs);
+    toy::run(test_reactor_interval);
+    toy::run(test_interval);
+    toy::run(test_peek_next_deadline);
+    toy::run(test_endless_sleep);
+    toy::run(test_rt_select);
+    toy::run(test_rt_try_join);
+    toy::run(test_rt_join_catching);
+    toy::run(test_rt_join_deadline);
+    toy::run(test_rt_chain);
+    toy::run(test_rt_task_set);
+    toy::run(test_for_each_concurrent);
+    toy::run(test_join_stream);
+    toy::run(test_spawn);
+    toy::run(test_rt_join_all);
+    toy::run(test_many_timers);
+    toy::run(test_cpu_accounting);
+    toy::run(test_foreign_waker);
+    toy::run(test_defer_dispatch);
+    toy::run(test_observer);
+    toy::run(test_task_ids);
+    toy::run(test_task_locations);
+    toy::run(test_async_drop_chain);
+    test_run_output();
+    test_deadline_check();
+    test_wait_batch();
+    test_timer_resolution();
+    test_poll_ready();
+    test_detach_second();
+    test_nested_depth_limit();
+    test_reentrant_poll();
+    test_deadlock_history();
+    test_contract_checks();
 }
@@ -0,0 +1,57 @@
+use super::Runtime;
+
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::rc::Rc;
+
+// Async drop hook for a future that owns resources with async cleanup. Combinators that abandon
+// the future before it completes (e.g. ToyFutureExt::timeout_with_cleanup()) run the returned
+// cleanup in a nested loop before the future is dropped.
+pub trait AsyncDrop {
+    fn async_drop(self: Pin<&mut Self>) -> Pin<Box<dyn Future<Output = ()> + '_>>;
+}
+
+// Owns the value and runs its async drop in a nested loop when dropped, so the code does not need
+// to open-code `rt.nested_loop(cleanup)` in Drop. The value is dropped after its async drop is
+// completed, the guards inside of it run their nested loops at that moment.
+pub struct AsyncDropGuard<T>
+where
+    T: AsyncDrop,
+{
+    rt: Rc<Runtime>,
+    value: Pin<Box<T>>,
+}
+
+impl<T> AsyncDropGuard<T>
+where
+    T: AsyncDrop,
+{
+    pub fn new(rt: &Rc<Runtime>, value: T) -> Self {
+        Self {
+            rt: rt.clone(),
+            value: Box::pin(value),
+        }
+    }
+}
+
+impl<T> Deref for AsyncDropGuard<T>
+where
+    T: AsyncDrop,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> Drop for AsyncDropGuard<T>
+where
+    T: AsyncDrop,
+{
+    fn drop(&mut self) {
+        let rt = self.rt.clone();
+        rt.nested_loop(self.value.as_mut().async_drop());
+    }
+}
@@ -0,0 +1,62 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+// What runtime does when a poll takes longer than the threshold, see
+// Runtime::set_blocking_detection().
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BlockingAction {
+    Warn,
+    Panic,
+}
+
+#[derive(Copy, Clone)]
+struct Detection {
+    threshold: Duration,
+    action: BlockingAction,
+}
+
+// Measures how long the user code runs between the points it gives the control back to runtime:
+// return from poll or entering the nested_loop(). Time spent in nested loops is not counted for
+// the poll that has started the loop.
+pub(super) struct BlockingDetector {
+    detection: Cell<Option<Detection>>,
+    user_code_since: Cell<Option<Instant>>,
+}
+
+impl BlockingDetector {
+    pub(super) fn new() -> Self {
+        Self {
+            detection: Cell::new(None),
+            user_code_since: Cell::new(None),
+        }
+    }
+
+    pub(super) fn set(&self, threshold: Duration, action: BlockingAction) {
+        self.detection.set(Some(Detection { threshold, action }));
+    }
+
+    // Runtime is about to give control to user code, e.g. poll a task.
+    pub(super) fn enter_user_code(&self) {
+        if self.detection.get().is_some() {
+            self.user_code_since.set(Some(Instant::now()));
+        }
+    }
+
+    // User code has returned control to runtime.
+    pub(super) fn leave_user_code(&self) {
+        let since = self.user_code_since.take();
+        if let (Some(detection), Some(since)) = (self.detection.get(), since) {
+            let elapsed = since.elapsed();
+            if elapsed > detection.threshold {
+                let message = format!(
+                    "blocking call inside poll: the user code took {:?}, the threshold is {:?}",
+                    elapsed, detection.threshold
+                );
+                match detection.action {
+                    BlockingAction::Warn => println!("warning: {}", message),
+                    BlockingAction::Panic => panic!("{}", message),
+                }
+            }
+        }
+    }
+}
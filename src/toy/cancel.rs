@@ -0,0 +1,129 @@
+use super::contract::FuturePolledAfterCompletion;
+use super::reactor::EventId;
+use super::Runtime;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+// Asks the tasks to stop: unlike the drop of a task, the task sees the request and can run its own
+// async cleanup before it ends. The clones share the state, cancel() of any clone completes all
+// the cancelled() futures. Same as oneshot::Receiver the waiting futures have the timers that
+// never fire and these are replaced with the timers that fire right away on cancel().
+#[derive(Clone)]
+pub struct CancellationToken {
+    rt: Rc<Runtime>,
+    state: Rc<RefCell<State>>,
+}
+
+struct Waiter {
+    key: u64,
+    event_id: EventId,
+    waker: Waker,
+}
+
+struct State {
+    cancelled: bool,
+    waiters: Vec<Waiter>,
+    last_key: u64,
+}
+
+impl State {
+    // Stops waiting, the pending timer of the future is cancelled.
+    fn leave(&mut self, rt: &Runtime, key: u64) {
+        if let Some(pos) = self.waiters.iter().position(|waiter| waiter.key == key) {
+            let waiter = self.waiters.remove(pos);
+            rt.retire_event(waiter.event_id);
+        }
+    }
+}
+
+impl CancellationToken {
+    pub fn new(rt: &Rc<Runtime>) -> Self {
+        let state = State {
+            cancelled: false,
+            waiters: Vec::new(),
+            last_key: 0,
+        };
+        Self {
+            rt: rt.clone(),
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    // Wakes all the cancelled() futures, the ones made after that are completed right away.
+    pub fn cancel(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.cancelled {
+            return;
+        }
+        state.cancelled = true;
+        for waiter in state.waiters.iter_mut() {
+            self.rt.retire_event(waiter.event_id);
+            waiter.event_id = self.rt.reactor().add_timer(&waiter.waker, Duration::ZERO);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.borrow().cancelled
+    }
+
+    // Completes once the token is cancelled, it is meant to be raced against the work.
+    pub fn cancelled(&self) -> Cancelled {
+        let key = {
+            let mut state = self.state.borrow_mut();
+            state.last_key += 1;
+            state.last_key
+        };
+        Cancelled {
+            token: self.clone(),
+            key,
+            completed: false,
+        }
+    }
+}
+
+pub struct Cancelled {
+    token: CancellationToken,
+    key: u64,
+    completed: bool,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        let rt = &self.token.rt;
+        let mut state = self.token.state.borrow_mut();
+        if !state.cancelled {
+            if !state.waiters.iter().any(|waiter| waiter.key == self.key) {
+                let waiter = Waiter {
+                    key: self.key,
+                    event_id: rt.reactor().add_timer(ctx.waker(), Duration::MAX),
+                    waker: ctx.waker().clone(),
+                };
+                state.waiters.push(waiter);
+            }
+            return Poll::Pending;
+        }
+
+        state.leave(rt, self.key);
+        drop(state);
+        self.completed = true;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Cancelled {
+    fn drop(&mut self) {
+        let mut state = self.token.state.borrow_mut();
+        state.leave(&self.token.rt, self.key);
+    }
+}
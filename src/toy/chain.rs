@@ -0,0 +1,61 @@
+use super::contract::FuturePolledAfterCompletion;
+
+use pin_project::pin_project;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Future made by ToyFutureExt::chain(): runs the first future to completion, then the next one.
+// The next future is not polled (so it does not register anything in reactor) until the first
+// one is completed.
+#[pin_project(project = ChainProj)]
+pub enum Chain<FutT1, FutT2>
+where
+    FutT1: Future<Output = ()>,
+    FutT2: Future,
+{
+    First(#[pin] FutT1, Option<FutT2>),
+    Next(#[pin] FutT2),
+    Done,
+}
+
+impl<FutT1, FutT2> Chain<FutT1, FutT2>
+where
+    FutT1: Future<Output = ()>,
+    FutT2: Future,
+{
+    pub(super) fn new(first: FutT1, next: FutT2) -> Self {
+        Chain::First(first, Some(next))
+    }
+}
+
+impl<FutT1, FutT2> Future for Chain<FutT1, FutT2>
+where
+    FutT1: Future<Output = ()>,
+    FutT2: Future,
+{
+    type Output = FutT2::Output;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match self.as_mut().project() {
+                ChainProj::First(first, next) => match first.poll(ctx) {
+                    Poll::Ready(()) => {
+                        let next = next.take().unwrap();
+                        self.set(Chain::Next(next));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                ChainProj::Next(next) => match next.poll(ctx) {
+                    Poll::Ready(output) => {
+                        self.set(Chain::Done);
+                        return Poll::Ready(output);
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                ChainProj::Done => FuturePolledAfterCompletion::panic::<Self>(),
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use super::current;
+use super::task::TaskId;
+
+use pin_project::pin_project;
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Violation of the Future contract: the future has been polled after it returned Poll::Ready.
+// Futures of this runtime panic with this error as a message, so it is clear which future has
+// been misused and by which task.
+#[derive(Debug)]
+pub struct FuturePolledAfterCompletion {
+    future: &'static str,
+    // The task the runtime is polling, if any
+    task: Option<(&'static str, TaskId)>,
+}
+
+impl FuturePolledAfterCompletion {
+    // Panics with the error about future of type FutT.
+    #[track_caller]
+    pub(super) fn panic<FutT: ?Sized>() -> ! {
+        let error = Self {
+            future: std::any::type_name::<FutT>(),
+            task: current::try_runtime()
+                .and_then(|rt| rt.polled_task())
+                .map(|task| (task.name(), task.id())),
+        };
+        panic!("{}", error)
+    }
+}
+
+impl fmt::Display for FuturePolledAfterCompletion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "FuturePolledAfterCompletion: `{}` has been polled after it returned Poll::Ready",
+            self.future
+        )?;
+        match self.task {
+            Some((name, id)) => write!(f, " in task `{}` ({:?})", name, id),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for FuturePolledAfterCompletion {}
+
+// Wrapper that makes sure the inner future is not polled after it returned Poll::Ready. In debug
+// builds the runtime wraps every task future into it, see also ToyFutureExt::checked().
+#[pin_project]
+pub struct ContractCheck<FutT>
+where
+    FutT: Future,
+{
+    #[pin]
+    fut: FutT,
+    completed: bool,
+}
+
+impl<FutT> ContractCheck<FutT>
+where
+    FutT: Future,
+{
+    pub(super) fn new(fut: FutT) -> Self {
+        Self {
+            fut,
+            completed: false,
+        }
+    }
+}
+
+impl<FutT> Future for ContractCheck<FutT>
+where
+    FutT: Future,
+{
+    type Output = FutT::Output;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if *this.completed {
+            FuturePolledAfterCompletion::panic::<FutT>();
+        }
+
+        let poll = this.fut.poll(ctx);
+        *this.completed = poll.is_ready();
+        poll
+    }
+}
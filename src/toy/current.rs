@@ -0,0 +1,83 @@
+use super::rt_join::{make_rt_join2, RtJoin2};
+use super::sleep::sleep;
+use super::Runtime;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::time::Duration;
+
+thread_local! {
+    // The runtimes of run() on this thread, the innermost one is on top
+    static CURRENT: RefCell<Vec<Rc<Runtime>>> = const { RefCell::new(Vec::new()) };
+}
+
+// Makes the runtime current for the scope of run(), the nested loops run within this scope too.
+pub(super) struct CurrentGuard;
+
+impl CurrentGuard {
+    pub(super) fn enter(rt: &Rc<Runtime>) -> Self {
+        CURRENT.with(|current| current.borrow_mut().push(rt.clone()));
+        CurrentGuard
+    }
+}
+
+impl Drop for CurrentGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.borrow_mut().pop());
+    }
+}
+
+// The runtime that runs the calling code, so the helpers do not need `rt` to be passed through
+// every function, e.g. `toy::current().sleep(duration).await`. Panics if called outside of run().
+pub fn current() -> RuntimeHandle {
+    RuntimeHandle {
+        rt: try_runtime()
+            .expect("toy::current() is called outside of run(), there is no current runtime"),
+    }
+}
+
+// Same as current(), but None outside of run(), e.g. for the diagnostics.
+pub(super) fn try_runtime() -> Option<Rc<Runtime>> {
+    CURRENT.with(|current| current.borrow().last().cloned())
+}
+
+// Same as Rc<Runtime>, with the helpers that take the runtime from it.
+pub struct RuntimeHandle {
+    rt: Rc<Runtime>,
+}
+
+impl RuntimeHandle {
+    pub fn runtime(&self) -> &Rc<Runtime> {
+        &self.rt
+    }
+
+    // See toy::sleep(), the future does not borrow the handle.
+    pub fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+        let rt = self.rt.clone();
+        async move { sleep(&rt, duration).await }
+    }
+
+    // See toy::make_rt_join2().
+    #[track_caller]
+    pub fn make_rt_join2<'f1, 'f2, FutT1, FutT2>(
+        &self,
+        f1: FutT1,
+        f2: FutT2,
+    ) -> RtJoin2<FutT1, FutT2>
+    where
+        FutT1: Future + 'f1,
+        FutT2: Future + 'f2,
+    {
+        make_rt_join2(&self.rt, f1, f2)
+    }
+}
+
+impl Deref for RuntimeHandle {
+    type Target = Runtime;
+
+    fn deref(&self) -> &Runtime {
+        &self.rt
+    }
+}
@@ -0,0 +1,91 @@
+use super::reactor::{EventId, TimerSource, Wait};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+// Number of the last fires kept by DeadlineCheck.
+const FIRE_HISTORY: usize = 256;
+
+// The timer that has fired: its deadline and when it has actually fired.
+#[derive(Copy, Clone, Debug)]
+pub struct Fire {
+    pub event_id: EventId,
+    pub deadline: Instant,
+    pub fired_on: Instant,
+}
+
+impl Fire {
+    pub fn lateness(&self) -> Duration {
+        self.fired_on.duration_since(self.deadline)
+    }
+}
+
+// Decorator of the timer source that records the deadline and the fire time of every timer and
+// panics if a timer fires before its deadline. There is no tolerance: firing early is always a
+// bug. Every fire path (wait(), poll_ready() and wait_batch() made of them) is checked, the other
+// methods of the source are reached by Deref. It is a test harness: the runtime drives the plain
+// Reactor, the tests wrap their reactors with it, so the release build pays nothing for it.
+pub struct DeadlineCheck<SourceT> {
+    source: SourceT,
+    fires: RefCell<VecDeque<Fire>>,
+}
+
+impl<SourceT> DeadlineCheck<SourceT> {
+    pub fn new(source: SourceT) -> Self {
+        Self {
+            source,
+            fires: RefCell::new(VecDeque::with_capacity(FIRE_HISTORY)),
+        }
+    }
+
+    // The last fires, the oldest first.
+    pub fn fires(&self) -> Vec<Fire> {
+        self.fires.borrow().iter().copied().collect()
+    }
+
+    fn check(&self, wait: Option<Wait>) -> Option<Wait> {
+        let wait = wait?;
+        let fired_on = Instant::now();
+        if fired_on < wait.awake_on {
+            panic!(
+                "timer {:?} has fired {:?} before its deadline",
+                wait.event_id,
+                wait.awake_on - fired_on
+            );
+        }
+
+        let mut fires = self.fires.borrow_mut();
+        if fires.len() == FIRE_HISTORY {
+            fires.pop_front();
+        }
+        fires.push_back(Fire {
+            event_id: wait.event_id,
+            deadline: wait.awake_on,
+            fired_on,
+        });
+        Some(wait)
+    }
+}
+
+impl<SourceT> TimerSource for DeadlineCheck<SourceT>
+where
+    SourceT: TimerSource,
+{
+    fn wait(&self) -> Option<Wait> {
+        self.check(self.source.wait())
+    }
+
+    fn poll_ready(&self) -> Option<Wait> {
+        self.check(self.source.poll_ready())
+    }
+}
+
+impl<SourceT> Deref for DeadlineCheck<SourceT> {
+    type Target = SourceT;
+
+    fn deref(&self) -> &SourceT {
+        &self.source
+    }
+}
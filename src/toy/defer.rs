@@ -0,0 +1,75 @@
+use super::reactor::{EventId, Wait};
+use super::Runtime;
+
+use std::cell::{Cell, RefCell};
+
+// Holds the events of the tasks outside of the running cleanup while there are DeferGuards, see
+// Runtime::defer_dispatch().
+pub(super) struct Deferral {
+    guards: Cell<usize>,
+    // Events in the order they have fired
+    held: RefCell<Vec<Wait>>,
+}
+
+impl Deferral {
+    pub(super) fn new() -> Self {
+        Self {
+            guards: Cell::new(0),
+            held: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub(super) fn is_active(&self) -> bool {
+        self.guards.get() > 0
+    }
+
+    pub(super) fn hold(&self, wait: Wait) {
+        self.held.borrow_mut().push(wait);
+    }
+
+    #[cfg(debug_assertions)]
+    pub(super) fn any_waker<PredFn>(&self, pred: PredFn) -> bool
+    where
+        PredFn: Fn(&std::task::Waker) -> bool,
+    {
+        self.held.borrow().iter().any(|wait| pred(&wait.waker))
+    }
+
+    pub(super) fn retire(&self, event_id: EventId) {
+        self.held
+            .borrow_mut()
+            .retain(|wait| wait.event_id != event_id);
+    }
+
+    fn enter(&self) {
+        self.guards.set(self.guards.get() + 1);
+    }
+
+    // Returns the held events once the last guard is gone.
+    fn leave(&self) -> Vec<Wait> {
+        self.guards.set(self.guards.get() - 1);
+        if self.is_active() {
+            return Vec::new();
+        }
+        std::mem::take(&mut *self.held.borrow_mut())
+    }
+}
+
+// Keeps the dispatch of the events deferred, see Runtime::defer_dispatch().
+pub struct DeferGuard<'rt> {
+    rt: &'rt Runtime,
+}
+
+impl<'rt> DeferGuard<'rt> {
+    pub(super) fn new(rt: &'rt Runtime) -> Self {
+        rt.deferral().enter();
+        Self { rt }
+    }
+}
+
+impl Drop for DeferGuard<'_> {
+    fn drop(&mut self) {
+        let held = self.rt.deferral().leave();
+        self.rt.release_deferred(held);
+    }
+}
@@ -0,0 +1,78 @@
+use super::async_drop::AsyncDrop;
+use super::chain::Chain;
+use super::contract::ContractCheck;
+use super::flatten::Flatten;
+use super::join::{make_join2, Join2};
+use super::select::{make_select2, Select2};
+use super::sleep::Sleep;
+use super::timeout::{Timeout, TimeoutWithCleanup};
+use super::Runtime;
+
+use std::future::Future;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Combinators for any future in the method chaining form, e.g. `fut.timeout(&rt, dur).await`.
+pub trait ToyFutureExt: Future + Sized {
+    // Completes with Err(Elapsed) if the future has not completed within given duration.
+    fn timeout(self, rt: &Rc<Runtime>, duration: Duration) -> Timeout<Self> {
+        Timeout::new(self, Sleep::new(rt, duration))
+    }
+
+    // Same as timeout() but with absolute deadline: operations that share a deadline expire
+    // together, even if they have been started at different moments.
+    fn timeout_at(self, rt: &Rc<Runtime>, deadline: Instant) -> Timeout<Self> {
+        Timeout::new(self, Sleep::until(rt, deadline))
+    }
+
+    // Same as timeout(), but the timed out future gets its async drop run to completion before
+    // Err(Elapsed) is returned.
+    fn timeout_with_cleanup(self, rt: &Rc<Runtime>, duration: Duration) -> TimeoutWithCleanup<Self>
+    where
+        Self: AsyncDrop,
+    {
+        TimeoutWithCleanup::new(rt, self, Sleep::new(rt, duration))
+    }
+
+    // Method form of make_join2(): `a.join(b).await`.
+    fn join<FutT>(self, other: FutT) -> Join2<Self, FutT>
+    where
+        FutT: Future,
+    {
+        make_join2(self, other)
+    }
+
+    // Method form of make_select2(): `a.race(b).await`.
+    fn race<FutT>(self, other: FutT) -> Select2<Self, FutT>
+    where
+        FutT: Future,
+    {
+        make_select2(self, other)
+    }
+
+    // Runs the future to completion and then the next one: `cleanup1.chain(cleanup2).await`.
+    fn chain<FutT>(self, next: FutT) -> Chain<Self, FutT>
+    where
+        Self: Future<Output = ()>,
+        FutT: Future,
+    {
+        Chain::new(self, next)
+    }
+
+    // Panics with FuturePolledAfterCompletion if the future is polled after it has completed,
+    // even if the future itself would not notice. Task futures get it for free in debug builds.
+    fn checked(self) -> ContractCheck<Self> {
+        ContractCheck::new(self)
+    }
+
+    // For the future that resolves to another future: waits for both and resolves to the output
+    // of the inner one.
+    fn flatten(self) -> Flatten<Self>
+    where
+        Self::Output: Future,
+    {
+        Flatten::new(self)
+    }
+}
+
+impl<FutT> ToyFutureExt for FutT where FutT: Future {}
@@ -0,0 +1,57 @@
+use super::contract::FuturePolledAfterCompletion;
+
+use pin_project::pin_project;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Future made by ToyFutureExt::flatten(): polls the outer future to get the inner one, then polls
+// the inner future to completion.
+#[pin_project(project = FlattenProj)]
+pub enum Flatten<FutT>
+where
+    FutT: Future,
+    FutT::Output: Future,
+{
+    Outer(#[pin] FutT),
+    Inner(#[pin] FutT::Output),
+    Done,
+}
+
+impl<FutT> Flatten<FutT>
+where
+    FutT: Future,
+    FutT::Output: Future,
+{
+    pub(super) fn new(fut: FutT) -> Self {
+        Flatten::Outer(fut)
+    }
+}
+
+impl<FutT> Future for Flatten<FutT>
+where
+    FutT: Future,
+    FutT::Output: Future,
+{
+    type Output = <FutT::Output as Future>::Output;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match self.as_mut().project() {
+                FlattenProj::Outer(fut) => match fut.poll(ctx) {
+                    Poll::Ready(inner) => self.set(Flatten::Inner(inner)),
+                    Poll::Pending => return Poll::Pending,
+                },
+                FlattenProj::Inner(fut) => match fut.poll(ctx) {
+                    Poll::Ready(output) => {
+                        self.set(Flatten::Done);
+                        return Poll::Ready(output);
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                FlattenProj::Done => FuturePolledAfterCompletion::panic::<Self>(),
+            }
+        }
+    }
+}
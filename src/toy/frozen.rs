@@ -0,0 +1,145 @@
+use super::reactor::{EventId, Wait};
+use super::task::Task;
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// The event that has fired for a frozen task and waits in Runtime::frozen_events for the task to
+// unfreeze.
+pub(super) struct FrozenEvent {
+    pub(super) wait: Wait,
+    pub(super) parked_on: Instant,
+    // Nesting depth of the loop the event has been parked by
+    pub(super) depth: u32,
+    // How many times runtime has tried to deliver the event but the task was still frozen
+    pub(super) attempts: u32,
+    // The task to poll instead of the task of the waker, set for the events of the foreign
+    // wakers, see Runtime::park_foreign_event()
+    pub(super) target: Option<Arc<Task>>,
+}
+
+impl FrozenEvent {
+    pub(super) fn new(wait: Wait, depth: u32) -> Self {
+        Self {
+            wait,
+            parked_on: Instant::now(),
+            depth,
+            attempts: 0,
+            target: None,
+        }
+    }
+
+    pub(super) fn with_target(wait: Wait, depth: u32, target: Arc<Task>) -> Self {
+        Self {
+            target: Some(target),
+            ..Self::new(wait, depth)
+        }
+    }
+}
+
+// Analysis of the parked event, see Runtime::analyze_frozen().
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct FrozenReport {
+    pub(super) event_id: EventId,
+    // Type of the task future the event is for
+    pub(super) task: &'static str,
+    // The frozen task that holds the delivery: the task itself or its ancestor that runs a
+    // nested loop.
+    pub(super) frozen_by: &'static str,
+    pub(super) parked_for: Duration,
+    pub(super) depth: u32,
+    pub(super) replay_attempts: u32,
+}
+
+impl FrozenReport {
+    pub fn event_id(&self) -> EventId {
+        self.event_id
+    }
+
+    pub fn task(&self) -> &'static str {
+        self.task
+    }
+
+    pub fn frozen_by(&self) -> &'static str {
+        self.frozen_by
+    }
+
+    pub fn parked_for(&self) -> Duration {
+        self.parked_for
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn replay_attempts(&self) -> u32 {
+        self.replay_attempts
+    }
+}
+
+type Callback = dyn Fn(&[FrozenReport]);
+
+struct Settings {
+    max_count: usize,
+    max_age: Duration,
+    callback: Box<Callback>,
+}
+
+// Reports frozen events once there are too many of them or the oldest one is parked for too long,
+// see Runtime::set_frozen_alert().
+pub(super) struct FrozenAlert {
+    settings: RefCell<Option<Rc<Settings>>>,
+    // The alert fires once, it is re-armed when frozen_events are drained
+    fired: Cell<bool>,
+}
+
+impl FrozenAlert {
+    pub(super) fn new() -> Self {
+        Self {
+            settings: RefCell::new(None),
+            fired: Cell::new(false),
+        }
+    }
+
+    pub(super) fn set<CallbackFn>(&self, max_count: usize, max_age: Duration, cb: CallbackFn)
+    where
+        CallbackFn: Fn(&[FrozenReport]) + 'static,
+    {
+        *self.settings.borrow_mut() = Some(Rc::new(Settings {
+            max_count,
+            max_age,
+            callback: Box::new(cb),
+        }));
+        self.fired.set(false);
+    }
+
+    // Checks the thresholds for the count of the parked events and the park time of the oldest
+    // one, the reports are only made if the alert fires. The events are not borrowed by the
+    // callback, so it can retire them.
+    pub(super) fn check<ReportFn>(&self, count: usize, oldest: Option<Instant>, reports: ReportFn)
+    where
+        ReportFn: FnOnce() -> Vec<FrozenReport>,
+    {
+        let oldest = match oldest {
+            Some(oldest) => oldest,
+            None => {
+                self.fired.set(false);
+                return;
+            }
+        };
+
+        let settings = self.settings.borrow().clone();
+        let settings = match settings {
+            Some(settings) if !self.fired.get() => settings,
+            _ => return,
+        };
+
+        if count >= settings.max_count || oldest.elapsed() >= settings.max_age {
+            self.fired.set(true);
+            (settings.callback)(&reports());
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use super::reactor::MIN_INTERVAL_PERIOD;
+use super::sleep::{sleep_until, Sleep};
+use super::stream::Stream;
+use super::Runtime;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// What Interval does after a tick that is late for more than a period, e.g. because the code
+// between ticks took too long. The late tick itself completes right away in both cases.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MissedTick {
+    // The schedule is realigned: the next tick is a period after the late one
+    Delay,
+    // The missed ticks are skipped: the next tick is the next one of the original schedule
+    Skip,
+}
+
+// Makes the periodic timer, the first tick completes one period from now. See Interval. Same as
+// Reactor::add_interval() zero period is treated as MIN_INTERVAL_PERIOD.
+pub fn interval(rt: &Rc<Runtime>, period: Duration) -> Interval {
+    let period = period.max(MIN_INTERVAL_PERIOD);
+    Interval {
+        rt: rt.clone(),
+        period,
+        next: Instant::now() + period,
+        missed_tick: MissedTick::Delay,
+        sleep: None,
+    }
+}
+
+// Periodic timer: `loop { interval.tick().await; ... }`. The deadlines are anchored to the start
+// (next = previous deadline + period rather than now + period), so the ticks do not drift with the
+// time spent between them. Every tick() registers its own timer in reactor. It is also the
+// endless Stream of the ticks.
+pub struct Interval {
+    rt: Rc<Runtime>,
+    period: Duration,
+    // Deadline of the next tick
+    next: Instant,
+    missed_tick: MissedTick,
+    // The sleep till the next tick for poll_next()
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Interval {
+    pub fn set_missed_tick(&mut self, missed_tick: MissedTick) {
+        self.missed_tick = missed_tick;
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    // Completes on the deadline of the next tick and resolves to it. Dropping the future before
+    // completion does not skip the tick.
+    pub async fn tick(&mut self) -> Instant {
+        let deadline = self.next;
+        sleep_until(&self.rt, deadline).await;
+        self.advance(deadline)
+    }
+
+    // Schedules the tick after the one with the deadline, returns the deadline.
+    fn advance(&mut self, deadline: Instant) -> Instant {
+        self.sleep = None;
+        let now = Instant::now();
+        self.next = deadline + self.period;
+        if now >= self.next {
+            self.next = match self.missed_tick {
+                MissedTick::Delay => now + self.period,
+                MissedTick::Skip => {
+                    let missed = (now - deadline).as_nanos() / self.period.as_nanos();
+                    deadline + self.period * (missed as u32 + 1)
+                }
+            };
+        }
+        deadline
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    // Same as tick(), the stream never ends.
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        let this = &mut *self;
+        let deadline = this.next;
+        let sleep = this
+            .sleep
+            .get_or_insert_with(|| Box::pin(Sleep::until(&this.rt, deadline)));
+        match sleep.as_mut().poll(ctx) {
+            Poll::Ready(()) => Poll::Ready(Some(this.advance(deadline))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
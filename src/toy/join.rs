@@ -1,16 +1,30 @@
+use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use pin_project::pin_project;
 
-// Make a future that completes as soon as both futures are completed. This join
-// does not create tasks in runtime. Similar to `join!` in other crates except that only 2 params
-// and no return value.
+use super::contract::FuturePolledAfterCompletion;
+use super::map::{Inspect, Map};
+
+// Which of the joined futures have completed, for diagnostics of a join that hangs. Frozen is only
+// known for the tasks of RtJoin2: the task that runs a nested loop.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct JoinState {
+    pub first_done: bool,
+    pub second_done: bool,
+    pub first_frozen: bool,
+    pub second_frozen: bool,
+}
+
+// Make a future that completes as soon as both futures are completed and resolves to the tuple
+// of their outputs. This join does not create tasks in runtime. Similar to `join!` in other
+// crates except that only 2 params.
 pub fn make_join2<FutT1, FutT2>(f1: FutT1, f2: FutT2) -> Join2<FutT1, FutT2>
 where
-    FutT1: Future<Output = ()>,
-    FutT2: Future<Output = ()>,
+    FutT1: Future,
+    FutT2: Future,
 {
     Join2::<FutT1, FutT2>::new(f1, f2)
 }
@@ -18,63 +32,512 @@ where
 #[pin_project]
 pub struct Join2<FutT1, FutT2>
 where
-    FutT1: Future<Output = ()>,
-    FutT2: Future<Output = ()>,
+    FutT1: Future,
+    FutT2: Future,
 {
     #[pin]
     fut1: FutT1,
     #[pin]
     fut2: FutT2,
 
-    fut1_done: bool,
-    fut2_done: bool,
+    // outputs of the futures that have completed first
+    output1: Option<FutT1::Output>,
+    output2: Option<FutT2::Output>,
+    completed: bool,
+    // The order of polls changes on every poll, so neither future is favored
+    fut2_first: bool,
 }
 
 impl<FutT1, FutT2> Join2<FutT1, FutT2>
 where
-    FutT1: Future<Output = ()>,
-    FutT2: Future<Output = ()>,
+    FutT1: Future,
+    FutT2: Future,
 {
     fn new(f1: FutT1, f2: FutT2) -> Self {
         Self {
             fut1: f1,
             fut2: f2,
-            fut1_done: false,
-            fut2_done: false,
+            output1: None,
+            output2: None,
+            completed: false,
+            fut2_first: false,
+        }
+    }
+
+    // If Join2 has returned Ready, it must not be polled anymore: there is no output to return
+    // again, so it is reported as FuturePolledAfterCompletion. The completed child is never
+    // polled again either.
+    pub fn is_terminated(&self) -> bool {
+        self.completed
+    }
+
+    pub fn state(&self) -> JoinState {
+        JoinState {
+            first_done: self.completed || self.output1.is_some(),
+            second_done: self.completed || self.output2.is_some(),
+            ..JoinState::default()
         }
     }
+
+    // Applies the closure to the outputs once both are completed, see Map.
+    pub fn map<FnT, T>(self, f: FnT) -> Map<Self, FnT>
+    where
+        FnT: FnOnce((FutT1::Output, FutT2::Output)) -> T,
+    {
+        Map::new(self, f)
+    }
+
+    // Calls the closure with the outputs once both are completed, see Inspect.
+    pub fn inspect<FnT>(self, f: FnT) -> Inspect<Self, FnT>
+    where
+        FnT: FnOnce(&(FutT1::Output, FutT2::Output)),
+    {
+        Inspect::new(self, f)
+    }
+}
+
+impl<FutT1, FutT2> fmt::Debug for Join2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Join2")
+            .field("state", &self.state())
+            .finish()
+    }
 }
 
 impl<FutT1, FutT2> Future for Join2<FutT1, FutT2>
 where
-    FutT1: Future<Output = ()>,
-    FutT2: Future<Output = ()>,
+    FutT1: Future,
+    FutT2: Future,
+{
+    type Output = (FutT1::Output, FutT2::Output);
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if *this.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        if *this.fut2_first {
+            poll_into(this.fut2, this.output2, ctx);
+            poll_into(this.fut1, this.output1, ctx);
+        } else {
+            poll_into(this.fut1, this.output1, ctx);
+            poll_into(this.fut2, this.output2, ctx);
+        }
+        *this.fut2_first = !*this.fut2_first;
+
+        if this.output1.is_some() && this.output2.is_some() {
+            *this.completed = true;
+            return Poll::Ready((this.output1.take().unwrap(), this.output2.take().unwrap()));
+        }
+
+        Poll::Pending
+    }
+}
+
+// Same as make_join2() but for 3 futures.
+pub fn make_join3<FutT1, FutT2, FutT3>(
+    f1: FutT1,
+    f2: FutT2,
+    f3: FutT3,
+) -> Join3<FutT1, FutT2, FutT3>
+where
+    FutT1: Future,
+    FutT2: Future,
+    FutT3: Future,
+{
+    Join3 {
+        fut1: f1,
+        fut2: f2,
+        fut3: f3,
+        output1: None,
+        output2: None,
+        output3: None,
+        completed: false,
+    }
+}
+
+#[pin_project]
+pub struct Join3<FutT1, FutT2, FutT3>
+where
+    FutT1: Future,
+    FutT2: Future,
+    FutT3: Future,
+{
+    #[pin]
+    fut1: FutT1,
+    #[pin]
+    fut2: FutT2,
+    #[pin]
+    fut3: FutT3,
+
+    output1: Option<FutT1::Output>,
+    output2: Option<FutT2::Output>,
+    output3: Option<FutT3::Output>,
+    completed: bool,
+}
+
+impl<FutT1, FutT2, FutT3> Future for Join3<FutT1, FutT2, FutT3>
+where
+    FutT1: Future,
+    FutT2: Future,
+    FutT3: Future,
+{
+    type Output = (FutT1::Output, FutT2::Output, FutT3::Output);
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if *this.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        poll_into(this.fut1, this.output1, ctx);
+        poll_into(this.fut2, this.output2, ctx);
+        poll_into(this.fut3, this.output3, ctx);
+
+        if this.output1.is_some() && this.output2.is_some() && this.output3.is_some() {
+            *this.completed = true;
+            return Poll::Ready((
+                this.output1.take().unwrap(),
+                this.output2.take().unwrap(),
+                this.output3.take().unwrap(),
+            ));
+        }
+
+        Poll::Pending
+    }
+}
+
+// Same as make_join2() but for 4 futures.
+pub fn make_join4<FutT1, FutT2, FutT3, FutT4>(
+    f1: FutT1,
+    f2: FutT2,
+    f3: FutT3,
+    f4: FutT4,
+) -> Join4<FutT1, FutT2, FutT3, FutT4>
+where
+    FutT1: Future,
+    FutT2: Future,
+    FutT3: Future,
+    FutT4: Future,
+{
+    Join4 {
+        fut1: f1,
+        fut2: f2,
+        fut3: f3,
+        fut4: f4,
+        output1: None,
+        output2: None,
+        output3: None,
+        output4: None,
+        completed: false,
+    }
+}
+
+#[pin_project]
+pub struct Join4<FutT1, FutT2, FutT3, FutT4>
+where
+    FutT1: Future,
+    FutT2: Future,
+    FutT3: Future,
+    FutT4: Future,
+{
+    #[pin]
+    fut1: FutT1,
+    #[pin]
+    fut2: FutT2,
+    #[pin]
+    fut3: FutT3,
+    #[pin]
+    fut4: FutT4,
+
+    output1: Option<FutT1::Output>,
+    output2: Option<FutT2::Output>,
+    output3: Option<FutT3::Output>,
+    output4: Option<FutT4::Output>,
+    completed: bool,
+}
+
+impl<FutT1, FutT2, FutT3, FutT4> Future for Join4<FutT1, FutT2, FutT3, FutT4>
+where
+    FutT1: Future,
+    FutT2: Future,
+    FutT3: Future,
+    FutT4: Future,
+{
+    type Output = (FutT1::Output, FutT2::Output, FutT3::Output, FutT4::Output);
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if *this.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        poll_into(this.fut1, this.output1, ctx);
+        poll_into(this.fut2, this.output2, ctx);
+        poll_into(this.fut3, this.output3, ctx);
+        poll_into(this.fut4, this.output4, ctx);
+
+        if this.output1.is_some()
+            && this.output2.is_some()
+            && this.output3.is_some()
+            && this.output4.is_some()
+        {
+            *this.completed = true;
+            return Poll::Ready((
+                this.output1.take().unwrap(),
+                this.output2.take().unwrap(),
+                this.output3.take().unwrap(),
+                this.output4.take().unwrap(),
+            ));
+        }
+
+        Poll::Pending
+    }
+}
+
+// Join for any number of futures of the same type, resolves to their outputs in the same order.
+pub fn join_all<FutT>(futures: Vec<FutT>) -> JoinAll<FutT>
+where
+    FutT: Future,
+{
+    JoinAll {
+        outputs: futures.iter().map(|_| None).collect(),
+        futures: futures.into_iter().map(|fut| Some(Box::pin(fut))).collect(),
+        completed: false,
+    }
+}
+
+pub struct JoinAll<FutT>
+where
+    FutT: Future,
 {
-    type Output = ();
+    // The completed futures are dropped, so they are never polled again
+    futures: Vec<Option<Pin<Box<FutT>>>>,
+    outputs: Vec<Option<FutT::Output>>,
+    completed: bool,
+}
+
+// Futures are boxed and outputs are never pinned, so JoinAll does not need pinning.
+impl<FutT> Unpin for JoinAll<FutT> where FutT: Future {}
+
+impl<FutT> Future for JoinAll<FutT>
+where
+    FutT: Future,
+{
+    type Output = Vec<FutT::Output>;
 
     fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.as_mut().project();
-
-        match this.fut1.poll(ctx) {
-            Poll::Ready(_) => {
-                *this.fut1_done = true;
-                if *this.fut2_done {
-                    return Poll::Ready(());
-                }
-            }
-            _ => (),
+        let this = &mut *self;
+
+        if this.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
         }
 
-        match this.fut2.poll(ctx) {
-            Poll::Ready(_) => {
-                *this.fut2_done = true;
-                if *this.fut1_done {
-                    return Poll::Ready(());
-                }
-            }
-            _ => (),
+        let pairs = this.futures.iter_mut().zip(this.outputs.iter_mut());
+        for (slot, output) in pairs {
+            poll_boxed_into(slot, output, ctx);
+        }
+
+        if this.futures.iter().any(Option::is_some) {
+            return Poll::Pending;
+        }
+
+        this.completed = true;
+        let outputs = this.outputs.iter_mut().map(|output| output.take().unwrap());
+        Poll::Ready(outputs.collect())
+    }
+}
+
+// Join of 2 futures that resolve to Result: completes with the outputs of both if both succeed
+// or with the first error as soon as it is encountered. The other future is dropped right away,
+// so a pending Sleep in it cancels its timer. If both fail on the same poll, the error of the
+// first one is returned.
+pub fn make_try_join2<FutT1, FutT2, T1, T2, E>(f1: FutT1, f2: FutT2) -> TryJoin2<FutT1, FutT2>
+where
+    FutT1: Future<Output = Result<T1, E>>,
+    FutT2: Future<Output = Result<T2, E>>,
+{
+    TryJoin2 {
+        fut1: Some(Box::pin(f1)),
+        fut2: Some(Box::pin(f2)),
+        output1: None,
+        output2: None,
+        completed: false,
+    }
+}
+
+pub struct TryJoin2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    // Futures are dropped once completed or when the other has failed
+    fut1: Option<Pin<Box<FutT1>>>,
+    fut2: Option<Pin<Box<FutT2>>>,
+    output1: Option<FutT1::Output>,
+    output2: Option<FutT2::Output>,
+    completed: bool,
+}
+
+// Same as JoinAll, the futures are boxed.
+impl<FutT1, FutT2> Unpin for TryJoin2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+}
+
+impl<FutT1, FutT2, T1, T2, E> Future for TryJoin2<FutT1, FutT2>
+where
+    FutT1: Future<Output = Result<T1, E>>,
+    FutT2: Future<Output = Result<T2, E>>,
+{
+    type Output = Result<(T1, T2), E>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        if this.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        poll_boxed_into(&mut this.fut1, &mut this.output1, ctx);
+        if let Some(Err(_)) = this.output1 {
+            return Poll::Ready(this.complete());
+        }
+
+        poll_boxed_into(&mut this.fut2, &mut this.output2, ctx);
+        if let Some(Err(_)) = this.output2 {
+            return Poll::Ready(this.complete());
+        }
+
+        if this.output1.is_some() && this.output2.is_some() {
+            return Poll::Ready(this.complete());
         }
 
         Poll::Pending
     }
 }
+
+impl<FutT1, FutT2, T1, T2, E> TryJoin2<FutT1, FutT2>
+where
+    FutT1: Future<Output = Result<T1, E>>,
+    FutT2: Future<Output = Result<T2, E>>,
+{
+    // Drops the futures that are still pending and hands out the outputs or the first error.
+    fn complete(&mut self) -> Result<(T1, T2), E> {
+        self.completed = true;
+        self.fut1 = None;
+        self.fut2 = None;
+        match (self.output1.take(), self.output2.take()) {
+            (Some(Err(error)), _) | (_, Some(Err(error))) => Err(error),
+            (Some(Ok(value1)), Some(Ok(value2))) => Ok((value1, value2)),
+            _ => unreachable!("TryJoin2 is completed without outputs"),
+        }
+    }
+}
+
+// Races the futures that resolve to Result: completes with the first success, the rest of the
+// futures are dropped right away (so their pending timers are cancelled). If all of them fail, the
+// errors are in the same order as the futures, an empty vector fails at once.
+pub fn first_ok<FutT, T, E>(futures: Vec<FutT>) -> FirstOk<FutT>
+where
+    FutT: Future<Output = Result<T, E>>,
+{
+    FirstOk {
+        outputs: futures.iter().map(|_| None).collect(),
+        futures: futures.into_iter().map(|fut| Some(Box::pin(fut))).collect(),
+        completed: false,
+    }
+}
+
+pub struct FirstOk<FutT>
+where
+    FutT: Future,
+{
+    // Same as in JoinAll, the failed futures are dropped
+    futures: Vec<Option<Pin<Box<FutT>>>>,
+    outputs: Vec<Option<FutT::Output>>,
+    completed: bool,
+}
+
+// Same as JoinAll, the futures are boxed.
+impl<FutT> Unpin for FirstOk<FutT> where FutT: Future {}
+
+impl<FutT, T, E> Future for FirstOk<FutT>
+where
+    FutT: Future<Output = Result<T, E>>,
+{
+    type Output = Result<T, Vec<E>>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        if this.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        let mut succeeded = None;
+        let pairs = this.futures.iter_mut().zip(this.outputs.iter_mut());
+        for (slot, output) in pairs {
+            poll_boxed_into(slot, output, ctx);
+            if let Some(Ok(_)) = output {
+                succeeded = output.take();
+                break;
+            }
+        }
+
+        if let Some(Ok(value)) = succeeded {
+            this.completed = true;
+            this.futures.clear(); // drops the rest
+            return Poll::Ready(Ok(value));
+        }
+
+        if this.futures.iter().any(Option::is_some) {
+            return Poll::Pending;
+        }
+
+        this.completed = true;
+        let errors = this.outputs.iter_mut().map(|output| match output.take() {
+            Some(Err(error)) => error,
+            _ => unreachable!("FirstOk has failed without errors"),
+        });
+        Poll::Ready(Err(errors.collect()))
+    }
+}
+
+// Polls the future unless it has completed already and keeps its output.
+fn poll_into<FutT>(fut: Pin<&mut FutT>, output: &mut Option<FutT::Output>, ctx: &mut Context<'_>)
+where
+    FutT: Future,
+{
+    if output.is_none() {
+        if let Poll::Ready(value) = fut.poll(ctx) {
+            *output = Some(value);
+        }
+    }
+}
+
+// Same as poll_into() for the boxed future that is dropped once completed.
+fn poll_boxed_into<FutT>(
+    slot: &mut Option<Pin<Box<FutT>>>,
+    output: &mut Option<FutT::Output>,
+    ctx: &mut Context<'_>,
+) where
+    FutT: Future,
+{
+    if let Some(fut) = slot {
+        if let Poll::Ready(value) = fut.as_mut().poll(ctx) {
+            *output = Some(value);
+            *slot = None;
+        }
+    }
+}
@@ -0,0 +1,62 @@
+// Joins any number of futures, resolves to the tuple of their outputs. Expands to the nested
+// make_join2(), so it does not create tasks: join!(a, b, c) is make_join2(a, make_join2(b, c))
+// with the nested tuple flattened.
+macro_rules! join {
+    ($($fut:expr),+ $(,)?) => {
+        $crate::toy::join!(@bind [] $($fut),+)
+    };
+    // every future gets its own output variable, `output` is a new one in every expansion
+    (@bind [$(($bound:expr, $output:ident))*] $fut:expr $(, $rest:expr)*) => {
+        $crate::toy::join!(@bind [$(($bound, $output))* ($fut, output)] $($rest),*)
+    };
+    (@bind [$(($bound:expr, $output:ident))*]) => {{
+        let joined = $crate::toy::join!(@nest make_join2, $($bound),*);
+        async move {
+            let $crate::toy::join!(@pattern $($output)*) = joined.await;
+            ($($output,)*)
+        }
+    }};
+    (@nest $join:ident, $fut:expr) => {
+        $fut
+    };
+    (@nest $join:ident, $fut:expr, $($rest:expr),+) => {
+        $crate::toy::$join($fut, $crate::toy::join!(@nest $join, $($rest),+))
+    };
+    (@pattern $output:ident) => {
+        $output
+    };
+    (@pattern $output:ident $($rest:ident)+) => {
+        ($output, $crate::toy::join!(@pattern $($rest)+))
+    };
+}
+
+// Same as join!() but every future runs in its own task, like in make_rt_join2().
+macro_rules! rt_join {
+    // a single future still gets its task
+    ($rt:expr, $fut:expr $(,)?) => {{
+        let joined = $crate::toy::make_rt_join2($rt, $fut, async {});
+        async move { (joined.await.0,) }
+    }};
+    ($rt:expr, $($fut:expr),+ $(,)?) => {
+        $crate::toy::rt_join!(@bind $rt, [] $($fut),+)
+    };
+    (@bind $rt:expr, [$(($bound:expr, $output:ident))*] $fut:expr $(, $rest:expr)*) => {
+        $crate::toy::rt_join!(@bind $rt, [$(($bound, $output))* ($fut, output)] $($rest),*)
+    };
+    (@bind $rt:expr, [$(($bound:expr, $output:ident))*]) => {{
+        let rt: &std::rc::Rc<$crate::toy::Runtime> = $rt;
+        let joined = $crate::toy::rt_join!(@nest rt, $($bound),*);
+        async move {
+            let $crate::toy::join!(@pattern $($output)*) = joined.await;
+            ($($output,)*)
+        }
+    }};
+    (@nest $rt:ident, $fut:expr, $last:expr) => {
+        $crate::toy::make_rt_join2($rt, $fut, $last)
+    };
+    (@nest $rt:ident, $fut:expr, $($rest:expr),+) => {
+        $crate::toy::make_rt_join2($rt, $fut, $crate::toy::rt_join!(@nest $rt, $($rest),+))
+    };
+}
+
+pub(crate) use {join, rt_join};
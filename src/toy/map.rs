@@ -0,0 +1,80 @@
+use super::contract::FuturePolledAfterCompletion;
+
+use pin_project::pin_project;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Future made by map() of the joins, e.g. RtJoin2::map(): applies the closure to the output. The
+// join is kept as is, so dropping Map destroys the tasks of the join the same way.
+#[pin_project]
+pub struct Map<FutT, FnT> {
+    #[pin]
+    fut: FutT,
+    // Taken on completion
+    f: Option<FnT>,
+}
+
+impl<FutT, FnT> Map<FutT, FnT> {
+    pub(super) fn new(fut: FutT, f: FnT) -> Self {
+        Self { fut, f: Some(f) }
+    }
+}
+
+impl<FutT, FnT, T> Future for Map<FutT, FnT>
+where
+    FutT: Future,
+    FnT: FnOnce(FutT::Output) -> T,
+{
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if this.f.is_none() {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        match this.fut.poll(ctx) {
+            Poll::Ready(output) => Poll::Ready((this.f.take().unwrap())(output)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Future made by inspect() of the joins: same as Map, but the closure only looks at the output.
+#[pin_project]
+pub struct Inspect<FutT, FnT> {
+    #[pin]
+    fut: FutT,
+    f: Option<FnT>,
+}
+
+impl<FutT, FnT> Inspect<FutT, FnT> {
+    pub(super) fn new(fut: FutT, f: FnT) -> Self {
+        Self { fut, f: Some(f) }
+    }
+}
+
+impl<FutT, FnT> Future for Inspect<FutT, FnT>
+where
+    FutT: Future,
+    FnT: FnOnce(&FutT::Output),
+{
+    type Output = FutT::Output;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if this.f.is_none() {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        match this.fut.poll(ctx) {
+            Poll::Ready(output) => {
+                (this.f.take().unwrap())(&output);
+                Poll::Ready(output)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,310 @@
+#[cfg(feature = "trace")]
+use std::cell::RefCell;
+use std::panic::Location;
+#[cfg(feature = "trace")]
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Number of buckets in Histogram. The bucket N counts durations in [2^(N-1), 2^N) microseconds,
+// the bucket 0 is for durations below 1µs and the last bucket also takes everything above.
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
+// Number of the busiest tasks that are gone Metrics keeps for top_cpu_tasks().
+#[cfg(feature = "trace")]
+const RETIRED_CPU_TASKS: usize = 64;
+
+// Histogram with fixed power-of-two buckets, so recording never allocates.
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl Histogram {
+    #[cfg(feature = "trace")]
+    fn record(&mut self, duration: Duration) {
+        self.buckets[Self::bucket_index(duration)] += 1;
+    }
+
+    // Index of the bucket the duration goes to.
+    pub fn bucket_index(duration: Duration) -> usize {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let index = (u64::BITS - micros.leading_zeros()) as usize;
+        index.min(HISTOGRAM_BUCKETS - 1)
+    }
+
+    pub fn buckets(&self) -> &[u64; HISTOGRAM_BUCKETS] {
+        &self.buckets
+    }
+
+    // Total number of recorded durations
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+// Histograms collected by runtime. Useful to choose the budgets for async cleanup.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct Histograms {
+    // How long each nested_loop() took to complete the cleanup.
+    pub(super) cleanup: Histogram,
+    // Time from the timer deadline till the task that waits for it is polled. It includes the
+    // time the event has been parked in frozen_events.
+    pub(super) timer_latency: Histogram,
+}
+
+impl Histograms {
+    pub fn cleanup(&self) -> &Histogram {
+        &self.cleanup
+    }
+
+    pub fn timer_latency(&self) -> &Histogram {
+        &self.timer_latency
+    }
+}
+
+// Poll statistics of a task. The time the task spends in nested_loop() is accounted to the cleanup
+// tasks of the loop, not to the task that has started it.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct TaskCpu {
+    // Type of the task future
+    pub(super) name: &'static str,
+    // Where the task has been created
+    pub(super) created_at: &'static Location<'static>,
+    pub(super) polls: u32,
+    // Total time spent in poll
+    pub(super) busy: Duration,
+}
+
+impl TaskCpu {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn created_at(&self) -> &'static Location<'static> {
+        self.created_at
+    }
+
+    pub fn polls(&self) -> u32 {
+        self.polls
+    }
+
+    pub fn busy(&self) -> Duration {
+        self.busy
+    }
+}
+
+// Moment captured for instrumentation. It is zero sized and free to take when the "trace"
+// feature is off.
+#[derive(Copy, Clone)]
+pub(super) struct Stamp {
+    #[cfg(feature = "trace")]
+    at: Instant,
+}
+
+impl Stamp {
+    #[inline(always)]
+    pub(super) fn now() -> Self {
+        Self {
+            #[cfg(feature = "trace")]
+            at: Instant::now(),
+        }
+    }
+}
+
+// Busy time of a poll without the nested loops run from it, see Runtime::pause_poll_frame(). It
+// is zero sized and never reads the clock when the "trace" feature is off.
+pub(super) struct PollClock {
+    #[cfg(feature = "trace")]
+    resumed_on: Instant,
+    #[cfg(feature = "trace")]
+    busy: Duration,
+}
+
+impl PollClock {
+    #[inline(always)]
+    pub(super) fn start() -> Self {
+        Self {
+            #[cfg(feature = "trace")]
+            resumed_on: Instant::now(),
+            #[cfg(feature = "trace")]
+            busy: Duration::ZERO,
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    pub(super) fn pause(&mut self) {
+        self.busy += self.resumed_on.elapsed();
+    }
+
+    #[cfg(feature = "trace")]
+    pub(super) fn resume(&mut self) {
+        self.resumed_on = Instant::now();
+    }
+
+    #[inline(always)]
+    pub(super) fn stop(self) -> Duration {
+        #[cfg(feature = "trace")]
+        return self.busy + self.resumed_on.elapsed();
+        #[cfg(not(feature = "trace"))]
+        Duration::ZERO
+    }
+}
+
+// Poll statistics of the tasks: the live ones by their slots and the busiest of the gone ones.
+#[cfg(feature = "trace")]
+#[derive(Default)]
+struct CpuTable {
+    // None for the free slots
+    live: Vec<Option<TaskCpu>>,
+    free: Vec<usize>,
+    // At most RETIRED_CPU_TASKS
+    retired: Vec<TaskCpu>,
+}
+
+#[cfg(feature = "trace")]
+impl CpuTable {
+    // Frees the slot of the gone task, its statistics are kept only if it is among the busiest.
+    fn retire(&mut self, index: usize) {
+        let task = self.live[index].take().expect("the slot of the live task");
+        self.free.push(index);
+        if self.retired.len() < RETIRED_CPU_TASKS {
+            self.retired.push(task);
+            return;
+        }
+        let idlest = self.retired.iter_mut().min_by_key(|retired| retired.busy);
+        if let Some(idlest) = idlest.filter(|idlest| idlest.busy < task.busy) {
+            *idlest = task;
+        }
+    }
+}
+
+// Slot of the task in Metrics for its poll statistics, it is freed when the task is gone. Zero
+// sized when the "trace" feature is off.
+pub(super) struct CpuSlot {
+    #[cfg(feature = "trace")]
+    table: Rc<RefCell<CpuTable>>,
+    #[cfg(feature = "trace")]
+    index: usize,
+}
+
+#[cfg(feature = "trace")]
+impl Drop for CpuSlot {
+    fn drop(&mut self) {
+        self.table.borrow_mut().retire(self.index);
+    }
+}
+
+// Runtime metrics, see Runtime::metrics(). Nothing is recorded when the "trace" feature is off.
+#[derive(Default)]
+pub struct Metrics {
+    #[cfg(feature = "trace")]
+    histograms: RefCell<Histograms>,
+    #[cfg(feature = "trace")]
+    tasks: Rc<RefCell<CpuTable>>,
+}
+
+impl Metrics {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "trace")]
+impl Metrics {
+    // Returns the copy of histograms collected so far.
+    pub fn histograms(&self) -> Histograms {
+        self.histograms.borrow().clone()
+    }
+
+    pub(super) fn record_cleanup(&self, started_on: Stamp) {
+        let duration = started_on.at.elapsed();
+        self.histograms.borrow_mut().cleanup.record(duration);
+    }
+
+    pub(super) fn record_timer_latency(&self, deadline: Instant, polled_on: Stamp) {
+        let latency = polled_on.at.saturating_duration_since(deadline);
+        self.histograms.borrow_mut().timer_latency.record(latency);
+    }
+
+    // The k tasks that have spent the most time in poll, the busiest first. Includes the tasks
+    // that are gone already, but only the RETIRED_CPU_TASKS busiest of them are kept.
+    pub fn top_cpu_tasks(&self, k: usize) -> Vec<TaskCpu> {
+        let table = self.tasks.borrow();
+        let live = table.live.iter().flatten();
+        let mut tasks: Vec<_> = live.chain(&table.retired).cloned().collect();
+        tasks.sort_by_key(|task| std::cmp::Reverse(task.busy));
+        tasks.truncate(k);
+        tasks
+    }
+
+    // Returns the slot for the task statistics.
+    pub(super) fn register_task(
+        &self,
+        name: &'static str,
+        created_at: &'static Location<'static>,
+    ) -> CpuSlot {
+        let mut table = self.tasks.borrow_mut();
+        let task = Some(TaskCpu {
+            name,
+            created_at,
+            polls: 0,
+            busy: Duration::ZERO,
+        });
+        let index = match table.free.pop() {
+            Some(index) => {
+                table.live[index] = task;
+                index
+            }
+            None => {
+                table.live.push(task);
+                table.live.len() - 1
+            }
+        };
+        CpuSlot {
+            table: self.tasks.clone(),
+            index,
+        }
+    }
+
+    pub(super) fn record_poll(&self, slot: &CpuSlot, busy: Duration) {
+        let mut table = self.tasks.borrow_mut();
+        let task = table.live[slot.index]
+            .as_mut()
+            .expect("the slot of the live task");
+        task.polls += 1;
+        task.busy += busy;
+    }
+}
+
+#[cfg(not(feature = "trace"))]
+impl Metrics {
+    // Histograms are always empty without "trace" feature.
+    pub fn histograms(&self) -> Histograms {
+        Histograms::default()
+    }
+
+    #[inline(always)]
+    pub(super) fn record_cleanup(&self, _started_on: Stamp) {}
+
+    #[inline(always)]
+    pub(super) fn record_timer_latency(&self, _deadline: Instant, _polled_on: Stamp) {}
+
+    // Tasks are not accounted without "trace" feature.
+    pub fn top_cpu_tasks(&self, _k: usize) -> Vec<TaskCpu> {
+        Vec::new()
+    }
+
+    #[inline(always)]
+    pub(super) fn register_task(
+        &self,
+        _name: &'static str,
+        _created_at: &'static Location<'static>,
+    ) -> CpuSlot {
+        CpuSlot {}
+    }
+
+    #[inline(always)]
+    pub(super) fn record_poll(&self, _slot: &CpuSlot, _busy: Duration) {}
+}
@@ -1,12 +1,65 @@
+mod async_drop;
+mod blocking;
+mod cancel;
+mod chain;
+mod contract;
+mod current;
+mod deadline_check;
+mod defer;
+mod ext;
+mod flatten;
+mod frozen;
+mod interval;
 mod join;
+mod join_macro;
+mod map;
+mod metrics;
+mod observer;
+pub mod oneshot;
+pub mod prelude;
 mod reactor;
+mod recorder;
+mod rt_chain;
 mod rt_join;
+mod rt_select;
 mod runtime;
+mod scope;
+mod select;
+mod shared;
 mod sleep;
+mod spawn;
+mod stream;
 mod task;
+mod task_set;
+mod timeout;
+mod watchdog;
 
-pub use join::make_join2;
-pub use reactor::Reactor;
-pub use rt_join::make_rt_join2;
+pub use async_drop::{AsyncDrop, AsyncDropGuard};
+pub use blocking::BlockingAction;
+pub use cancel::CancellationToken;
+pub use current::current;
+pub use deadline_check::DeadlineCheck;
+pub use ext::ToyFutureExt;
+pub use frozen::FrozenReport;
+pub use interval::{interval, MissedTick};
+pub use join::{first_ok, join_all, make_join2, make_join3, make_join4, make_try_join2, JoinState};
+pub(crate) use join_macro::{join, rt_join};
+pub use metrics::{Histogram, Histograms, TaskCpu};
+pub use observer::{RuntimeObserver, StdoutObserver};
+pub use reactor::{EventId, Reactor, TimerSource, Wait, MIN_INTERVAL_PERIOD};
+pub use rt_chain::make_rt_chain2;
+pub use rt_join::{
+    make_rt_join2, make_rt_join2_catching, make_rt_join2_deadline, make_rt_join_all,
+    make_rt_try_join2,
+};
+pub use rt_select::make_rt_select2;
 pub use runtime::{run, Runtime};
-pub use sleep::sleep;
+pub use scope::async_scope;
+pub use select::{make_select2, race2, Either, NoBranches, Select};
+pub use shared::shared;
+pub use sleep::{sleep, sleep_remaining, sleep_until, yield_now, Sleep, Slept};
+pub use stream::Stream;
+pub use task::TaskId;
+pub use task_set::{for_each_concurrent, join_stream, RtTaskSet, ZeroLimit};
+pub use timeout::{timeout, timeout_at, timeout_rt, Deadline, Elapsed};
+pub use watchdog::WatchdogVerdict;
@@ -0,0 +1,94 @@
+use super::frozen::FrozenReport;
+use super::reactor::EventId;
+use super::task::TaskId;
+use super::watchdog::WatchdogReport;
+
+// Hooks into the runtime for diagnostics, see Runtime::set_observer(). The methods do nothing by
+// default, so the observer only implements the ones it is interested in.
+pub trait RuntimeObserver {
+    // block_on() starts the top level loop
+    fn on_block_on(&self) {}
+
+    // The event parked in frozen_events is delivered: the task is not frozen anymore
+    fn on_poll_frozen(&self, _task: TaskId, _parent: Option<TaskId>) {}
+
+    // The event is for the task that has been destroyed already
+    fn on_poll_gone(&self, _task: TaskId) {}
+
+    // The event is for the task that has been aborted
+    fn on_poll_aborted(&self, _task: TaskId) {}
+
+    // The event is for a task outside of the running cleanup and it is held, see defer_dispatch()
+    fn on_event_deferred(&self, _event: EventId) {}
+
+    // The held events are released when the last DeferGuard is dropped
+    fn on_deferred_released(&self, _count: usize) {}
+
+    // The event has a foreign waker, the cleanup of the loop is polled for it
+    fn on_foreign_waker(&self, _event: EventId) {}
+
+    // The frozen events alert has fired, see set_frozen_alert()
+    fn on_frozen_alert(&self, _reports: &[FrozenReport]) {}
+
+    // The nested loop has not completed in time, see set_watchdog()
+    fn on_watchdog_report(&self, _report: &WatchdogReport) {}
+
+    // The watchdog has cancelled the nested loop at the depth
+    fn on_loop_cancelled(&self, _depth: u32) {}
+}
+
+// The observer of the runtime until another one is set.
+pub(super) struct NoopObserver;
+
+impl RuntimeObserver for NoopObserver {}
+
+// Prints the runtime events to stdout.
+pub struct StdoutObserver;
+
+impl RuntimeObserver for StdoutObserver {
+    fn on_block_on(&self) {
+        println!("block_on");
+    }
+
+    fn on_poll_frozen(&self, task: TaskId, parent: Option<TaskId>) {
+        println!(
+            "poll task {:?} (parent {:?}) from frozen_events",
+            task, parent
+        );
+    }
+
+    fn on_poll_gone(&self, task: TaskId) {
+        println!("poll the destroyed task {:?}, no-op", task);
+    }
+
+    fn on_poll_aborted(&self, task: TaskId) {
+        println!("poll the aborted task {:?}, no-op", task);
+    }
+
+    fn on_event_deferred(&self, event: EventId) {
+        println!("event {:?} is deferred", event);
+    }
+
+    fn on_deferred_released(&self, count: usize) {
+        println!("{} deferred events are released", count);
+    }
+
+    fn on_foreign_waker(&self, event: EventId) {
+        println!("event {:?} has a foreign waker", event);
+    }
+
+    fn on_frozen_alert(&self, reports: &[FrozenReport]) {
+        println!("frozen events alert: {:#?}", reports);
+    }
+
+    fn on_watchdog_report(&self, report: &WatchdogReport) {
+        println!(
+            "watchdog: nested loop has not completed in time: {:?}",
+            report
+        );
+    }
+
+    fn on_loop_cancelled(&self, depth: u32) {
+        println!("watchdog: nested loop at depth {} is cancelled", depth);
+    }
+}
@@ -0,0 +1,109 @@
+use super::contract::FuturePolledAfterCompletion;
+use super::reactor::EventId;
+use super::Runtime;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+// Makes a channel for a single value. The runtime only wakes tasks by reactor events, so the
+// waiting receiver has a timer that never fires and the sender replaces it with the timer that
+// fires right away.
+pub fn channel<T>(rt: &Rc<Runtime>) -> (Sender<T>, Receiver<T>) {
+    let shared = Rc::new(RefCell::new(Shared {
+        value: None,
+        sender_gone: false,
+        waiting: None,
+    }));
+    let sender = Sender {
+        rt: rt.clone(),
+        shared: shared.clone(),
+    };
+    let receiver = Receiver {
+        rt: rt.clone(),
+        shared,
+        completed: false,
+    };
+    (sender, receiver)
+}
+
+struct Shared<T> {
+    value: Option<T>,
+    sender_gone: bool,
+    // The event the receiver waits for and its waker
+    waiting: Option<(EventId, Waker)>,
+}
+
+pub struct Sender<T> {
+    rt: Rc<Runtime>,
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    pub fn send(self, value: T) {
+        self.shared.borrow_mut().value = Some(value);
+        // the receiver is notified by drop
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.sender_gone = true;
+        if let Some((event_id, waker)) = shared.waiting.take() {
+            self.rt.retire_event(event_id);
+            let event_id = self.rt.reactor().add_timer(&waker, Duration::ZERO);
+            shared.waiting = Some((event_id, waker));
+        }
+    }
+}
+
+// Resolves to the sent value or to None if the sender is dropped without sending.
+pub struct Receiver<T> {
+    rt: Rc<Runtime>,
+    shared: Rc<RefCell<Shared<T>>>,
+    completed: bool,
+}
+
+impl<T> Receiver<T> {
+    fn retire_waiting(&self) {
+        let waiting = self.shared.borrow_mut().waiting.take();
+        if let Some((event_id, _)) = waiting {
+            self.rt.retire_event(event_id);
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        let mut shared = self.shared.borrow_mut();
+        if shared.value.is_none() && !shared.sender_gone {
+            if shared.waiting.is_none() {
+                let event_id = self.rt.reactor().add_timer(ctx.waker(), Duration::MAX);
+                shared.waiting = Some((event_id, ctx.waker().clone()));
+            }
+            return Poll::Pending;
+        }
+
+        let value = shared.value.take();
+        drop(shared);
+        self.retire_waiting();
+        self.completed = true;
+        Poll::Ready(value)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.retire_waiting();
+    }
+}
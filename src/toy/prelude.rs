@@ -0,0 +1,3 @@
+// The traits most code needs, so `use toy::prelude::*;` keeps working when the traits are moved
+// around or new ones are added.
+pub use super::{AsyncDrop, Stream, TimerSource, ToyFutureExt};
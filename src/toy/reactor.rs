@@ -1,126 +1,382 @@
+use super::recorder::{FlightRecorder, Record};
+
 use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
 use std::task::Waker;
 use std::time::{Duration, Instant};
 
-// ID of the event in the reactor. This is a toy reactor, the only event is timer.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub struct EventId(u32);
+// The shortest period of the interval timer, see Reactor::add_interval().
+pub const MIN_INTERVAL_PERIOD: Duration = Duration::from_millis(1);
+
+// ID of the event in the reactor. This is a toy reactor, the only event is timer. Ids are never
+// reused within the reactor: the counter is 64 bit, so it does not wrap around in practice and a
+// live id cannot be handed out again.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct EventId(u64);
 
 #[derive(Clone, Debug)]
 pub struct Wait {
     pub event_id: EventId,
     pub waker: Waker,
+    pub awake_on: Instant,
 }
 
 impl Wait {
-    fn new(event_id: EventId, waker: Waker) -> Self {
-        Self { event_id, waker }
+    fn new(event_id: EventId, waker: Waker, awake_on: Instant) -> Self {
+        Self {
+            event_id,
+            waker,
+            awake_on,
+        }
+    }
+}
+
+// Source of the fired timers: the Reactor itself or a decorator on top of it, see DeadlineCheck.
+pub trait TimerSource {
+    /// Waits (sleeps) for a first timer to occurs. Returns None if there is no timers to wait.
+    fn wait(&self) -> Option<Wait>;
+
+    /// Fires the timer that is due already, never sleeps.
+    fn poll_ready(&self) -> Option<Wait>;
+
+    /// Same as wait(), but returns all the timers that are due by the time the first one fires,
+    /// e.g. the ones with the same deadline. Empty if there are no timers to wait.
+    fn wait_batch(&self) -> Vec<Wait> {
+        let first = match self.wait() {
+            Some(first) => first,
+            None => return Vec::new(),
+        };
+
+        let mut batch = vec![first];
+        batch.extend(std::iter::from_fn(|| self.poll_ready()));
+        batch
     }
 }
 
 pub struct Reactor {
     inner: RefCell<ReactorInner>,
+    recorder: FlightRecorder,
 }
 
 impl Reactor {
     pub fn new() -> Self {
         Self {
             inner: RefCell::new(ReactorInner::new()),
+            recorder: FlightRecorder::new(),
         }
     }
 
     /// Adds timer into reactor
     pub(super) fn add_timer(&self, waker: &Waker, duration: Duration) -> EventId {
-        self.inner.borrow_mut().add_timer(waker, duration)
+        let event_id = self.inner.borrow_mut().add_timer(waker, duration);
+        self.recorder.record(Record::TimerAdded(event_id));
+        event_id
     }
 
-    /// Cancel the timer by id. Panics if there is no timer with given id
-    pub(super) fn cancel_timer(&self, event_id: EventId) {
-        self.inner.borrow_mut().cancel_timer(event_id)
+    /// Adds timer into reactor that awakes on given instant
+    pub fn add_timer_at(&self, waker: &Waker, deadline: Instant) -> EventId {
+        let event_id = self.inner.borrow_mut().add_timer_at(waker, deadline);
+        self.recorder.record(Record::TimerAdded(event_id));
+        event_id
     }
 
-    /// Waits (sleeps) for a first timer to occurs. Returns None if there is no timers to wait.
-    pub(super) fn wait(&self) -> Option<Wait> {
-        self.inner.borrow_mut().wait()
+    /// Adds repeating timer into reactor, it fires every period till cancelled. Zero period is
+    /// treated as MIN_INTERVAL_PERIOD, so the interval cannot make the runtime busy-spin.
+    pub fn add_interval(&self, waker: &Waker, period: Duration) -> EventId {
+        let event_id = self.inner.borrow_mut().add_interval(waker, period);
+        self.recorder.record(Record::TimerAdded(event_id));
+        event_id
+    }
+
+    /// Cancel the timer by id. Returns false if there is no timer with given id, e.g. because it
+    /// has fired already.
+    pub fn cancel_timer(&self, event_id: EventId) -> bool {
+        let cancelled = self.inner.borrow_mut().cancel_timer(event_id);
+        if cancelled {
+            self.recorder.record(Record::TimerCancelled(event_id));
+        }
+        cancelled
+    }
+
+    /// Coalesces the timers: the ones due within the resolution after the earliest deadline fire
+    /// together on a single wakeup (see wait_batch()). The timers never fire early, so the earlier
+    /// ones of the window are late by up to the resolution. Zero (the default) is exact timing.
+    pub fn set_resolution(&self, resolution: Duration) {
+        self.inner.borrow_mut().resolution = resolution;
+    }
+
+    /// Last events of the runtime for the diagnostics
+    pub(super) fn recorder(&self) -> &FlightRecorder {
+        &self.recorder
+    }
+
+    /// Events waiting in reactor with their wakers
+    pub(super) fn pending_events(&self) -> Vec<(EventId, Waker)> {
+        let inner = self.inner.borrow();
+        let timers = inner.timers.iter();
+        let mut events: Vec<_> = timers
+            .map(|(&event_id, timer)| (event_id, timer.waker.clone()))
+            .collect();
+        events.sort_by_key(|(event_id, _)| *event_id);
+        events
+    }
+
+    /// If any of the events waiting in reactor has the waker matching the predicate
+    #[cfg(debug_assertions)]
+    pub(super) fn any_waker<PredFn>(&self, mut pred: PredFn) -> bool
+    where
+        PredFn: FnMut(&Waker) -> bool,
+    {
+        let inner = self.inner.borrow();
+        let mut timers = inner.timers.values();
+        timers.any(|timer| pred(&timer.waker))
+    }
+
+    /// Deadline of the timer that fires next, it is not consumed. None if there are no timers,
+    /// or only the ones that never fire.
+    pub fn peek_next_deadline(&self) -> Option<Instant> {
+        self.inner.borrow_mut().peek_next_deadline()
+    }
+
+    /// Deadline of the pending timer, None if the timer never fires or is not in reactor.
+    pub(super) fn timer_deadline(&self, event_id: EventId) -> Option<Instant> {
+        self.inner.borrow().timers.get(&event_id)?.awake_on
+    }
+
+    /// Number of timers waiting in reactor
+    pub fn timer_count(&self) -> usize {
+        self.inner.borrow().timers.len()
+    }
+
+    /// Pauses all timers till resume()
+    pub(super) fn suspend(&self) {
+        self.inner.borrow_mut().suspend()
+    }
+
+    /// Re-arms the timers paused by suspend()
+    pub(super) fn resume(&self) {
+        self.inner.borrow_mut().resume()
+    }
+}
+
+impl TimerSource for Reactor {
+    fn wait(&self) -> Option<Wait> {
+        if self.inner.borrow().suspended_on.is_some() {
+            panic!(
+                "Reactor.wait() is called while the runtime is suspended\n{}",
+                self.recorder.dump()
+            );
+        }
+
+        let wait = self.inner.borrow_mut().wait();
+        if let Some(wait) = &wait {
+            self.recorder.record(Record::TimerFired(wait.event_id));
+        }
+        wait
+    }
+
+    /// None if the next deadline is still ahead (see peek_next_deadline()) or the timers are
+    /// paused, so a foreign event loop can drive the reactor without blocking.
+    fn poll_ready(&self) -> Option<Wait> {
+        if self.inner.borrow().suspended_on.is_some() {
+            return None;
+        }
+
+        let wait = self.inner.borrow_mut().take_due()?;
+        self.recorder.record(Record::TimerFired(wait.event_id));
+        Some(wait)
     }
 }
 
 #[derive(Clone)]
 struct Timer {
-    event_id: EventId,
-    awake_on: Instant,
+    awake_on: Option<Instant>, // None is for the timer that never fires
     waker: Waker,
+    period: Option<Duration>, // Some for the interval timer
 }
 
 impl Timer {
-    fn new(event_id: EventId, waker: &Waker, duration: Duration) -> Self {
+    fn new(waker: &Waker, awake_on: Option<Instant>) -> Self {
         Self {
-            event_id,
-            awake_on: Instant::now() + duration,
+            awake_on,
             waker: waker.clone(),
+            period: None,
         }
     }
 }
 
+// Timers are ordered by the min-heap of deadlines, the wakers are kept aside. The cancelled timer is
+// only removed from the map, its heap entry becomes a tombstone that wait() skips. The timer which
+// deadline does not fit Instant never fires, so it is only in the map.
 struct ReactorInner {
-    timers: Vec<Timer>,
-    last_event_id: u32,
+    deadlines: BinaryHeap<Reverse<(Instant, EventId)>>,
+    timers: HashMap<EventId, Timer>,
+    last_event_id: u64,
+    suspended_on: Option<Instant>,
+    resolution: Duration,
 }
 
 impl ReactorInner {
     pub fn new() -> Self {
         Self {
-            timers: Vec::new(),
+            deadlines: BinaryHeap::new(),
+            timers: HashMap::new(),
             last_event_id: 0,
+            suspended_on: None,
+            resolution: Duration::ZERO,
         }
     }
 
-    /// Adds timer into reactors.
+    /// Adds timer into reactors. The timer added while suspended starts counting on resume. The
+    /// timer for a duration too long for Instant (e.g. Duration::MAX) never fires.
     pub fn add_timer(&mut self, waker: &Waker, duration: Duration) -> EventId {
+        let now = self.suspended_on.unwrap_or_else(Instant::now);
+        self.insert_timer(waker, now.checked_add(duration))
+    }
+
+    /// Adds timer into reactors.
+    pub fn add_timer_at(&mut self, waker: &Waker, awake_on: Instant) -> EventId {
+        self.insert_timer(waker, Some(awake_on))
+    }
+
+    /// Adds interval timer into reactors, the first tick is one period from now.
+    pub fn add_interval(&mut self, waker: &Waker, period: Duration) -> EventId {
+        let period = period.max(MIN_INTERVAL_PERIOD);
+        let now = self.suspended_on.unwrap_or_else(Instant::now);
+        let mut timer = Timer::new(waker, now.checked_add(period));
+        timer.period = Some(period);
+        self.insert(timer)
+    }
+
+    fn insert_timer(&mut self, waker: &Waker, awake_on: Option<Instant>) -> EventId {
+        self.insert(Timer::new(waker, awake_on))
+    }
+
+    fn insert(&mut self, timer: Timer) -> EventId {
         self.last_event_id += 1;
-        self.timers
-            .push(Timer::new(EventId(self.last_event_id), waker, duration));
+        let event_id = EventId(self.last_event_id);
+        if let Some(awake_on) = timer.awake_on {
+            self.deadlines.push(Reverse((awake_on, event_id)));
+        }
+        self.timers.insert(event_id, timer);
 
-        EventId(self.last_event_id)
+        event_id
     }
 
-    /// Cancel the timer by id. Panics if event_id is unknown.
-    pub fn cancel_timer(&mut self, event_id: EventId) {
-        // todo: maybe we should also make sure that event is removed from runtime.frozen_events.
-        let index = self
-            .timers
-            .iter()
-            .position(|timer| timer.event_id == event_id)
-            .expect("Canceled unknown timer");
+    // Removes the fired timer, or re-arms it if it is an interval. The next tick is counted from
+    // the deadline of this one, not from now, so the interval does not drift.
+    fn fire(&mut self, event_id: EventId) -> Option<Waker> {
+        let timer = self.timers.get_mut(&event_id)?;
+        match timer.period {
+            None => self.timers.remove(&event_id).map(|timer| timer.waker),
+            Some(period) => {
+                let next = timer
+                    .awake_on
+                    .and_then(|awake_on| awake_on.checked_add(period));
+                timer.awake_on = next;
+                if let Some(next) = next {
+                    self.deadlines.push(Reverse((next, event_id)));
+                }
+                Some(timer.waker.clone())
+            }
+        }
+    }
+
+    /// Cancel the timer by id. Returns false if event_id is unknown. Runtime::retire_event() takes
+    /// care of the fired events that runtime may still keep.
+    pub fn cancel_timer(&mut self, event_id: EventId) -> bool {
+        self.timers.remove(&event_id).is_some()
+    }
 
-        self.timers.remove(index);
+    /// Drops the tombstones from the top of the heap to find the next live deadline.
+    pub fn peek_next_deadline(&mut self) -> Option<Instant> {
+        while let Some(&Reverse((awake_on, event_id))) = self.deadlines.peek() {
+            if self.timers.contains_key(&event_id) {
+                return Some(awake_on);
+            }
+            self.deadlines.pop();
+        }
+        None
     }
 
     pub fn wait(&mut self) -> Option<Wait> {
         // This reactor IO is only timer.
-        // Looking for a first timer to awake on
-        let index = self
-            .timers
+        // Looking for a first timer to awake on, skipping the cancelled ones
+        while let Some(Reverse((awake_on, event_id))) = self.deadlines.pop() {
+            let waker = match self.fire(event_id) {
+                Some(waker) => waker,
+                None => continue, // tombstone
+            };
+
+            let now = Instant::now();
+            let wake_on = self.coalesced_deadline(awake_on);
+            if now < wake_on {
+                std::thread::sleep(wake_on - now);
+            }
+
+            // Firing a timer before its deadline is always a bug, DeadlineCheck catches it
+            return Some(Wait::new(event_id, waker, awake_on));
+        }
+
+        None // No events to wait, except the timers that never fire
+    }
+
+    // The latest deadline within the resolution after the given one, the sleep lasts till then so
+    // the whole window is due on wakeup.
+    fn coalesced_deadline(&self, awake_on: Instant) -> Instant {
+        if self.resolution.is_zero() {
+            return awake_on;
+        }
+
+        let window_end = awake_on.checked_add(self.resolution);
+        let timers = &self.timers;
+        let in_window = |deadline: &Instant| window_end.is_none_or(|end| *deadline <= end);
+        self.deadlines
             .iter()
-            .enumerate() // [(position, Timer)]
-            .min_by(|&l, &r| l.1.awake_on.cmp(&r.1.awake_on)) // Option<(position, Timer)>
-            .map(|pair| pair.0); // Option(position)
+            .filter(|Reverse((deadline, event_id))| {
+                in_window(deadline) && timers.contains_key(event_id)
+            })
+            .fold(awake_on, |latest, Reverse((deadline, _))| {
+                latest.max(*deadline)
+            })
+    }
+
+    /// Fires the next timer if it is due already, never sleeps.
+    pub fn take_due(&mut self) -> Option<Wait> {
+        let awake_on = self.peek_next_deadline()?;
+        if awake_on > Instant::now() {
+            return None;
+        }
+        let Reverse((awake_on, event_id)) = self.deadlines.pop()?;
+        let waker = self.fire(event_id)?;
+        Some(Wait::new(event_id, waker, awake_on))
+    }
 
-        if let Some(index) = index {
-            let Timer {
-                event_id,
-                awake_on,
-                waker,
-            } = self.timers.remove(index);
+    /// Remembers when the timers were paused. Does nothing if already suspended.
+    pub fn suspend(&mut self) {
+        if self.suspended_on.is_none() {
+            self.suspended_on = Some(Instant::now());
+        }
+    }
 
-            let now = Instant::now();
-            if now < awake_on {
-                std::thread::sleep(awake_on - now);
+    /// Shifts all deadlines by the time spent in suspension. All timers are moved by the same
+    /// amount, so their relative order is preserved.
+    pub fn resume(&mut self) {
+        if let Some(suspended_on) = self.suspended_on.take() {
+            let suspended_for = suspended_on.elapsed();
+            for timer in self.timers.values_mut() {
+                timer.awake_on = timer
+                    .awake_on
+                    .and_then(|awake_on| awake_on.checked_add(suspended_for));
             }
 
-            Some(Wait::new(event_id, waker))
-        } else {
-            None // No events to wait
+            // Tombstones are dropped on the way
+            let timers = &self.timers;
+            self.deadlines = timers
+                .iter()
+                .filter_map(|(&event_id, timer)| Some(Reverse((timer.awake_on?, event_id))))
+                .collect();
         }
     }
 }
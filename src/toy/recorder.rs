@@ -0,0 +1,84 @@
+use super::reactor::EventId;
+
+use std::cell::Cell;
+use std::fmt::{self, Write};
+use std::time::Instant;
+
+// Number of the last runtime events kept by FlightRecorder.
+const RECORDER_CAPACITY: usize = 256;
+
+// Runtime event kept in the flight recorder, only ids, so it is cheap to copy.
+#[derive(Copy, Clone)]
+pub(super) enum Record {
+    TimerAdded(EventId),
+    TimerFired(EventId),
+    TimerCancelled(EventId),
+    // Event has fired for the frozen task and has been parked in frozen_events
+    EventFrozen(EventId),
+    // Nested loop of given depth has started/finished
+    LoopEntered(u32),
+    LoopLeft(u32),
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Record::TimerAdded(event_id) => write!(f, "TimerAdded({:?})", event_id),
+            Record::TimerFired(event_id) => write!(f, "TimerFired({:?})", event_id),
+            Record::TimerCancelled(event_id) => write!(f, "TimerCancelled({:?})", event_id),
+            Record::EventFrozen(event_id) => write!(f, "EventFrozen({:?})", event_id),
+            Record::LoopEntered(depth) => write!(f, "LoopEntered({})", depth),
+            Record::LoopLeft(depth) => write!(f, "LoopLeft({})", depth),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    record: Record,
+    at: Instant,
+}
+
+// Ring of the last runtime events that is always on (unlike the observer), so the runtime panics
+// can tell what has happened right before. Recording is a couple of stores and never allocates,
+// the formatting happens only in dump().
+pub(super) struct FlightRecorder {
+    entries: [Cell<Option<Entry>>; RECORDER_CAPACITY],
+    total: Cell<usize>,
+}
+
+impl FlightRecorder {
+    pub(super) fn new() -> Self {
+        Self {
+            entries: std::array::from_fn(|_| Cell::new(None)),
+            total: Cell::new(0),
+        }
+    }
+
+    pub(super) fn record(&self, record: Record) {
+        let total = self.total.get();
+        let at = Instant::now();
+        self.entries[total % RECORDER_CAPACITY].set(Some(Entry { record, at }));
+        self.total.set(total + 1);
+    }
+
+    // Formats the recorded events from the oldest to the newest, to be appended to panic messages.
+    pub(super) fn dump(&self) -> String {
+        let total = self.total.get();
+        let first = total.saturating_sub(RECORDER_CAPACITY);
+        let now = Instant::now();
+
+        let mut dump = format!(
+            "flight recorder, last {} of {} events:",
+            total - first,
+            total
+        );
+        for seq in first..total {
+            if let Some(entry) = self.entries[seq % RECORDER_CAPACITY].get() {
+                let ago = now.saturating_duration_since(entry.at);
+                let _ = write!(dump, "\n  {}: {} {:?} ago", seq, entry.record, ago);
+            }
+        }
+        dump
+    }
+}
@@ -0,0 +1,139 @@
+use super::contract::FuturePolledAfterCompletion;
+use super::task::{GuardedTask, Task};
+use super::Runtime;
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+// Make a future that runs the first future to completion and then the second one, resolves to
+// both outputs. Unlike `chain()` or awaiting one after another, the whole sequence is a task (as
+// in make_rt_join2()), so it shows up in the task forest. If RtChain2 is dropped while the second
+// future is pending, the task is destroyed: the second future is dropped first (its async cleanup
+// runs in nested loops) and then the output of the first one.
+#[track_caller]
+pub fn make_rt_chain2<'f1, 'f2, FutT1, FutT2>(
+    rt: &Rc<Runtime>,
+    f1: FutT1,
+    f2: FutT2,
+) -> RtChain2<FutT1, FutT2>
+where
+    FutT1: Future + 'f1,
+    FutT2: Future + 'f2,
+{
+    let sequence = Sequence {
+        fut2: Some(Box::pin(f2)),
+        output1: None,
+        fut1: Some(Box::pin(f1)),
+    };
+    RtChain2 {
+        task: Some(unsafe { Task::allocate(rt, sequence) }),
+        _lifetime1: PhantomData,
+        _lifetime2: PhantomData,
+    }
+}
+
+pub struct RtChain2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    // None once completed
+    task: Option<GuardedTask<(FutT1::Output, FutT2::Output)>>,
+
+    // Same as in RtJoin2
+    _lifetime1: PhantomData<FutT1>,
+    _lifetime2: PhantomData<FutT2>,
+}
+
+// The task is allocated in heap, RtChain2 does not need pinning.
+impl<FutT1, FutT2> Unpin for RtChain2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+}
+
+impl<FutT1, FutT2> Future for RtChain2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    type Output = (FutT1::Output, FutT2::Output);
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let task = match &self.task {
+            Some(guarded) => guarded.task.clone(),
+            None => FuturePolledAfterCompletion::panic::<Self>(),
+        };
+
+        // The task could have been completed by a nested loop
+        if !task.is_completed() {
+            task.poll_child(ctx);
+        }
+
+        match self.task.as_ref().and_then(GuardedTask::take_output) {
+            Some(outputs) => {
+                self.task = None;
+                Poll::Ready(outputs)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+// The future of the chain task. The fields are dropped in the order of declaration: the pending
+// second future first, then the output of the first one.
+struct Sequence<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    fut2: Option<Pin<Box<FutT2>>>,
+    output1: Option<FutT1::Output>,
+    // None once completed
+    fut1: Option<Pin<Box<FutT1>>>,
+}
+
+// Futures are boxed, Sequence does not need pinning.
+impl<FutT1, FutT2> Unpin for Sequence<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+}
+
+impl<FutT1, FutT2> Future for Sequence<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    type Output = (FutT1::Output, FutT2::Output);
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if let Some(fut1) = &mut this.fut1 {
+            match fut1.as_mut().poll(ctx) {
+                Poll::Ready(output) => {
+                    this.output1 = Some(output);
+                    this.fut1 = None;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let fut2 = match &mut this.fut2 {
+            Some(fut2) => fut2,
+            None => FuturePolledAfterCompletion::panic::<Self>(),
+        };
+        match fut2.as_mut().poll(ctx) {
+            Poll::Ready(output2) => {
+                this.fut2 = None;
+                Poll::Ready((this.output1.take().unwrap(), output2))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
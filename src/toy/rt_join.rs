@@ -1,24 +1,35 @@
+use super::contract::FuturePolledAfterCompletion;
+use super::join::JoinState;
+use super::map::{Inspect, Map};
+use super::select::Either;
+use super::sleep::Sleep;
 use super::task::{GuardedTask, Task};
+use super::timeout::Elapsed;
 use super::Runtime;
+use std::any::Any;
+use std::fmt;
 use std::future::Future;
 use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use pin_project::pin_project;
 
-// Make a future that completes as soon as both futures are completed. Unlike other `make_join2`
-// (or `join!` in other crates). This one also creates tasks, which can be polled even if another
-// task is frozen by a nested_loop().
+// Make a future that completes as soon as both futures are completed and resolves to the tuple of
+// their outputs. Unlike other `make_join2` (or `join!` in other crates). This one also creates
+// tasks, which can be polled even if another task is frozen by a nested_loop().
+#[track_caller]
 pub fn make_rt_join2<'f1, 'f2, FutT1, FutT2>(
     rt: &Rc<Runtime>,
     f1: FutT1,
     f2: FutT2,
 ) -> RtJoin2<FutT1, FutT2>
 where
-    FutT1: Future<Output = ()> + 'f1,
-    FutT2: Future<Output = ()> + 'f2,
+    FutT1: Future + 'f1,
+    FutT2: Future + 'f2,
 {
     RtJoin2::<FutT1, FutT2>::new(rt, f1, f2)
 }
@@ -26,11 +37,17 @@ where
 #[pin_project]
 pub struct RtJoin2<FutT1, FutT2>
 where
-    FutT1: Future<Output = ()>,
-    FutT2: Future<Output = ()>,
+    FutT1: Future,
+    FutT2: Future,
 {
-    task1: GuardedTask,
-    task2: GuardedTask,
+    rt: Rc<Runtime>,
+    task1: GuardedTask<FutT1::Output>,
+    task2: GuardedTask<FutT2::Output>,
+    // Same as in Join2, the order of polls changes on every poll
+    task2_first: bool,
+    // Both tasks are polled on the first poll, then only the one the event is for
+    polled: bool,
+    terminated: bool,
 
     // Makes RtJoin2 to look like it owns FutT1 and FutT2 for borrow checker. If future has
     // references borrow checker would complain whenever user attempt RtJoin2 to outlive these.
@@ -40,13 +57,18 @@ where
 
 impl<FutT1, FutT2> RtJoin2<FutT1, FutT2>
 where
-    FutT1: Future<Output = ()>,
-    FutT2: Future<Output = ()>,
+    FutT1: Future,
+    FutT2: Future,
 {
+    #[track_caller]
     fn new(rt: &Rc<Runtime>, f1: FutT1, f2: FutT2) -> Self {
         Self {
+            rt: rt.clone(),
             task1: unsafe { Task::allocate(rt, f1) },
             task2: unsafe { Task::allocate(rt, f2) },
+            task2_first: false,
+            polled: false,
+            terminated: false,
             _lifetime1: PhantomData,
             _lifetime2: PhantomData,
         }
@@ -55,32 +77,530 @@ where
     fn is_completed(&self) -> bool {
         self.task1.task.is_completed() && self.task2.task.is_completed()
     }
+
+    // Same as Join2::is_terminated(): RtJoin2 has returned Ready and must not be polled anymore.
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    pub fn state(&self) -> JoinState {
+        JoinState {
+            first_done: self.task1.task.is_completed(),
+            second_done: self.task2.task.is_completed(),
+            first_frozen: self.task1.task.is_frozen(),
+            second_frozen: self.task2.task.is_frozen(),
+        }
+    }
+
+    // Applies the closure to the outputs once both are completed, see Map.
+    pub fn map<FnT, T>(self, f: FnT) -> Map<Self, FnT>
+    where
+        FnT: FnOnce((FutT1::Output, FutT2::Output)) -> T,
+    {
+        Map::new(self, f)
+    }
+
+    // Calls the closure with the outputs once both are completed, see Inspect.
+    pub fn inspect<FnT>(self, f: FnT) -> Inspect<Self, FnT>
+    where
+        FnT: FnOnce(&(FutT1::Output, FutT2::Output)),
+    {
+        Inspect::new(self, f)
+    }
+
+    // Turns the join into the future that completes as soon as the first task completes, the second
+    // task is handed over to the runtime and keeps running in background. Nobody gets its output,
+    // but run() does not return before it completes, so it is not cancelled halfway.
+    pub fn detach_second(self, rt: &Rc<Runtime>) -> RtJoinFirst<FutT1>
+    where
+        FutT2: 'static,
+    {
+        rt.detach(self.task2);
+        RtJoinFirst {
+            task: self.task1,
+            _lifetime: PhantomData,
+        }
+    }
+
+    // Hands out the outputs once both tasks are completed.
+    fn take_outputs(self: Pin<&mut Self>) -> Poll<(FutT1::Output, FutT2::Output)> {
+        if !self.is_completed() {
+            return Poll::Pending;
+        }
+
+        match (self.task1.take_output(), self.task2.take_output()) {
+            (Some(output1), Some(output2)) => {
+                *self.project().terminated = true;
+                Poll::Ready((output1, output2))
+            }
+            _ => FuturePolledAfterCompletion::panic::<Self>(),
+        }
+    }
+}
+
+impl<FutT1, FutT2> fmt::Debug for RtJoin2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RtJoin2")
+            .field("state", &self.state())
+            .finish()
+    }
 }
 
 impl<FutT1, FutT2> Future for RtJoin2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    type Output = (FutT1::Output, FutT2::Output);
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.is_completed() {
+            return self.take_outputs();
+        }
+
+        let this = self.as_mut().project();
+        let (first, second) = match this.task2_first {
+            true => (&this.task2.task, &this.task1.task),
+            false => (&this.task1.task, &this.task2.task),
+        };
+        *this.task2_first = !*this.task2_first;
+
+        // The child whose subtree the event is not for has nothing to do. The decision is made
+        // before the polls: a nested loop in the first child polls other tasks.
+        let first_poll = !std::mem::replace(this.polled, true);
+        let needs_poll = |task| first_poll || this.rt.is_event_within(task) != Some(false);
+        let (poll_first, poll_second) = (needs_poll(first), needs_poll(second));
+
+        if poll_first {
+            first.poll_child(ctx);
+        }
+        if poll_second && !second.is_completed() {
+            second.poll_child(ctx);
+        }
+        self.take_outputs()
+    }
+}
+
+// The join with the second task detached, see RtJoin2::detach_second().
+pub struct RtJoinFirst<FutT>
+where
+    FutT: Future,
+{
+    task: GuardedTask<FutT::Output>,
+    // Same as in RtJoin2
+    _lifetime: PhantomData<FutT>,
+}
+
+// The task is allocated in heap, RtJoinFirst does not need pinning.
+impl<FutT> Unpin for RtJoinFirst<FutT> where FutT: Future {}
+
+impl<FutT> Future for RtJoinFirst<FutT>
+where
+    FutT: Future,
+{
+    type Output = FutT::Output;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.task.task.is_completed() {
+            self.task.task.poll_child(ctx);
+            if !self.task.task.is_completed() {
+                return Poll::Pending;
+            }
+        }
+
+        match self.task.take_output() {
+            Some(output) => Poll::Ready(output),
+            None => FuturePolledAfterCompletion::panic::<Self>(),
+        }
+    }
+}
+
+// Same as make_rt_join2(), but the panic of a future does not unwind through the runtime: it is
+// caught and the future is completed with it, the other one keeps running to completion. Resolves
+// to the payloads of the caught panics, if any.
+#[track_caller]
+pub fn make_rt_join2_catching<'f1, 'f2, FutT1, FutT2>(
+    rt: &Rc<Runtime>,
+    f1: FutT1,
+    f2: FutT2,
+) -> RtJoin2Catching<FutT1, FutT2>
+where
+    FutT1: Future<Output = ()> + 'f1,
+    FutT2: Future<Output = ()> + 'f2,
+{
+    RtJoin2Catching {
+        join: RtJoin2::new(rt, CatchPanic::new(f1), CatchPanic::new(f2)),
+    }
+}
+
+// Polls the future catching its panic, the panic completes it with the payload.
+struct CatchPanic<FutT> {
+    fut: Pin<Box<FutT>>,
+}
+
+impl<FutT> CatchPanic<FutT> {
+    fn new(fut: FutT) -> Self {
+        Self { fut: Box::pin(fut) }
+    }
+}
+
+impl<FutT> Future for CatchPanic<FutT>
+where
+    FutT: Future,
+{
+    type Output = Result<FutT::Output, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.fut.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| fut.poll(ctx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+#[pin_project]
+pub struct RtJoin2Catching<FutT1, FutT2>
 where
     FutT1: Future<Output = ()>,
     FutT2: Future<Output = ()>,
 {
-    type Output = ();
+    #[pin]
+    join: RtJoin2<CatchPanic<FutT1>, CatchPanic<FutT2>>,
+}
+
+impl<FutT1, FutT2> Future for RtJoin2Catching<FutT1, FutT2>
+where
+    FutT1: Future<Output = ()>,
+    FutT2: Future<Output = ()>,
+{
+    type Output = Result<(), Vec<Box<dyn Any + Send>>>;
 
     fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.as_ref().project_ref();
+        self.project().join.poll(ctx).map(|(result1, result2)| {
+            let panics: Vec<_> = [result1, result2]
+                .into_iter()
+                .filter_map(Result::err)
+                .collect();
+            match panics.is_empty() {
+                true => Ok(()),
+                false => Err(panics),
+            }
+        })
+    }
+}
 
-        if self.is_completed() {
-            return Poll::Ready(());
+// Same as make_rt_join2() but for the futures that resolve to Result: completes as soon as one of
+// the tasks fails and the error tells which one. The other task is destroyed before the error is
+// returned, so its async cleanup (nested loops in the drops of its values) is completed by then.
+#[track_caller]
+pub fn make_rt_try_join2<'f1, 'f2, FutT1, FutT2, T1, T2, E1, E2>(
+    rt: &Rc<Runtime>,
+    f1: FutT1,
+    f2: FutT2,
+) -> RtTryJoin2<FutT1, FutT2>
+where
+    FutT1: Future<Output = Result<T1, E1>> + 'f1,
+    FutT2: Future<Output = Result<T2, E2>> + 'f2,
+{
+    RtTryJoin2 {
+        task1: Some(unsafe { Task::allocate(rt, f1) }),
+        task2: Some(unsafe { Task::allocate(rt, f2) }),
+        output1: None,
+        output2: None,
+        _lifetime1: PhantomData,
+        _lifetime2: PhantomData,
+    }
+}
+
+pub struct RtTryJoin2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    // Both are None once completed
+    task1: Option<GuardedTask<FutT1::Output>>,
+    task2: Option<GuardedTask<FutT2::Output>>,
+
+    // Outputs taken from the completed tasks
+    output1: Option<FutT1::Output>,
+    output2: Option<FutT2::Output>,
+
+    // Same as in RtJoin2
+    _lifetime1: PhantomData<FutT1>,
+    _lifetime2: PhantomData<FutT2>,
+}
+
+// Tasks are allocated in heap, RtTryJoin2 does not need pinning.
+impl<FutT1, FutT2> Unpin for RtTryJoin2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+}
+
+impl<FutT1, FutT2, T1, T2, E1, E2> RtTryJoin2<FutT1, FutT2>
+where
+    FutT1: Future<Output = Result<T1, E1>>,
+    FutT2: Future<Output = Result<T2, E2>>,
+{
+    fn collect_outputs(&mut self) {
+        if self.output1.is_none() {
+            self.output1 = self.task1.as_ref().and_then(GuardedTask::take_output);
         }
+        if self.output2.is_none() {
+            self.output2 = self.task2.as_ref().and_then(GuardedTask::take_output);
+        }
+    }
 
-        this.task1.task.poll_child(ctx);
-        if self.is_completed() {
-            return Poll::Ready(());
+    fn has_failed(&self) -> bool {
+        matches!(self.output1, Some(Err(_))) || matches!(self.output2, Some(Err(_)))
+    }
+
+    // Destroys the tasks and hands out the outputs or the error. A task is frozen only while its
+    // own poll, made from the poll of RtTryJoin2, runs a nested loop, so the sibling of the failed
+    // task is never frozen here: the error that comes during its nested loop is seen after it.
+    fn finish(&mut self) -> Poll<Result<(T1, T2), Either<E1, E2>>> {
+        self.task1 = None;
+        self.task2 = None;
+        match (self.output1.take(), self.output2.take()) {
+            (Some(Err(error)), _) => Poll::Ready(Err(Either::Left(error))),
+            (_, Some(Err(error))) => Poll::Ready(Err(Either::Right(error))),
+            (Some(Ok(value1)), Some(Ok(value2))) => Poll::Ready(Ok((value1, value2))),
+            _ => unreachable!("RtTryJoin2 is completed without outputs"),
         }
+    }
+}
 
-        this.task2.task.poll_child(ctx);
-        if self.is_completed() {
-            return Poll::Ready(());
+impl<FutT1, FutT2, T1, T2, E1, E2> Future for RtTryJoin2<FutT1, FutT2>
+where
+    FutT1: Future<Output = Result<T1, E1>>,
+    FutT2: Future<Output = Result<T2, E2>>,
+{
+    type Output = Result<(T1, T2), Either<E1, E2>>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let (task1, task2) = match (&this.task1, &this.task2) {
+            (Some(task1), Some(task2)) => (task1.task.clone(), task2.task.clone()),
+            _ => FuturePolledAfterCompletion::panic::<Self>(),
+        };
+
+        // Either task could have been completed by a nested loop
+        this.collect_outputs();
+        if this.has_failed() {
+            return this.finish();
+        }
+
+        for task in [task1, task2] {
+            if !task.is_completed() {
+                task.poll_child(ctx);
+                this.collect_outputs();
+                if this.has_failed() {
+                    return this.finish();
+                }
+            }
+        }
+
+        if this.output1.is_some() && this.output2.is_some() {
+            return this.finish();
         }
 
         Poll::Pending
     }
 }
+
+// Same as make_rt_join2() but the pair is raced against the deadline: if the deadline passes
+// first, the unfinished tasks are destroyed (their async cleanup is completed by then) and the
+// error has the outputs of the tasks that have completed in time.
+#[track_caller]
+pub fn make_rt_join2_deadline<'f1, 'f2, FutT1, FutT2>(
+    rt: &Rc<Runtime>,
+    duration: Duration,
+    f1: FutT1,
+    f2: FutT2,
+) -> RtJoin2Deadline<FutT1, FutT2>
+where
+    FutT1: Future + 'f1,
+    FutT2: Future + 'f2,
+{
+    RtJoin2Deadline {
+        task1: Some(unsafe { Task::allocate(rt, f1) }),
+        task2: Some(unsafe { Task::allocate(rt, f2) }),
+        output1: None,
+        output2: None,
+        deadline: Box::pin(Sleep::new(rt, duration)),
+        elapsed: false,
+        _lifetime1: PhantomData,
+        _lifetime2: PhantomData,
+    }
+}
+
+pub struct RtJoin2Deadline<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    // Both are None once completed
+    task1: Option<GuardedTask<FutT1::Output>>,
+    task2: Option<GuardedTask<FutT2::Output>>,
+
+    // Outputs taken from the completed tasks
+    output1: Option<FutT1::Output>,
+    output2: Option<FutT2::Output>,
+
+    deadline: Pin<Box<Sleep>>,
+    elapsed: bool,
+
+    // Same as in RtJoin2
+    _lifetime1: PhantomData<FutT1>,
+    _lifetime2: PhantomData<FutT2>,
+}
+
+// Same as RtTryJoin2, the tasks and the sleep are allocated in heap.
+impl<FutT1, FutT2> Unpin for RtJoin2Deadline<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+}
+
+impl<FutT1, FutT2> RtJoin2Deadline<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    fn collect_outputs(&mut self) {
+        if self.output1.is_none() {
+            self.output1 = self.task1.as_ref().and_then(GuardedTask::take_output);
+        }
+        if self.output2.is_none() {
+            self.output2 = self.task2.as_ref().and_then(GuardedTask::take_output);
+        }
+    }
+
+    // Same as RtTryJoin2::finish(): no task is frozen here, the deadline that passes during the
+    // nested loop of a task is seen after it.
+    fn finish(&mut self) -> Poll<<Self as Future>::Output> {
+        self.task1 = None;
+        self.task2 = None;
+        match (self.output1.take(), self.output2.take()) {
+            (Some(output1), Some(output2)) => Poll::Ready(Ok((output1, output2))),
+            (output1, output2) => Poll::Ready(Err((Elapsed, output1, output2))),
+        }
+    }
+}
+
+impl<FutT1, FutT2> Future for RtJoin2Deadline<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    // On timeout the error has the outputs of the tasks completed in time
+    type Output = Result<
+        (FutT1::Output, FutT2::Output),
+        (Elapsed, Option<FutT1::Output>, Option<FutT2::Output>),
+    >;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let (task1, task2) = match (&this.task1, &this.task2) {
+            (Some(task1), Some(task2)) => (task1.task.clone(), task2.task.clone()),
+            _ => FuturePolledAfterCompletion::panic::<Self>(),
+        };
+
+        // Either task could have been completed by a nested loop
+        this.collect_outputs();
+        if !this.elapsed {
+            for task in [task1, task2] {
+                if !task.is_completed() {
+                    task.poll_child(ctx);
+                }
+            }
+            this.collect_outputs();
+            if this.output1.is_some() && this.output2.is_some() {
+                return this.finish();
+            }
+            this.elapsed = this.deadline.as_mut().poll(ctx).is_ready();
+        }
+
+        match this.elapsed {
+            true => this.finish(),
+            false => Poll::Pending,
+        }
+    }
+}
+
+// Same as make_rt_join2() but for any number of futures of the same type: every future gets its
+// own task, all of them are children of the task that polls RtJoinAll. Resolves to the outputs in
+// the same order.
+#[track_caller]
+pub fn make_rt_join_all<'f, FutT>(rt: &Rc<Runtime>, futures: Vec<FutT>) -> RtJoinAll<FutT>
+where
+    FutT: Future + 'f,
+{
+    // not a closure, it would be the location of the tasks instead of the caller
+    let mut tasks = Vec::with_capacity(futures.len());
+    for fut in futures {
+        tasks.push(unsafe { Task::allocate(rt, fut) });
+    }
+
+    RtJoinAll {
+        tasks,
+        _lifetime: PhantomData,
+    }
+}
+
+pub struct RtJoinAll<FutT>
+where
+    FutT: Future,
+{
+    tasks: Vec<GuardedTask<FutT::Output>>,
+
+    // Same as in RtJoin2
+    _lifetime: PhantomData<FutT>,
+}
+
+impl<FutT> RtJoinAll<FutT>
+where
+    FutT: Future,
+{
+    fn is_completed(&self) -> bool {
+        self.tasks.iter().all(|guarded| guarded.task.is_completed())
+    }
+
+    fn take_outputs(&self) -> Poll<Vec<FutT::Output>> {
+        if !self.is_completed() {
+            return Poll::Pending;
+        }
+
+        let outputs = self.tasks.iter().map(GuardedTask::take_output);
+        match outputs.collect::<Option<Vec<_>>>() {
+            Some(outputs) => Poll::Ready(outputs),
+            None => FuturePolledAfterCompletion::panic::<Self>(),
+        }
+    }
+}
+
+impl<FutT> Future for RtJoinAll<FutT>
+where
+    FutT: Future,
+{
+    type Output = Vec<FutT::Output>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Some of the tasks could have been completed by nested loops, they are not polled again
+        for guarded in self.tasks.iter() {
+            if !guarded.task.is_completed() {
+                guarded.task.poll_child(ctx);
+            }
+        }
+
+        self.take_outputs()
+    }
+}
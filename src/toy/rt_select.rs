@@ -0,0 +1,107 @@
+use super::contract::FuturePolledAfterCompletion;
+use super::select::Either;
+use super::task::{GuardedTask, Task};
+use super::Runtime;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+// Make a future that completes as soon as one of the futures is completed, like make_select2(),
+// but each future runs in its own task as in make_rt_join2(). The task that lost the race is
+// destroyed before RtSelect2 returns Ready, so if the loser has values with async cleanup
+// (e.g. a Drop that runs nested_loop()) the cleanup is completed by then. The tasks are destroyed
+// in reverse order, the second one first, like the locals of a scope.
+#[track_caller]
+pub fn make_rt_select2<'f1, 'f2, FutT1, FutT2>(
+    rt: &Rc<Runtime>,
+    f1: FutT1,
+    f2: FutT2,
+) -> RtSelect2<FutT1, FutT2>
+where
+    FutT1: Future + 'f1,
+    FutT2: Future + 'f2,
+{
+    RtSelect2 {
+        task1: Some(unsafe { Task::allocate(rt, f1) }),
+        task2: Some(unsafe { Task::allocate(rt, f2) }),
+        _lifetime1: PhantomData,
+        _lifetime2: PhantomData,
+    }
+}
+
+pub struct RtSelect2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    // Both are None once the race is over. Declared in reverse, so task2 is dropped first.
+    task2: Option<GuardedTask<FutT2::Output>>,
+    task1: Option<GuardedTask<FutT1::Output>>,
+
+    // Same as in RtJoin2
+    _lifetime1: PhantomData<FutT1>,
+    _lifetime2: PhantomData<FutT2>,
+}
+
+// Tasks are allocated in heap, RtSelect2 does not need pinning.
+impl<FutT1, FutT2> Unpin for RtSelect2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+}
+
+impl<FutT1, FutT2> RtSelect2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    // Takes the output of the winner and destroys both tasks. The loser is not frozen here: if
+    // it runs a nested loop, RtSelect2 is polled by its ancestor that waits for the loop.
+    fn finish(&mut self) -> Poll<Either<FutT1::Output, FutT2::Output>> {
+        let output1 = self.task1.as_ref().and_then(GuardedTask::take_output);
+        let output2 = || self.task2.as_ref().and_then(GuardedTask::take_output);
+        let winner = if let Some(output) = output1 {
+            Either::Left(output)
+        } else if let Some(output) = output2() {
+            Either::Right(output)
+        } else {
+            return Poll::Pending;
+        };
+
+        self.task2 = None;
+        self.task1 = None;
+        Poll::Ready(winner)
+    }
+}
+
+impl<FutT1, FutT2> Future for RtSelect2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    type Output = Either<FutT1::Output, FutT2::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let (task1, task2) = match (&this.task1, &this.task2) {
+            (Some(task1), Some(task2)) => (task1.task.clone(), task2.task.clone()),
+            _ => FuturePolledAfterCompletion::panic::<Self>(),
+        };
+
+        // The winner could have been completed by a nested loop
+        if task1.is_completed() || task2.is_completed() {
+            return this.finish();
+        }
+
+        task1.poll_child(ctx);
+        if task1.is_completed() {
+            return this.finish();
+        }
+
+        task2.poll_child(ctx);
+        this.finish()
+    }
+}
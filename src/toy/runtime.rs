@@ -2,143 +2,873 @@ use std::cell::{Cell, RefCell};
 use std::future::Future;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::task::Waker;
+use std::time::{Duration, Instant};
 
+use super::blocking::{BlockingAction, BlockingDetector};
+use super::current::CurrentGuard;
+use super::defer::{DeferGuard, Deferral};
+use super::frozen::{FrozenAlert, FrozenEvent, FrozenReport};
+use super::metrics::{Metrics, PollClock, Stamp};
+use super::observer::{NoopObserver, RuntimeObserver};
+use super::oneshot;
 use super::reactor::EventId;
-use super::reactor::Wait;
-use super::task::Task;
+use super::reactor::{TimerSource, Wait};
+use super::recorder::Record;
+use super::spawn::{JoinHandle, Spawned};
 use super::task::TaskPoll;
+use super::task::{GuardedTask, Task, TaskId};
+use super::watchdog::{Watchdog, WatchdogReport, WatchdogVerdict};
 use crate::toy::Reactor;
 
+// The task name in FrozenReport for the events with foreign wakers, see task_of_waker().
+const FOREIGN_WAKER: &str = "<foreign waker>";
+
+// Default limit of nested_loop() depth, see Runtime::set_max_nested_depth()
+const MAX_NESTED_DEPTH: u32 = 256;
+
+// The task being polled, the tasks polled by nested loops are on top of it.
+struct PollFrame {
+    task: Arc<Task>,
+    // The task the reactor event is for, None if the poll is not caused by the event of a known
+    // task (the first poll, a foreign waker)
+    event_task: Option<Arc<Task>>,
+    clock: PollClock,
+}
+
 // Implementation of toy Runtime: async executor with reactor that only capable of scheduling
 // timers. It should be enough to demo the idea.
 pub struct Runtime {
     reactor: Reactor,
     awoken_event: Cell<Option<EventId>>,
-    frozen_events: RefCell<Vec<Wait>>,
+    frozen_events: RefCell<Vec<FrozenEvent>>,
+    frozen_alert: FrozenAlert,
+    deferral: Deferral,
+    metrics: Metrics,
+    blocking: BlockingDetector,
+    watchdog: Watchdog,
+    observer: RefCell<Box<dyn RuntimeObserver>>,
+    depth: Cell<u32>,
+    max_nested_depth: Cell<u32>,
+    in_block_on: Cell<bool>,
+    spawned: RefCell<Vec<Spawned>>,
+    // Notified when the detached tasks are completed, see run()
+    detached: RefCell<Vec<oneshot::Receiver<()>>>,
+    poll_frames: RefCell<Vec<PollFrame>>,
 
     // Need this visible for Waker/Task
     pub(super) awoken_task: Arc<RefCell<Option<Arc<Task>>>>,
+    // The tasks which polls are in progress (so they are frozen), the innermost on top
+    pub(super) polled_tasks: Arc<RefCell<Vec<Arc<Task>>>>,
+    pub(super) last_task_id: Cell<u64>,
 }
 
 impl Runtime {
+    // Runtime is single threaded, Arc is only here because Task has to be in Arc for Wake.
+    #[allow(clippy::arc_with_non_send_sync)]
     fn new() -> Self {
         Runtime {
             reactor: Reactor::new(),
             awoken_task: Arc::new(RefCell::new(None)),
+            polled_tasks: Arc::new(RefCell::new(Vec::new())),
             awoken_event: Cell::new(None),
             frozen_events: RefCell::new(Vec::new()),
+            frozen_alert: FrozenAlert::new(),
+            deferral: Deferral::new(),
+            metrics: Metrics::new(),
+            blocking: BlockingDetector::new(),
+            watchdog: Watchdog::new(),
+            observer: RefCell::new(Box::new(NoopObserver)),
+            depth: Cell::new(0),
+            max_nested_depth: Cell::new(MAX_NESTED_DEPTH),
+            in_block_on: Cell::new(false),
+            spawned: RefCell::new(Vec::new()),
+            detached: RefCell::new(Vec::new()),
+            poll_frames: RefCell::new(Vec::new()),
+            last_task_id: Cell::new(0),
         }
     }
 
     // Function that runs the nested poll loop making async destruction possible without
     // blocking all the tasks. So it starts the cleanup as a new task and poll all task
     // it can until cleanup is completed.
+    //
+    // The cleanup future may borrow from the caller, including `&mut self` of the value being
+    // dropped: the function does not return (or unwind) before the cleanup future is destroyed.
+    //
+    // While the cleanup runs, the other tasks keep running too unless they are frozen, and a
+    // frozen task gets its events once unfrozen. So the cleanup can await a value the sibling
+    // task sends only after its own nested loop is done: the sibling is polled by this loop, its
+    // nested loop polls this cleanup in turn (see test_async_drop_chain in main.rs).
+    #[track_caller]
     pub fn nested_loop<FutT>(&self, cleanup: FutT)
     where
         FutT: Future<Output = ()>,
+    {
+        self.run_nested_loop(cleanup, None);
+    }
+
+    // Same as nested_loop() but sets the deadline for the cleanup that the cleanup code can
+    // respect, e.g. with sleep_remaining(). The deadline is advisory, the runtime still waits for
+    // the cleanup to complete. Nested cleanups inherit the deadline: the tighter one wins.
+    #[track_caller]
+    pub fn nested_loop_with_deadline<FutT>(&self, deadline: Instant, cleanup: FutT)
+    where
+        FutT: Future<Output = ()>,
+    {
+        self.run_nested_loop(cleanup, Some(deadline));
+    }
+
+    #[track_caller]
+    fn run_nested_loop<FutT>(&self, cleanup: FutT, deadline: Option<Instant>)
+    where
+        FutT: Future<Output = ()>,
+    {
+        if self.depth.get() >= self.max_nested_depth.get() {
+            panic!(
+                "nested_loop() depth limit {} is exceeded, is there a runaway chain of async \
+                 drops?\n{}",
+                self.max_nested_depth.get(),
+                self.reactor.recorder().dump()
+            );
+        }
+
+        // The cleanup started from another cleanup cannot take longer than the outer one
+        let outer_deadline = self
+            .poll_frames
+            .borrow()
+            .last()
+            .and_then(|frame| frame.task.root().deadline());
+        let deadline = match (deadline, outer_deadline) {
+            (Some(deadline), Some(outer_deadline)) => Some(deadline.min(outer_deadline)),
+            (deadline, outer_deadline) => deadline.or(outer_deadline),
+        };
+
+        // The task that polls this nested loop is frozen till it is done, see Task::poll_impl()
+        let polling = self
+            .poll_frames
+            .borrow()
+            .last()
+            .map(|frame| frame.task.clone());
+        let polling = polling.map(|task| {
+            let in_nested_loop = task.set_in_nested_loop(true);
+            (task, in_nested_loop)
+        });
+
+        let started_on = Stamp::now();
+        self.blocking.leave_user_code();
+        let depth = self.depth.get() + 1;
+        self.depth.set(depth);
+        self.reactor.recorder().record(Record::LoopEntered(depth));
+        self.pause_poll_frame();
+        let _left = NestedLoopGuard {
+            rt: self,
+            depth,
+            polling,
+        };
+        self.run_loop(cleanup, deadline);
+        self.metrics.record_cleanup(started_on);
+    }
+
+    // The poll loop shared by nested_loop() and block_on(), returns the output of the future
+    // unless the loop has been cancelled by watchdog.
+    #[track_caller]
+    fn run_loop<FutT>(&self, cleanup: FutT, deadline: Option<Instant>) -> Option<FutT::Output>
+    where
+        FutT: Future,
     {
         let cleanup_task = unsafe { Task::allocate(self, cleanup) };
+        cleanup_task.task.set_deadline(deadline);
+        self.drive_loop(&cleanup_task.task);
+        cleanup_task.take_output()
+    }
 
+    // Polls the cleanup task and the tasks awoken by reactor until the cleanup is completed.
+    fn drive_loop(&self, cleanup: &Arc<Task>) {
         // Poll future once to give it chance to schedule its i/o in reactor
-        match cleanup_task.task.poll() {
-            TaskPoll::Ready => return,
-            _ => (),
+        if let TaskPoll::Ready = self.poll_task(cleanup, None) {
+            return;
         }
 
+        let mut watch = self.watchdog.watch();
+
         // Now wait for events from reactor to wake up unfrozen tasks
         loop {
+            self.poll_spawned();
             // If there are any events that was scheduled for frozen task that now unfrozen
             // and can be polled.
             self.poll_frozen_events();
             // cleanup task can be completed by some other nested loop
-            if cleanup_task.task.is_completed() {
+            if cleanup.is_completed() {
                 return;
             }
 
-            let wait = self.reactor().wait().expect("Reactor.wait() has failed");
+            // Nothing can wake the task up anymore
+            let wait = match self.reactor.wait() {
+                Some(wait) => wait,
+                None => panic!(
+                    "deadlock: task `{}` created at {} of the loop at depth {} is not completed, \
+                     but there are no events to wait for\n{}",
+                    cleanup.name(),
+                    cleanup.location(),
+                    self.depth.get(),
+                    self.reactor.recorder().dump()
+                ),
+            };
+
+            if self.deferral.is_active() && !self.is_event_of(&wait, cleanup) {
+                self.observer.borrow().on_event_deferred(wait.event_id);
+                self.deferral.hold(wait);
+                continue;
+            }
 
             self.awoken_event.set(Some(wait.event_id));
-            wait.waker.clone().wake(); // sets self.awoken_task
+            wait.waker.wake_by_ref(); // sets self.awoken_task
 
-            let awoken_task = self.awoken_task.borrow_mut().take().unwrap();
+            // A foreign waker (e.g. the one a combinator wraps the task waker with) may not wake
+            // the task right away, in this case the cleanup task of this loop is re-polled and
+            // the combinator is expected to poll its child that waits for the event. The event
+            // can be for a task of an outer loop as well, so it is parked for them too.
+            let event_task = self.awoken_task.borrow_mut().take();
+            let awoken_task = event_task.clone().unwrap_or_else(|| {
+                self.observer.borrow().on_foreign_waker(wait.event_id);
+                self.park_foreign_event(&wait);
+                cleanup.clone()
+            });
             let awoken_task = awoken_task.first_unfrozen_parent();
 
-            match awoken_task.poll() {
-                TaskPoll::Frozen => self.frozen_events.borrow_mut().push(wait),
-                _ => (),
+            let polled_on = Stamp::now();
+            match self.poll_task(&awoken_task, event_task) {
+                TaskPoll::Frozen => self.park_frozen_event(wait),
+                _ => self.metrics.record_timer_latency(wait.awake_on, polled_on),
             }
 
             // cleanup task can be completed by some other nested loop
-            if cleanup_task.task.is_completed() {
+            if cleanup.is_completed() {
                 return;
             }
+
+            // The top level loop of block_on() is not a cleanup, so it is not watched
+            if self.depth.get() > 0 {
+                let verdict = self.watchdog.on_wake(&mut watch, |wakes, elapsed| {
+                    self.watchdog_report(cleanup, wakes, elapsed)
+                });
+                if verdict == WatchdogVerdict::Cancel {
+                    self.observer.borrow().on_loop_cancelled(self.depth.get());
+                    return;
+                }
+            }
         }
     }
 
+    // Makes the first poll of the spawned tasks, so they can register their events, and destroys
+    // the tasks that are completed or cancelled. The tasks are polled and destroyed without
+    // self.spawned borrowed, because they can spawn or cancel other tasks.
+    fn poll_spawned(&self) {
+        let first_polls: Vec<_> = self
+            .spawned
+            .borrow_mut()
+            .iter_mut()
+            .filter(|spawned| !spawned.polled)
+            .map(|spawned| {
+                spawned.polled = true;
+                spawned.task().clone()
+            })
+            .collect();
+        for task in first_polls {
+            self.poll_task(&task, None);
+        }
+
+        let spawned = self.spawned.take();
+        let (done, alive): (Vec<_>, Vec<_>) = spawned.into_iter().partition(Spawned::is_done);
+        *self.spawned.borrow_mut() = alive;
+        drop(done);
+    }
+
+    // Aborts the spawned task and destroys it unless it is frozen, the frozen one is destroyed by
+    // the loop once its abort is completed.
+    pub(super) fn cancel_spawned(&self, task: &Arc<Task>) {
+        let pos = self
+            .spawned
+            .borrow()
+            .iter()
+            .position(|spawned| Arc::ptr_eq(spawned.task(), task));
+        let Some(pos) = pos else {
+            return; // completed and destroyed
+        };
+
+        let spawned = self.spawned.borrow_mut().remove(pos);
+        spawned.guarded.cancel();
+        if !spawned.is_done() {
+            self.spawned.borrow_mut().push(spawned);
+        }
+    }
+
+    // Makes the report about the stuck nested loop and emits the warning.
+    fn watchdog_report(
+        &self,
+        cleanup: &Arc<Task>,
+        wakes: u32,
+        elapsed: Duration,
+    ) -> WatchdogReport {
+        let waiting_on = self
+            .reactor
+            .pending_events()
+            .into_iter()
+            .filter(|(_, waker)| {
+                self.task_of_waker(waker)
+                    .is_some_and(|task| task.is_descendant_of(cleanup))
+            })
+            .map(|(event_id, _)| event_id)
+            .collect();
+
+        let report = WatchdogReport {
+            wakes,
+            elapsed,
+            depth: self.depth.get(),
+            task_name: cleanup.name(),
+            waiting_on,
+            task_dump: self.task_dump(cleanup),
+            history: self.reactor.recorder().dump(),
+        };
+        self.observer.borrow().on_watchdog_report(&report);
+        report
+    }
+
+    // The tree of the tasks the runtime knows about: the polled ones (they are frozen by the nested
+    // loops), the ones the pending and the parked events are for, and their ancestors.
+    fn task_dump(&self, cleanup: &Arc<Task>) -> String {
+        let polled = self.polled_tasks.borrow();
+        let pending = self.reactor.pending_events();
+        let parked = self.frozen_events.borrow();
+        let pending = pending
+            .iter()
+            .filter_map(|(_, waker)| self.task_of_waker(waker));
+        let known = polled
+            .iter()
+            .cloned()
+            .chain(pending)
+            .chain(parked.iter().filter_map(|event| self.task_of_event(event)))
+            .chain(std::iter::once(cleanup.clone()));
+
+        let mut tasks: Vec<Arc<Task>> = Vec::new();
+        for task in known {
+            let mut task = Some(task);
+            while let Some(current) = task {
+                if tasks.iter().any(|known| Arc::ptr_eq(known, &current)) {
+                    break;
+                }
+                task = current.parent();
+                tasks.push(current);
+            }
+        }
+        tasks.sort_by_key(|task| task.id());
+
+        // roots first, every child is under its parent
+        let mut dump = String::new();
+        let roots = tasks.iter().filter(|task| task.parent_id().is_none());
+        let mut stack: Vec<_> = roots.rev().map(|task| (task, 0)).collect();
+        while let Some((task, level)) = stack.pop() {
+            let marks = match (Arc::ptr_eq(task, cleanup), task.is_frozen()) {
+                (true, _) => " [cleanup]",
+                (false, true) => " [frozen]",
+                (false, false) => "",
+            };
+            dump += &format!(
+                "{:indent$}`{}` ({:?}) created at {}{}\n",
+                "",
+                task.name(),
+                task.id(),
+                task.location(),
+                marks,
+                indent = level * 2
+            );
+            let children = tasks
+                .iter()
+                .filter(|child| child.parent_id() == Some(task.id()))
+                .rev();
+            stack.extend(children.map(|child| (child, level + 1)));
+        }
+        dump
+    }
+
+    // If the event is for the cleanup task or its descendants. The task of a foreign waker is
+    // unknown, such event is considered to be the cleanup's.
+    fn is_event_of(&self, wait: &Wait, cleanup: &Arc<Task>) -> bool {
+        self.task_of_waker(&wait.waker)
+            .is_none_or(|task| task.is_descendant_of(cleanup))
+    }
+
+    // Converts waker to Arc<Task>, None for a foreign waker. The waker is not woken, so it is
+    // fine for the diagnostics.
+    fn task_of_waker(&self, waker: &Waker) -> Option<Arc<Task>> {
+        Task::from_waker(waker)
+    }
+
+    // The id of the task the waker is for, None for a foreign waker. Diagnostic helper for the
+    // task forest, the future can get the id of its own task from the context.
+    pub fn task_id(&self, waker: &Waker) -> Option<TaskId> {
+        self.task_of_waker(waker).map(|task| task.id())
+    }
+
+    // Same as task_id() but for the parent of the task, None for the root task.
+    pub fn parent_task_id(&self, waker: &Waker) -> Option<TaskId> {
+        self.task_of_waker(waker).and_then(|task| task.parent_id())
+    }
+
+    // Polls the root task and checks if it has been blocking.
+    fn poll_task(&self, task: &Arc<Task>, event_task: Option<Arc<Task>>) -> TaskPoll {
+        self.poll_frames.borrow_mut().push(PollFrame {
+            task: task.clone(),
+            event_task,
+            clock: PollClock::start(),
+        });
+        let frame = PollFrameGuard { rt: self, task };
+        self.blocking.enter_user_code();
+        let task_poll = task.poll();
+        self.blocking.leave_user_code();
+        drop(frame);
+
+        #[cfg(debug_assertions)]
+        if let TaskPoll::Pending = task_poll {
+            self.check_pending(task);
+        }
+
+        task_poll
+    }
+
+    // The task that returns Poll::Pending must have an event to be woken up with, either waiting
+    // in reactor or already fired and parked in frozen_events. Otherwise it hangs forever. The
+    // task of a foreign waker is unknown, so such a waker is given the benefit of the doubt.
+    #[cfg(debug_assertions)]
+    fn check_pending(&self, task: &Arc<Task>) {
+        let is_for_task = |waker: &Waker| {
+            self.task_of_waker(waker)
+                .is_none_or(|waker_task| waker_task.is_descendant_of(task))
+        };
+        let frozen_events = self.frozen_events.borrow();
+        let registered = self.reactor.any_waker(is_for_task)
+            || self.deferral.any_waker(is_for_task)
+            || frozen_events.iter().any(|event| match &event.target {
+                Some(target) => target.is_descendant_of(task),
+                None => is_for_task(&event.wait.waker),
+            });
+        if !registered {
+            panic!(
+                "contract violation: task `{}` created at {} has returned Poll::Pending without \
+                 an event in reactor, nothing is going to wake it up\n{}",
+                task.name(),
+                task.location(),
+                self.reactor.recorder().dump()
+            );
+        }
+    }
+
+    // Makes the held events deliverable the same way as the ones parked for the frozen tasks,
+    // so they are dispatched in the order they have fired.
+    pub(super) fn release_deferred(&self, held: Vec<Wait>) {
+        if held.is_empty() {
+            return;
+        }
+        self.observer.borrow().on_deferred_released(held.len());
+        let depth = self.depth.get();
+        let events = held.into_iter().map(|wait| FrozenEvent::new(wait, depth));
+        self.frozen_events.borrow_mut().extend(events);
+    }
+
+    pub(super) fn deferral(&self) -> &Deferral {
+        &self.deferral
+    }
+
+    // The time the polled task spends in nested loop goes to the cleanup tasks, not to it.
+    #[inline(always)]
+    fn pause_poll_frame(&self) {
+        #[cfg(feature = "trace")]
+        if let Some(frame) = self.poll_frames.borrow_mut().last_mut() {
+            frame.clock.pause();
+        }
+    }
+
+    #[inline(always)]
+    fn resume_poll_frame(&self) {
+        #[cfg(feature = "trace")]
+        if let Some(frame) = self.poll_frames.borrow_mut().last_mut() {
+            frame.clock.resume();
+        }
+    }
+
+    // The task the runtime is polling, the innermost one if nested loops are running.
+    pub(super) fn polled_task(&self) -> Option<Arc<Task>> {
+        let poll_frames = self.poll_frames.try_borrow().ok()?;
+        poll_frames.last().map(|frame| frame.task.clone())
+    }
+
+    // The deadline of the cleanup the currently polled task runs in, see
+    // nested_loop_with_deadline().
+    pub(super) fn cleanup_deadline(&self) -> Option<Instant> {
+        let poll_frames = self.poll_frames.borrow();
+        poll_frames
+            .last()
+            .and_then(|frame| frame.task.root().deadline())
+    }
+
+    // If the event the current poll is caused by is for the task or its descendants, None when it
+    // is unknown. Lets the combinators of tasks skip the children that have nothing to do.
+    pub(super) fn is_event_within(&self, task: &Arc<Task>) -> Option<bool> {
+        let poll_frames = self.poll_frames.borrow();
+        let event_task = poll_frames.last()?.event_task.as_ref()?;
+        Some(event_task.is_descendant_of(task))
+    }
+
     // Verifies if there is a event in self.frozen_events that can be polled because some of the
     // tasks has been recently unfrozen.
     fn poll_frozen_events(&self) {
-        while let Some((event_id, awoken_task)) = self.first_unfrozen_task() {
-            println!("poll task from frozen_events");
-            let awoken_task = awoken_task.first_unfrozen_parent();
-            self.awoken_event.set(Some(event_id));
+        while let Some((wait, event_task)) = self.first_unfrozen_task() {
+            let awoken_task = event_task.first_unfrozen_parent();
+            self.observer
+                .borrow()
+                .on_poll_frozen(awoken_task.id(), awoken_task.parent_id());
+            self.awoken_event.set(Some(wait.event_id));
+            self.metrics
+                .record_timer_latency(wait.awake_on, Stamp::now());
 
-            match awoken_task.poll() {
-                TaskPoll::Frozen => panic!("bug in first_unfrozen_task()/first_unfrozen_parent()"),
-                TaskPoll::Gone => println!("poll the destroyed task, no-op"),
+            match self.poll_task(&awoken_task, Some(event_task)) {
+                TaskPoll::Frozen => panic!(
+                    "bug in first_unfrozen_task()/first_unfrozen_parent()\n{}",
+                    self.reactor.recorder().dump()
+                ),
+                TaskPoll::Gone => self.observer.borrow().on_poll_gone(awoken_task.id()),
+                TaskPoll::Aborted => self.observer.borrow().on_poll_aborted(awoken_task.id()),
                 _ => (),
             }
         }
+        self.check_frozen_alert();
+    }
+
+    // Keeps the event for the frozen task till the task is unfrozen.
+    fn park_frozen_event(&self, wait: Wait) {
+        self.reactor
+            .recorder()
+            .record(Record::EventFrozen(wait.event_id));
+        let event = FrozenEvent::new(wait, self.depth.get());
+        self.frozen_events.borrow_mut().push(event);
+        self.check_frozen_alert();
+    }
+
+    // The alert callback can retire events (see retire_event()), so frozen_events is not borrowed
+    // while it runs.
+    fn check_frozen_alert(&self) {
+        let (count, oldest) = {
+            let frozen_events = self.frozen_events.borrow();
+            let oldest = frozen_events.first().map(|event| event.parked_on);
+            (frozen_events.len(), oldest)
+        };
+        self.frozen_alert.check(count, oldest, || {
+            let reports = self.frozen_reports(&self.frozen_events.borrow());
+            self.observer.borrow().on_frozen_alert(&reports);
+            reports
+        });
+    }
+
+    // The task of a foreign waker is unknown, it can be a task of the outer loops that are frozen
+    // till this loop is done. The event is parked for each of them to re-poll it when it
+    // unfreezes, the poll is spare if the event was not for it.
+    fn park_foreign_event(&self, wait: &Wait) {
+        let depth = self.depth.get();
+        let events: Vec<_> = self
+            .poll_frames
+            .borrow()
+            .iter()
+            .map(|frame| FrozenEvent::with_target(wait.clone(), depth, frame.task.clone()))
+            .collect();
+        if events.is_empty() {
+            return;
+        }
+        self.reactor
+            .recorder()
+            .record(Record::EventFrozen(wait.event_id));
+        self.frozen_events.borrow_mut().extend(events);
+        self.check_frozen_alert();
+    }
+
+    // The task to poll for the parked event.
+    fn task_of_event(&self, event: &FrozenEvent) -> Option<Arc<Task>> {
+        event
+            .target
+            .clone()
+            .or_else(|| self.task_of_waker(&event.wait.waker))
     }
 
     // Scans the self.frozen_event and returns the first event that supposed to be delivered to
     // currently unfrozen task.
-    fn first_unfrozen_task(&self) -> Option<(EventId, Arc<Task>)> {
-        // find the first unfrozen task in self.frozen_events
+    fn first_unfrozen_task(&self) -> Option<(Wait, Arc<Task>)> {
+        // find the first unfrozen task in self.frozen_events, counting the failed attempts
         let pos_and_task = self
             .frozen_events
             .borrow_mut()
-            .iter()
-            .map(|wait| {
-                // converts waker to Arc<Task>
-                wait.waker.clone().wake();
-                self.awoken_task.borrow_mut().take().unwrap()
-            })
+            .iter_mut()
+            .map(|event| (self.task_of_event(event), event))
             .enumerate()
-            .find(|(_pos, task)| !task.is_frozen());
+            .find_map(|(pos, (task, event))| match task {
+                Some(task) if task.is_frozen() => {
+                    event.attempts += 1;
+                    None
+                }
+                Some(task) => Some((pos, task)),
+                // the foreign wakers are parked with the target, see park_foreign_event()
+                None => None,
+            });
 
-        // Remove event from frozen_events and return as (EventId, Arc<Task>)
-        pos_and_task.map(|(pos, task)| {
-            let Wait { event_id, waker: _ } = self.frozen_events.borrow_mut().remove(pos);
-            (event_id, task)
-        })
+        // Remove event from frozen_events and return as (Wait, Arc<Task>)
+        pos_and_task.map(|(pos, task)| (self.frozen_events.borrow_mut().remove(pos).wait, task))
     }
 
     pub fn reactor(&self) -> &Reactor {
         &self.reactor
     }
 
+    // Histograms of cleanup durations and timer latencies, see Histograms.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
     pub fn is_awoken(&self, event_id: EventId) -> bool {
-        self.awoken_event.get().map_or(false, |id| id == event_id)
+        self.awoken_event.get() == Some(event_id)
     }
 
-    // The block_on version is private and therefore is not reentrable.
-    fn block_on<FutT>(&self, fut: FutT)
+    // Makes the event unobservable: cancels the timer in reactor if it has not fired yet, removes
+    // the event from frozen_events if it has fired while the task was frozen and resets the
+    // awoken_event. All the cancellation paths (e.g. drop of pending Sleep) should end up here,
+    // including the ones of the futures made on top of the reactor outside of this crate.
+    pub fn retire_event(&self, event_id: EventId) {
+        self.reactor.cancel_timer(event_id);
+        self.frozen_events
+            .borrow_mut()
+            .retain(|event| event.wait.event_id != event_id);
+        self.deferral.retire(event_id);
+
+        if self.is_awoken(event_id) {
+            self.awoken_event.set(None);
+        }
+    }
+
+    // Defers the dispatch of the events for the tasks outside of the running cleanup till the
+    // guard is dropped. The cleanup (and nested_loop() called while the guard is alive) still
+    // gets its own events, but the others are held even if their tasks are not frozen. When the
+    // last guard is dropped they are dispatched in the order they have fired.
+    pub fn defer_dispatch(&self) -> DeferGuard<'_> {
+        DeferGuard::new(self)
+    }
+
+    // Runs the future as a task owned by the runtime. The task is polled by the loops of the
+    // runtime (the first poll is made by the loop as well), the handle resolves to its output.
+    // Dropping the handle cancels the task, the tasks still running when run() returns are
+    // destroyed.
+    #[track_caller]
+    pub fn spawn<FutT>(self: &Rc<Self>, fut: FutT) -> JoinHandle<FutT::Output>
     where
-        FutT: Future<Output = ()>,
+        FutT: Future + 'static,
+    {
+        let (sender, receiver) = oneshot::channel(self);
+        let guarded = unsafe { Task::allocate(self, async move { sender.send(fut.await) }) };
+        let handle = JoinHandle::new(self, guarded.task.clone(), receiver);
+        self.spawned.borrow_mut().push(Spawned::new(guarded));
+        handle
+    }
+
+    // Hands the task over to the runtime: it becomes a root task polled by the loops of the runtime
+    // and run() does not return before it completes. See RtJoin2::detach_second().
+    pub(super) fn detach<T: 'static>(self: &Rc<Self>, guarded: GuardedTask<T>) {
+        guarded.task.detach_parent();
+        let (done, receiver) = oneshot::channel(self);
+        self.spawned
+            .borrow_mut()
+            .push(Spawned::detached(guarded, done));
+        self.detached.borrow_mut().push(receiver);
+    }
+
+    // Completes when all the detached tasks are completed, the ones detached meanwhile as well.
+    async fn wait_detached(self: Rc<Self>) {
+        loop {
+            let receiver = self.detached.borrow_mut().pop();
+            match receiver {
+                Some(receiver) => receiver.await,
+                None => break,
+            };
+        }
+    }
+
+    // Number of events that has fired for frozen tasks and wait for the tasks to unfreeze.
+    pub fn frozen_event_count(&self) -> usize {
+        self.frozen_events.borrow().len()
+    }
+
+    // Reports the events parked in frozen_events: which task they are for, which frozen task
+    // holds them and for how long. Slow shutdown is often caused by the events parked again and
+    // again.
+    pub fn analyze_frozen(&self) -> Vec<FrozenReport> {
+        self.frozen_reports(&self.frozen_events.borrow())
+    }
+
+    fn frozen_reports(&self, frozen_events: &[FrozenEvent]) -> Vec<FrozenReport> {
+        let reports = frozen_events.iter().map(|event| {
+            let task = self.task_of_event(event);
+            let frozen_by = task.as_ref().map(|task| {
+                task.first_frozen_ancestor()
+                    .unwrap_or_else(|| task.clone())
+                    .name()
+            });
+            FrozenReport {
+                event_id: event.wait.event_id,
+                task: task.map_or(FOREIGN_WAKER, |task| task.name()),
+                frozen_by: frozen_by.unwrap_or(FOREIGN_WAKER),
+                parked_for: event.parked_on.elapsed(),
+                depth: event.depth,
+                replay_attempts: event.attempts,
+            }
+        });
+        reports.collect()
+    }
+
+    // Calls the callback (and traces the reports) once frozen_events has max_count events or
+    // the oldest one has been parked for max_age. The alert is re-armed when all the parked
+    // events are delivered.
+    pub fn set_frozen_alert<CallbackFn>(&self, max_count: usize, max_age: Duration, cb: CallbackFn)
+    where
+        CallbackFn: Fn(&[FrozenReport]) + 'static,
     {
-        println!("block_on");
-        self.nested_loop(fut)
+        self.frozen_alert.set(max_count, max_age, cb);
+    }
+
+    // Makes runtime to detect the polls that take longer than threshold, e.g. because of a
+    // blocking call like std::thread::sleep() in async code, and either warn or panic. Time
+    // spent in nested_loop() is not counted for the poll that has invoked it.
+    pub fn set_blocking_detection(&self, threshold: Duration, action: BlockingAction) {
+        self.blocking.set(threshold, action);
+    }
+
+    // Sets the watchdog for the nested loops: if nested_loop() has processed max_wakes reactor
+    // events or has been running for max_duration and its cleanup is still not completed, the
+    // warning is emitted and the callback decides if the loop should be cancelled.
+    pub fn set_watchdog<CallbackFn>(&self, max_wakes: u32, max_duration: Duration, cb: CallbackFn)
+    where
+        CallbackFn: Fn(&WatchdogReport) -> WatchdogVerdict + 'static,
+    {
+        self.watchdog.set(max_wakes, max_duration, cb);
+    }
+
+    // Limits the depth of nested_loop() calls, the one that exceeds the limit panics. Every level
+    // runs its own loop on the stack, so a runaway chain of async drops would overflow it
+    // otherwise.
+    pub fn set_max_nested_depth(&self, max_depth: u32) {
+        self.max_nested_depth.set(max_depth);
+    }
+
+    // Replaces the observer of the runtime events, e.g. with StdoutObserver. By default the events
+    // are not observed.
+    pub fn set_observer(&self, observer: Box<dyn RuntimeObserver>) {
+        *self.observer.borrow_mut() = observer;
+    }
+
+    // Suspends the runtime: all pending timers are paused until resume(), so the time spent in
+    // suspension does not count for them. This is for inspecting a running system from a
+    // synchronous code, e.g. a debugger hook invoked from poll. Runtime cannot wait for events
+    // while suspended and panics if it has to.
+    pub fn suspend(&self) {
+        self.reactor.suspend();
+    }
+
+    // Resumes the runtime suspended by suspend(), the paused timers are re-armed with their
+    // remaining durations.
+    pub fn resume(&self) {
+        self.reactor.resume();
+    }
+
+    // Runs the future to completion on this runtime, e.g. from the starter of run(). It is not
+    // reentrant: the code that runs in the runtime has to use nested_loop() instead.
+    #[track_caller]
+    pub fn block_on<FutT>(&self, fut: FutT) -> FutT::Output
+    where
+        FutT: Future,
+    {
+        if self.in_block_on.replace(true) {
+            panic!(
+                "block_on() is called from the future the runtime runs, use nested_loop() \
+                 instead"
+            );
+        }
+
+        let _in_block_on = BlockOnGuard(&self.in_block_on);
+
+        self.observer.borrow().on_block_on();
+        // the top level loop either completes its task or panics on deadlock
+        let output = self.run_loop(fut, None);
+        output.expect("the task of block_on() is completed")
+    }
+}
+
+// Leaves the nested loop when it returns or unwinds, so the panic of the cleanup caught above the
+// runtime (see make_rt_join2_catching()) does not leak the depth or keep the polling task frozen.
+struct NestedLoopGuard<'rt> {
+    rt: &'rt Runtime,
+    depth: u32,
+    // The task that polls the loop and its in_nested_loop flag before the loop
+    polling: Option<(Arc<Task>, bool)>,
+}
+
+impl Drop for NestedLoopGuard<'_> {
+    fn drop(&mut self) {
+        let rt = self.rt;
+        rt.resume_poll_frame();
+        rt.reactor.recorder().record(Record::LoopLeft(self.depth));
+        rt.depth.set(self.depth - 1);
+        rt.blocking.enter_user_code();
+        if let Some((task, in_nested_loop)) = &self.polling {
+            task.set_in_nested_loop(*in_nested_loop);
+        }
     }
 }
 
-// This is how app creates Runtime.
-pub fn run<StarterFn, FutT>(starter: StarterFn)
+// Pops the poll frame of the task when the poll returns or unwinds, see NestedLoopGuard.
+struct PollFrameGuard<'rt> {
+    rt: &'rt Runtime,
+    task: &'rt Arc<Task>,
+}
+
+impl Drop for PollFrameGuard<'_> {
+    fn drop(&mut self) {
+        let frame = self.rt.poll_frames.borrow_mut().pop().unwrap();
+        self.rt
+            .metrics
+            .record_poll(self.task.cpu_slot(), frame.clock.stop());
+    }
+}
+
+// Resets Runtime::in_block_on when block_on() returns or unwinds, so the runtime can be used again
+// after the panic is caught.
+struct BlockOnGuard<'rt>(&'rt Cell<bool>);
+
+impl Drop for BlockOnGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+// This is how app creates Runtime, returns what the future made by starter resolves to.
+#[track_caller]
+pub fn run<StarterFn, FutT>(starter: StarterFn) -> FutT::Output
 where
     StarterFn: FnOnce(Rc<Runtime>) -> FutT,
-    FutT: Future<Output = ()>,
+    FutT: Future,
 {
     let rt = Rc::new(Runtime::new());
+    let _current = CurrentGuard::enter(&rt);
     let future = starter(rt.clone());
-    rt.block_on(future);
+    let output = rt.block_on(future);
+    if !rt.detached.borrow().is_empty() {
+        rt.block_on(rt.clone().wait_detached());
+    }
+    // the spawned tasks keep the runtime alive
+    drop(rt.spawned.take());
+    output
 }
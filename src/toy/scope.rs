@@ -0,0 +1,97 @@
+use super::contract::FuturePolledAfterCompletion;
+use super::Runtime;
+
+use pin_project::{pin_project, pinned_drop};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+// Runs the body and then the cleanup, resolves to the output of the body. The cleanup runs even
+// if the scope is dropped before completion: the body is dropped and the cleanup (or what is left
+// of it) is run to completion in a nested loop. RAII for async resources in the form of a
+// combinator, see AsyncDropGuard for the form of a trait.
+pub fn async_scope<BodyT, CleanupT>(
+    rt: &Rc<Runtime>,
+    body: BodyT,
+    cleanup: CleanupT,
+) -> AsyncScope<BodyT, CleanupT>
+where
+    BodyT: Future,
+    CleanupT: Future<Output = ()>,
+{
+    AsyncScope {
+        rt: rt.clone(),
+        body: Some(Box::pin(body)),
+        output: None,
+        cleanup: Some(Box::pin(cleanup)),
+    }
+}
+
+#[pin_project(PinnedDrop)]
+pub struct AsyncScope<BodyT, CleanupT>
+where
+    BodyT: Future,
+    CleanupT: Future<Output = ()>,
+{
+    rt: Rc<Runtime>,
+    // None once completed
+    body: Option<Pin<Box<BodyT>>>,
+    // Kept till the cleanup is completed
+    output: Option<BodyT::Output>,
+    // None once completed
+    cleanup: Option<Pin<Box<CleanupT>>>,
+}
+
+impl<BodyT, CleanupT> Future for AsyncScope<BodyT, CleanupT>
+where
+    BodyT: Future,
+    CleanupT: Future<Output = ()>,
+{
+    type Output = BodyT::Output;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if let Some(body) = this.body {
+            match body.as_mut().poll(ctx) {
+                Poll::Ready(output) => {
+                    *this.output = Some(output);
+                    *this.body = None;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        // the cleanup is awaited right here rather than in a nested loop
+        let cleanup = match this.cleanup {
+            Some(cleanup) => cleanup,
+            None => FuturePolledAfterCompletion::panic::<Self>(),
+        };
+        match cleanup.as_mut().poll(ctx) {
+            Poll::Ready(()) => {
+                *this.cleanup = None;
+                Poll::Ready(this.output.take().unwrap())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[pinned_drop]
+impl<BodyT, CleanupT> PinnedDrop for AsyncScope<BodyT, CleanupT>
+where
+    BodyT: Future,
+    CleanupT: Future<Output = ()>,
+{
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+
+        // the body goes first, as it would do in a scope
+        *this.body = None;
+        if let Some(cleanup) = this.cleanup.take() {
+            this.rt.nested_loop(cleanup);
+        }
+    }
+}
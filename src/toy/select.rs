@@ -0,0 +1,242 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use pin_project::pin_project;
+
+use super::contract::FuturePolledAfterCompletion;
+
+// Output of select combinators: tells which of the futures has completed first.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+impl<L, R> Either<L, R> {
+    pub fn map_left<MapFn, L2>(self, map: MapFn) -> Either<L2, R>
+    where
+        MapFn: FnOnce(L) -> L2,
+    {
+        match self {
+            Either::Left(left) => Either::Left(map(left)),
+            Either::Right(right) => Either::Right(right),
+        }
+    }
+
+    pub fn map_right<MapFn, R2>(self, map: MapFn) -> Either<L, R2>
+    where
+        MapFn: FnOnce(R) -> R2,
+    {
+        match self {
+            Either::Left(left) => Either::Left(left),
+            Either::Right(right) => Either::Right(map(right)),
+        }
+    }
+}
+
+// Make a future that completes as soon as one of the futures is completed. The future that lost
+// the race is dropped together with Select2, so a pending Sleep in it cancels its timer. This
+// select does not create tasks in runtime. The futures are dropped in reverse order, the second
+// one first, like the locals of a scope.
+pub fn make_select2<FutT1, FutT2>(f1: FutT1, f2: FutT2) -> Select2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    Select2::<FutT1, FutT2>::new(f1, f2)
+}
+
+#[pin_project]
+pub struct Select2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    // The fields are dropped in the order they are declared, so fut2 goes first
+    #[pin]
+    fut2: FutT2,
+    #[pin]
+    fut1: FutT1,
+}
+
+impl<FutT1, FutT2> Select2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    fn new(f1: FutT1, f2: FutT2) -> Self {
+        Self { fut1: f1, fut2: f2 }
+    }
+}
+
+impl<FutT1, FutT2> Future for Select2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    type Output = Either<FutT1::Output, FutT2::Output>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if let Poll::Ready(output) = this.fut1.poll(ctx) {
+            return Poll::Ready(Either::Left(output));
+        }
+
+        if let Poll::Ready(output) = this.fut2.poll(ctx) {
+            return Poll::Ready(Either::Right(output));
+        }
+
+        Poll::Pending
+    }
+}
+
+// Same as make_select2() but the futures are polled in turns: the one polled second on this poll
+// is polled first on the next one, so the second future cannot lose just because of the order.
+// The loser is dropped as soon as the winner is ready and is never polled again. Same as
+// make_select2() the second future is dropped first.
+pub fn race2<FutT1, FutT2>(f1: FutT1, f2: FutT2) -> Race2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    Race2 {
+        fut1: Some(Box::pin(f1)),
+        fut2: Some(Box::pin(f2)),
+        left_first: true,
+    }
+}
+
+pub struct Race2<FutT1, FutT2> {
+    // Both are None once the race is over. Declared in reverse, so fut2 is dropped first.
+    fut2: Option<Pin<Box<FutT2>>>,
+    fut1: Option<Pin<Box<FutT1>>>,
+    left_first: bool,
+}
+
+// Futures are boxed, Race2 does not need pinning.
+impl<FutT1, FutT2> Unpin for Race2<FutT1, FutT2> {}
+
+impl<FutT1, FutT2> Future for Race2<FutT1, FutT2>
+where
+    FutT1: Future,
+    FutT2: Future,
+{
+    type Output = Either<FutT1::Output, FutT2::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let (fut1, fut2) = match (&mut this.fut1, &mut this.fut2) {
+            (Some(fut1), Some(fut2)) => (fut1, fut2),
+            _ => FuturePolledAfterCompletion::panic::<Self>(),
+        };
+
+        let left_first = this.left_first;
+        let poll = if left_first {
+            match fut1.as_mut().poll(ctx) {
+                Poll::Ready(output) => Poll::Ready(Either::Left(output)),
+                Poll::Pending => fut2.as_mut().poll(ctx).map(Either::Right),
+            }
+        } else {
+            match fut2.as_mut().poll(ctx) {
+                Poll::Ready(output) => Poll::Ready(Either::Right(output)),
+                Poll::Pending => fut1.as_mut().poll(ctx).map(Either::Left),
+            }
+        };
+
+        this.left_first = !left_first;
+        if poll.is_ready() {
+            // drops the loser, the second future first
+            this.fut2 = None;
+            this.fut1 = None;
+        }
+        poll
+    }
+}
+
+// Error of Select without branches: it would never complete.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct NoBranches;
+
+// Race of any number of futures with the same output, e.g.
+// `Select::new().add(fut_a).add(fut_b).add(fut_c).await`. Resolves to the index of the winner
+// and its output. Same as race2() the futures are polled in turns starting from the next one on
+// every poll, and the losers are dropped right away (the tasks in them are destroyed with their
+// async cleanup). The losers are dropped in reverse order of add(), like the locals of a scope,
+// and the cleanup of one is completed before the next one is dropped. The futures with different
+// outputs can be mapped to a common type or raced with race2().
+pub struct Select<'f, T> {
+    // Empty once the race is over
+    futures: Vec<Pin<Box<dyn Future<Output = T> + 'f>>>,
+    first: usize,
+    completed: bool,
+    _lifetime: PhantomData<&'f ()>,
+}
+
+impl<'f, T> Select<'f, T> {
+    pub fn new() -> Self {
+        Self {
+            futures: Vec::new(),
+            first: 0,
+            completed: false,
+            _lifetime: PhantomData,
+        }
+    }
+
+    pub fn add<FutT>(mut self, fut: FutT) -> Self
+    where
+        FutT: Future<Output = T> + 'f,
+    {
+        self.futures.push(Box::pin(fut));
+        self
+    }
+
+    // The last added is dropped first, see the drop order above.
+    fn drop_futures(&mut self) {
+        while let Some(fut) = self.futures.pop() {
+            drop(fut);
+        }
+    }
+}
+
+impl<T> Default for Select<'_, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Future for Select<'_, T> {
+    type Output = Result<(usize, T), NoBranches>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if this.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        let count = this.futures.len();
+        if count == 0 {
+            this.completed = true;
+            return Poll::Ready(Err(NoBranches));
+        }
+
+        let first = this.first;
+        this.first = (first + 1) % count;
+        for index in (first..count).chain(0..first) {
+            if let Poll::Ready(output) = this.futures[index].as_mut().poll(ctx) {
+                this.completed = true;
+                this.drop_futures(); // drops the losers
+                return Poll::Ready(Ok((index, output)));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Select<'_, T> {
+    fn drop(&mut self) {
+        self.drop_futures();
+    }
+}
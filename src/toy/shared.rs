@@ -0,0 +1,195 @@
+use super::contract::FuturePolledAfterCompletion;
+use super::reactor::EventId;
+use super::Runtime;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+// Makes a future that can be cloned and awaited by several tasks, every clone resolves to the
+// clone of the output. The clone that polls first drives the inner future, the others wait for
+// the output. The runtime only wakes tasks by reactor events, so the waiting clones have the
+// timers that never fire (same as oneshot::Receiver) and these are replaced with the timers that
+// fire right away once the output is there.
+//
+// If the driving clone is dropped before completion, the next waiting clone takes over. The
+// events the inner future has already registered keep the waker of the former driver, so it only
+// works if both are in the same task or the inner future registers its events on every poll.
+pub fn shared<FutT>(rt: &Rc<Runtime>, fut: FutT) -> SharedFut<FutT>
+where
+    FutT: Future,
+    FutT::Output: Clone,
+{
+    let state = State {
+        fut: Some(Box::pin(fut)),
+        output: None,
+        driver: None,
+        waiters: Vec::new(),
+        last_key: 0,
+    };
+    SharedFut::new(rt, Rc::new(RefCell::new(state)))
+}
+
+struct Waiter {
+    key: u64,
+    event_id: EventId,
+    waker: Waker,
+}
+
+struct State<FutT>
+where
+    FutT: Future,
+{
+    // None while the driver polls it and once completed
+    fut: Option<Pin<Box<FutT>>>,
+    output: Option<FutT::Output>,
+    // The key of the clone that polls the inner future
+    driver: Option<u64>,
+    waiters: Vec<Waiter>,
+    last_key: u64,
+}
+
+impl<FutT> State<FutT>
+where
+    FutT: Future,
+{
+    // Replaces the timers of the waiters with the ones that fire right away.
+    fn wake_waiters(&mut self, rt: &Runtime, count: usize) {
+        for waiter in self.waiters.iter_mut().take(count) {
+            rt.retire_event(waiter.event_id);
+            waiter.event_id = rt.reactor().add_timer(&waiter.waker, Duration::ZERO);
+        }
+    }
+
+    // Stops waiting, the pending timer of the clone is cancelled.
+    fn leave(&mut self, rt: &Runtime, key: u64) {
+        if let Some(pos) = self.waiters.iter().position(|waiter| waiter.key == key) {
+            let waiter = self.waiters.remove(pos);
+            rt.retire_event(waiter.event_id);
+        }
+    }
+}
+
+pub struct SharedFut<FutT>
+where
+    FutT: Future,
+{
+    rt: Rc<Runtime>,
+    state: Rc<RefCell<State<FutT>>>,
+    key: u64,
+    completed: bool,
+}
+
+// The inner future is boxed, SharedFut does not need pinning.
+impl<FutT> Unpin for SharedFut<FutT> where FutT: Future {}
+
+impl<FutT> SharedFut<FutT>
+where
+    FutT: Future,
+{
+    fn new(rt: &Rc<Runtime>, state: Rc<RefCell<State<FutT>>>) -> Self {
+        let key = {
+            let mut state = state.borrow_mut();
+            state.last_key += 1;
+            state.last_key
+        };
+        Self {
+            rt: rt.clone(),
+            state,
+            key,
+            completed: false,
+        }
+    }
+}
+
+impl<FutT> Clone for SharedFut<FutT>
+where
+    FutT: Future,
+{
+    fn clone(&self) -> Self {
+        Self::new(&self.rt, self.state.clone())
+    }
+}
+
+impl<FutT> Future for SharedFut<FutT>
+where
+    FutT: Future,
+    FutT::Output: Clone,
+{
+    type Output = FutT::Output;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        let mut state = self.state.borrow_mut();
+        if state.output.is_none() {
+            match state.driver {
+                None => {
+                    // the former waiter takes over
+                    state.driver = Some(self.key);
+                    state.leave(&self.rt, self.key);
+                }
+                Some(key) if key == self.key => (),
+                Some(_) => {
+                    if !state.waiters.iter().any(|waiter| waiter.key == self.key) {
+                        let waiter = Waiter {
+                            key: self.key,
+                            event_id: self.rt.reactor().add_timer(ctx.waker(), Duration::MAX),
+                            waker: ctx.waker().clone(),
+                        };
+                        state.waiters.push(waiter);
+                    }
+                    return Poll::Pending;
+                }
+            }
+
+            // The inner future is polled without the state borrowed, it can run nested loops
+            // that poll other clones.
+            let mut fut = state
+                .fut
+                .take()
+                .expect("the shared future is polled reentrantly");
+            drop(state);
+            let poll = fut.as_mut().poll(ctx);
+            state = self.state.borrow_mut();
+            match poll {
+                Poll::Ready(output) => {
+                    state.output = Some(output);
+                    state.driver = None;
+                    let count = state.waiters.len();
+                    state.wake_waiters(&self.rt, count);
+                }
+                Poll::Pending => {
+                    state.fut = Some(fut);
+                    return Poll::Pending;
+                }
+            }
+        }
+
+        let output = state.output.clone().unwrap();
+        state.leave(&self.rt, self.key);
+        drop(state);
+        self.completed = true;
+        Poll::Ready(output)
+    }
+}
+
+impl<FutT> Drop for SharedFut<FutT>
+where
+    FutT: Future,
+{
+    fn drop(&mut self) {
+        let mut state = self.state.borrow_mut();
+        state.leave(&self.rt, self.key);
+        if state.driver == Some(self.key) {
+            // hands over the inner future to the first waiter
+            state.driver = None;
+            state.wake_waiters(&self.rt, 1);
+        }
+    }
+}
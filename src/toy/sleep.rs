@@ -8,38 +8,157 @@ use std::marker::PhantomPinned;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, Poll, Waker};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 // Async sleep
 pub async fn sleep(rt: &Rc<Runtime>, duration: Duration) {
     Sleep::new(rt, duration).await
 }
 
+// Async sleep until the given instant, the deadline can be shared by several sleeps so they
+// all awake together.
+pub async fn sleep_until(rt: &Rc<Runtime>, deadline: Instant) {
+    Sleep::until(rt, deadline).await
+}
+
+// Returns Pending once to let the other tasks run and completes on the next poll. Waking the task
+// from its own poll is a contract violation in this runtime, so the task is rescheduled with the
+// timer that fires right away, the loop gets it from wait() like any other event. The yield of a
+// frozen task is dispatched once the task is unfrozen (the event is parked as a frozen one). Same
+// as the zero sleep.
+pub async fn yield_now(rt: &Rc<Runtime>) {
+    Sleep::new(rt, Duration::ZERO).await
+}
+
+// Tells if sleep_remaining() has slept for the whole desired duration.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Slept {
+    Full,
+    // The sleep has been cut by the cleanup deadline, optional cleanup steps can be skipped.
+    Truncated,
+}
+
+// Async sleep for the desired duration, but not past the deadline of the cleanup it runs in (see
+// Runtime::nested_loop_with_deadline()). Outside of such cleanup it is the same as sleep().
+pub async fn sleep_remaining(rt: &Rc<Runtime>, desired: Duration) -> Slept {
+    // the body of async fn runs on the first poll, so this is the deadline of the polling task
+    let deadline = rt.cleanup_deadline();
+    match deadline {
+        Some(deadline)
+            if Instant::now()
+                .checked_add(desired)
+                .is_none_or(|end| deadline < end) =>
+        {
+            sleep_until(rt, deadline).await;
+            Slept::Truncated
+        }
+        _ => {
+            sleep(rt, desired).await;
+            Slept::Full
+        }
+    }
+}
+
+// When the timer should awake: duration is counted from the first poll.
+#[derive(Copy, Clone)]
+enum Wakeup {
+    After(Duration),
+    At(Instant),
+}
+
 #[derive(Copy, Clone)]
 enum PollState {
-    Idle(Duration),
+    Idle(Wakeup),
     Pending(EventId),
     Done,
 }
 
+// The future of sleep() and sleep_until(). Unlike them it can be inspected and its deadline can be
+// moved with reset() while it is pending.
 #[pin_project(PinnedDrop)]
-struct Sleep {
+pub struct Sleep {
     rt: Rc<Runtime>,
     poll_state: PollState,
+    // Known once the timer is scheduled, unless the deadline is given by until()
+    deadline: Option<Instant>,
+    // The waker of the pending timer, reset() schedules the new timer with it
+    waker: Option<Waker>,
+    // The completed sleep is Ready on every poll, these polls are counted rather than reported
+    polls_after_completion: usize,
     _pinned: PhantomPinned,
 }
 
 impl Sleep {
-    fn new(rt: &Rc<Runtime>, duration: Duration) -> Self {
+    pub fn new(rt: &Rc<Runtime>, duration: Duration) -> Self {
+        Self::with_wakeup(rt, Wakeup::After(duration))
+    }
+
+    pub fn until(rt: &Rc<Runtime>, deadline: Instant) -> Self {
+        Self::with_wakeup(rt, Wakeup::At(deadline))
+    }
+
+    fn with_wakeup(rt: &Rc<Runtime>, wakeup: Wakeup) -> Self {
+        let deadline = match wakeup {
+            Wakeup::At(deadline) => Some(deadline),
+            Wakeup::After(_) => None,
+        };
         Self {
             rt: rt.clone(),
-            poll_state: PollState::Idle(duration),
+            poll_state: PollState::Idle(wakeup),
+            deadline,
+            waker: None,
+            polls_after_completion: 0,
             _pinned: PhantomPinned,
         }
     }
 
-    fn schedule(&mut self, duration: Duration, waker: &Waker) -> Poll<()> {
-        self.poll_state = PollState::Pending(self.rt.reactor().add_timer(waker, duration));
+    // None for the sleep() that has not been polled yet (its duration is counted from the first
+    // poll) and for the sleep that never fires.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn is_elapsed(&self) -> bool {
+        matches!(self.poll_state, PollState::Done)
+            || self
+                .deadline
+                .is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    // How many times the sleep has been polled after it returned Poll::Ready (since the last
+    // reset()), it helps to find the combinators that poll their completed children.
+    pub fn polls_after_completion(&self) -> usize {
+        self.polls_after_completion
+    }
+
+    // Moves the deadline: the pending timer is replaced with the new one, the completed sleep is
+    // re-armed and can be awaited again.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+        self.polls_after_completion = 0;
+        self.poll_state = match (self.poll_state, &self.waker) {
+            (PollState::Pending(timer_id), Some(waker)) => {
+                self.cancel(timer_id);
+                PollState::Pending(self.rt.reactor().add_timer_at(waker, deadline))
+            }
+            _ => PollState::Idle(Wakeup::At(deadline)),
+        };
+    }
+
+    // The waker can be a foreign one that a combinator wraps the task waker with, the runtime
+    // re-polls the cleanup task of the loop on such events, see Runtime::run_loop().
+    //
+    // The zero sleep and the past deadline go through the reactor as well: the timer is due
+    // right away, but the task yields once, so a loop of such sleeps does not starve the others.
+    fn schedule(&mut self, wakeup: Wakeup, waker: &Waker) -> Poll<()> {
+        let reactor = self.rt.reactor();
+        let timer_id = match wakeup {
+            Wakeup::After(duration) => reactor.add_timer(waker, duration),
+            Wakeup::At(deadline) => reactor.add_timer_at(waker, deadline),
+        };
+        self.deadline = reactor.timer_deadline(timer_id);
+        self.waker = Some(waker.clone());
+        self.poll_state = PollState::Pending(timer_id);
         Poll::Pending
     }
 
@@ -53,7 +172,7 @@ impl Sleep {
     }
 
     fn cancel(&self, timer_id: EventId) {
-        self.rt.reactor().cancel_timer(timer_id);
+        self.rt.retire_event(timer_id);
     }
 }
 
@@ -64,9 +183,12 @@ impl Future for Sleep {
         let this = self.as_ref().project_ref();
 
         match *this.poll_state {
-            PollState::Idle(duration) => self.schedule(duration, ctx.waker()),
+            PollState::Idle(wakeup) => self.schedule(wakeup, ctx.waker()),
             PollState::Pending(timer_id) => self.complete(timer_id, ctx.waker()),
-            PollState::Done => panic!("polled the completed Sleep future"),
+            PollState::Done => {
+                self.polls_after_completion += 1;
+                Poll::Ready(())
+            }
         }
     }
 }
@@ -76,9 +198,8 @@ impl PinnedDrop for Sleep {
     fn drop(mut self: Pin<&mut Self>) {
         let this = self.as_ref().project_ref();
 
-        match this.poll_state {
-            PollState::Pending(timer_id) => self.cancel(*timer_id),
-            _ => (),
+        if let PollState::Pending(timer_id) = this.poll_state {
+            self.cancel(*timer_id);
         }
     }
 }
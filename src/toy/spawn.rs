@@ -0,0 +1,104 @@
+use super::oneshot::{Receiver, Sender};
+use super::task::{GuardedTask, Task};
+use super::Runtime;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Erases the output type of the tasks owned by the runtime.
+pub(super) trait OwnedTask {
+    fn task(&self) -> &Arc<Task>;
+    fn cancel(&self);
+}
+
+impl<T> OwnedTask for GuardedTask<T> {
+    fn task(&self) -> &Arc<Task> {
+        &self.task
+    }
+
+    fn cancel(&self) {
+        GuardedTask::cancel(self);
+    }
+}
+
+// The task made by Runtime::spawn(), it is owned by the runtime till it completes or its
+// JoinHandle is dropped. The detached tasks (see RtJoin2::detach_second()) are owned the same way,
+// but nobody waits for their output and run() does not return before they complete.
+pub(super) struct Spawned {
+    pub(super) guarded: Box<dyn OwnedTask>,
+    // The first poll is made by the loop, see Runtime::poll_spawned()
+    pub(super) polled: bool,
+    // Dropped with the completed task, that wakes run() waiting for the detached tasks
+    _detached: Option<Sender<()>>,
+}
+
+impl Spawned {
+    pub(super) fn new<T: 'static>(guarded: GuardedTask<T>) -> Self {
+        Self {
+            guarded: Box::new(guarded),
+            polled: false,
+            _detached: None,
+        }
+    }
+
+    pub(super) fn detached<T: 'static>(guarded: GuardedTask<T>, done: Sender<()>) -> Self {
+        Self {
+            _detached: Some(done),
+            ..Self::new(guarded)
+        }
+    }
+
+    pub(super) fn task(&self) -> &Arc<Task> {
+        self.guarded.task()
+    }
+
+    pub(super) fn is_done(&self) -> bool {
+        let task = self.task();
+        task.is_completed() || task.is_aborted()
+    }
+}
+
+// Resolves to the output of the spawned task. Dropping the handle cancels the task.
+pub struct JoinHandle<T> {
+    rt: Rc<Runtime>,
+    task: Arc<Task>,
+    output: Receiver<T>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(rt: &Rc<Runtime>, task: Arc<Task>, output: Receiver<T>) -> Self {
+        Self {
+            rt: rt.clone(),
+            task,
+            output,
+        }
+    }
+
+    // Aborts the spawned task like dropping the handle does, awaiting the handle after that
+    // panics.
+    pub fn abort(&self) {
+        self.rt.cancel_spawned(&self.task);
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.output).poll(ctx) {
+            Poll::Ready(output) => {
+                Poll::Ready(output.expect("the spawned task is aborted before completion"))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        self.rt.cancel_spawned(&self.task);
+    }
+}
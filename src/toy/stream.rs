@@ -0,0 +1,53 @@
+use pin_project::pin_project;
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Source of the items that come over time, e.g. the ticks of Interval. The async counterpart of
+// Iterator, see join_stream().
+pub trait Stream {
+    type Item;
+
+    // Ready(Some(item)) for the next item, Ready(None) once the stream has ended. Same as
+    // Future::poll() the stream that returns Pending has its event registered in reactor.
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+
+    // The stream that ends after the first n items of this one.
+    fn take(self, n: usize) -> Take<Self>
+    where
+        Self: Sized,
+    {
+        Take {
+            stream: self,
+            remaining: n,
+        }
+    }
+}
+
+// See Stream::take(). The source is not polled anymore once n items are taken.
+#[pin_project]
+pub struct Take<StreamT> {
+    #[pin]
+    stream: StreamT,
+    remaining: usize,
+}
+
+impl<StreamT> Stream for Take<StreamT>
+where
+    StreamT: Stream,
+{
+    type Item = StreamT::Item;
+
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if *this.remaining == 0 {
+            return Poll::Ready(None);
+        }
+
+        let next = this.stream.poll_next(ctx);
+        if let Poll::Ready(Some(_)) = next {
+            *this.remaining -= 1;
+        }
+        next
+    }
+}
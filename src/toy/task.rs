@@ -1,25 +1,56 @@
 use std::cell::{Cell, RefCell};
 use std::future::Future;
+use std::panic::Location;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::sync::Arc;
-use std::task::{Context, Poll, Wake};
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::time::Instant;
 
+#[cfg(debug_assertions)]
+use super::contract::ContractCheck;
+use super::metrics::CpuSlot;
 use super::Runtime;
 
+use pin_project::pin_project;
+
 pub(super) enum TaskPoll {
     Pending,
     Ready,
     Frozen,
     Gone,
+    // The task is aborted with Task::abort(), its future is dropped
+    Aborted,
 }
 
-// Helps to destroy task's future in a right time when all references are still valid.
-pub(super) struct GuardedTask {
+// Identifies the task in diagnostics, ids are unique within the runtime.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TaskId(u64);
+
+// Helps to destroy task's future in a right time when all references are still valid. Also
+// keeps the slot where the task stores the output of its future.
+pub(super) struct GuardedTask<T> {
     pub task: Arc<Task>,
+    // Shared with the task, because the task can complete in a nested loop when the owner of
+    // GuardedTask is not polling it.
+    output: Rc<RefCell<Option<T>>>,
+}
+
+impl<T> GuardedTask<T> {
+    // The output of the completed task, it can be taken only once.
+    pub fn take_output(&self) -> Option<T> {
+        self.output.borrow_mut().take()
+    }
+
+    // Aborts the task, see Task::abort().
+    pub fn cancel(&self) {
+        self.task.abort();
+    }
 }
 
-impl Drop for GuardedTask {
+impl<T> Drop for GuardedTask<T> {
     fn drop(&mut self) {
+        // the future is destroyed first, the output slot is dropped after it with other fields
         self.task.destroy();
     }
 }
@@ -30,15 +61,23 @@ impl Drop for GuardedTask {
 //     * nested_loop() also create a task
 //
 //  Task made with a lot of interiour mutability and the one for future has a function sense, e.g.
-//  when borrowed it means that the task is frozen. 
+//  when borrowed it means that the task is frozen.
 pub(super) struct Task {
+    id: TaskId,
     future: RefCell<Option<Pin<Box<dyn Future<Output = ()>>>>>,
     parent: RefCell<Option<Arc<Task>>>,
     awoken_task: Arc<RefCell<Option<Arc<Task>>>>, // this is Runtime::awoken_task
+    polled_tasks: Arc<RefCell<Vec<Arc<Task>>>>,   // this is Runtime::polled_tasks
     completed: Cell<bool>,
+    aborted: Cell<bool>,
+    in_nested_loop: Cell<bool>, // the poll (or drop) of the future runs a nested loop
+    deadline: Cell<Option<Instant>>, // cleanup deadline, only set for nested_loop() tasks
+    name: &'static str,         // type of the future for diagnostics
+    location: &'static Location<'static>, // where the task is created, e.g. make_rt_join2() call
+    cpu_slot: CpuSlot,          // slot in Metrics for poll statistics
 }
 
-// Added these to fix compliation error while working with std::task::Wake. This
+// Added these to fix compliation error while working with the Arc<Task> in Waker. This
 // toy runtime does not use threads and Task is not public, so unsafe should be ok.
 unsafe impl Sync for Task {}
 unsafe impl Send for Task {}
@@ -50,54 +89,160 @@ impl Task {
     // to the caller to ensure that allocated task object does not outlive the 'f, e.g.
     // objects referenced in the futures. This unsafeness is not exposed to app, it should be
     // internal thing.
-    pub(super) unsafe fn allocate<'f, FutT>(rt: &Runtime, f: FutT) -> GuardedTask
+    //
+    // The public APIs that create tasks are #[track_caller], so the location of the task is the
+    // app code that has called them.
+    #[track_caller]
+    pub(super) unsafe fn allocate<'f, FutT>(rt: &Runtime, f: FutT) -> GuardedTask<FutT::Output>
+    where
+        FutT: Future + 'f,
+    {
+        Self::allocate_at(rt, f, Location::caller())
+    }
+
+    // Same as allocate() for the task that is created later than the public API is called, e.g.
+    // from poll.
+    pub(super) unsafe fn allocate_at<'f, FutT>(
+        rt: &Runtime,
+        f: FutT,
+        location: &'static Location<'static>,
+    ) -> GuardedTask<FutT::Output>
     where
-        FutT: Future<Output = ()> + 'f,
+        FutT: Future + 'f,
     {
+        let name = std::any::type_name::<FutT>();
+        let id = rt.last_task_id.get() + 1;
+        rt.last_task_id.set(id);
+        let output = Rc::new(RefCell::new(None));
+        let f = StoreOutput {
+            fut: f,
+            output: output.clone(),
+        };
+
+        // In debug builds the runtime verifies the task future is never polled after completion
+        #[cfg(debug_assertions)]
+        let f = ContractCheck::new(f);
+
         // Make the box and erase lifetime
         let boxed_f: Pin<Box<dyn Future<Output = ()> + 'f>> = Box::pin(f);
         let boxed_f: Pin<Box<dyn Future<Output = ()> + 'static>> = std::mem::transmute(boxed_f);
 
         GuardedTask {
+            output,
             task: Arc::new(Self {
+                id: TaskId(id),
                 future: RefCell::new(Some(boxed_f)),
                 awoken_task: rt.awoken_task.clone(),
+                polled_tasks: rt.polled_tasks.clone(),
                 parent: RefCell::new(None),
                 completed: Cell::new(false),
+                aborted: Cell::new(false),
+                in_nested_loop: Cell::new(false),
+                deadline: Cell::new(None),
+                name,
+                location,
+                cpu_slot: rt.metrics().register_task(name, location),
             }),
         }
     }
 
-    // destroy is used to drop the future in the task, e.g. when leaving nested_loop we have to 
+    // destroy is used to drop the future in the task, e.g. when leaving nested_loop we have to
     // be sure that future is finished.
     pub fn destroy(&self) {
         // panics if self.future is already borrowed: it should never happens unless there is
         // a bug in crate.
-        *self.future.borrow_mut() = None; // drop the future
+        self.drop_future(&mut self.future.borrow_mut());
         *self.parent.borrow_mut() = None; // dec counter for parent
     }
 
+    // The drop of the future can run nested loops, the task is legitimately frozen by them.
+    fn drop_future(&self, future: &mut Option<Pin<Box<dyn Future<Output = ()>>>>) {
+        let in_nested_loop = self.in_nested_loop.replace(true);
+        *future = None;
+        self.in_nested_loop.set(in_nested_loop);
+    }
+
+    // Marks the task as the one that runs a nested loop from its poll, returns the previous
+    // mark. See run_nested_loop().
+    pub fn set_in_nested_loop(&self, in_nested_loop: bool) -> bool {
+        self.in_nested_loop.replace(in_nested_loop)
+    }
+
+    // The task is frozen legitimately when it or one of its ancestors is running a nested loop.
+    // Otherwise the task is polled while it is being polled already.
+    #[cfg(debug_assertions)]
+    fn is_frozen_by_nested_loop(self: &Arc<Self>) -> bool {
+        let mut task = self.clone();
+        loop {
+            if task.in_nested_loop.get() {
+                return true;
+            }
+            let parent = task.parent.borrow().clone();
+            match parent {
+                Some(parent) => task = parent,
+                None => return false,
+            }
+        }
+    }
+
+    // Drops the future of the task, so its events are cancelled, and the later polls return
+    // Aborted. The frozen task cannot be aborted right away: its future is dropped when the poll
+    // that is running its nested loop returns.
+    pub fn abort(&self) {
+        self.aborted.set(true);
+        if let Ok(mut future) = self.future.try_borrow_mut() {
+            // the future is dropped with the borrow held, so the task is frozen by its drop
+            self.drop_future(&mut future);
+        }
+    }
+
+    // Makes the task a root one, its events are dispatched to it rather than to the former parent.
+    pub fn detach_parent(&self) {
+        *self.parent.borrow_mut() = None;
+    }
+
     // Assigns parent to task
     fn assign_parent(&self, parent_context: Option<&mut Context<'_>>) {
-        if parent_context.is_some() {
+        if let Some(parent_context) = parent_context {
             let mut parent = self.parent.borrow_mut();
-            parent.get_or_insert(self.current_task(parent_context.unwrap()));
+            parent.get_or_insert(self.current_task(parent_context));
         }
     }
 
-    // Extracts task from Context
+    // Extracts task from Context. The waker of a combinator is not the task one, but the task
+    // that polls the child is the innermost one being polled.
     fn current_task(&self, ctx: &mut Context<'_>) -> Arc<Task> {
-        // By invoking wake() we have Arc<Task> written to self.awoken_task.
-        ctx.waker().clone().wake();
-        self.awoken_task.borrow_mut().take().unwrap().clone()
+        Task::from_waker(ctx.waker())
+            .or_else(|| self.polled_tasks.borrow().last().cloned())
+            .expect("the child task is polled outside of a task")
+    }
+
+    // The waker that makes the runtime poll the task, see TASK_WAKER_VTABLE.
+    pub fn waker(self: &Arc<Self>) -> Waker {
+        let data = Arc::into_raw(self.clone()) as *const ();
+        unsafe { Waker::from_raw(RawWaker::new(data, &TASK_WAKER_VTABLE)) }
+    }
+
+    // The task of the waker, None for a foreign one (e.g. the waker of a combinator). The task is
+    // recognized by the vtable, it is not woken.
+    pub fn from_waker(waker: &Waker) -> Option<Arc<Task>> {
+        if !std::ptr::eq(waker.vtable(), &TASK_WAKER_VTABLE) {
+            return None;
+        }
+        let data = waker.data() as *const Task;
+        unsafe {
+            Arc::increment_strong_count(data);
+            Some(Arc::from_raw(data))
+        }
+    }
+
+    fn wake(self: &Arc<Self>) {
+        *(self.awoken_task.borrow_mut()) = Some(self.clone());
     }
 
     // If current task is frozen
     pub fn is_frozen(&self) -> bool {
-        match self.future.try_borrow_mut() {
-            Err(_) => true,
-            Ok(_) => false,
-        }
+        self.future.try_borrow_mut().is_err()
     }
 
     // Polls a root task, e.g.  the task without parent. Root task is created by run() or
@@ -115,8 +260,24 @@ impl Task {
     // Shared impl of poll for poll() and poll_child().
     fn poll_impl(self: &Arc<Self>, parent_context: Option<&mut Context<'_>>) -> TaskPoll {
         match self.future.try_borrow_mut() {
-            Err(_) => TaskPoll::Frozen,
+            Err(_) => {
+                #[cfg(debug_assertions)]
+                if !self.is_frozen_by_nested_loop() {
+                    panic!(
+                        "reentrant poll: task `{}` created at {} is polled while it is being \
+                         polled already, but it is not frozen by nested_loop()",
+                        self.name, self.location
+                    );
+                }
+                TaskPoll::Frozen
+            }
             Ok(mut future) => {
+                if self.aborted.get() {
+                    // the abort of the frozen task is completed here
+                    self.drop_future(&mut future);
+                    return TaskPoll::Aborted;
+                }
+
                 if future.is_none() {
                     // Future is out out scope and had been deleteded. This must be some
                     // some call from frozen_event array.
@@ -136,9 +297,34 @@ impl Task {
                 // multiple roots can be created by nested_loop().
                 self.assign_parent(parent_context);
 
-                let waker = self.clone().into();
+                let waker = self.waker();
                 let mut ctx = Context::from_waker(&waker);
-                match future.as_mut().unwrap().as_mut().poll(&mut ctx) {
+                let depth = {
+                    let mut polled_tasks = self.polled_tasks.borrow_mut();
+                    polled_tasks.push(self.clone());
+                    polled_tasks.len() - 1
+                };
+                let poll = future.as_mut().unwrap().as_mut().poll(&mut ctx);
+                // truncated rather than popped, it heals if a poll inside has unwound
+                self.polled_tasks.borrow_mut().truncate(depth);
+
+                // A wake outside of the reactor is lost: the runtime only polls the tasks the
+                // reactor events are for, and the runtime itself never leaves a wake behind.
+                #[cfg(debug_assertions)]
+                if let Some(woken) = self.awoken_task.borrow_mut().take() {
+                    panic!(
+                        "contract violation: task `{}` created at {} has woken task `{}` from \
+                         poll, but this runtime only wakes tasks by reactor events",
+                        self.name, self.location, woken.name
+                    );
+                }
+
+                match poll {
+                    // aborted by its own poll, e.g. from a nested loop
+                    _ if self.aborted.get() => {
+                        self.drop_future(&mut future);
+                        TaskPoll::Aborted
+                    }
                     Poll::Ready(()) => {
                         self.completed.set(true);
                         TaskPoll::Ready
@@ -151,22 +337,99 @@ impl Task {
 
     // Find a closest unfronzen parent
     pub fn first_unfrozen_parent(self: &Arc<Self>) -> Arc<Self> {
-        if self.is_frozen() || self.parent.borrow().is_none() {
-            // 1. When task has no parent we can only return self
-            // 2. When the task is frozen parents must have been frozen as well, so return
-            //    self.
-            self.clone()
-        } else {
-            let parent = self.parent.borrow();
-            let parent = parent.as_ref().unwrap();
-            if parent.is_frozen() {
-                self.clone()
-            } else {
-                parent.first_unfrozen_parent()
+        // a loop rather than recursion, the task trees can be deep
+        let mut task = self.clone();
+        loop {
+            if task.is_frozen() {
+                // When the task is frozen parents must have been frozen as well
+                return task;
+            }
+            let parent = task.parent.borrow().clone();
+            match parent {
+                Some(parent) if !parent.is_frozen() => task = parent,
+                // the root or the child of the frozen task
+                _ => return task,
+            }
+        }
+    }
+
+    // The closest frozen task in the chain of the task and its ancestors
+    pub fn first_frozen_ancestor(self: &Arc<Self>) -> Option<Arc<Self>> {
+        let mut task = self.clone();
+        loop {
+            if task.is_frozen() {
+                return Some(task);
+            }
+            let parent = task.parent.borrow().clone();
+            task = parent?;
+        }
+    }
+
+    // Returns the root of the task tree this task belongs to
+    pub fn root(self: &Arc<Self>) -> Arc<Self> {
+        let mut task = self.clone();
+        loop {
+            let parent = task.parent.borrow().clone();
+            match parent {
+                Some(parent) => task = parent,
+                None => return task,
+            }
+        }
+    }
+
+    // If the task is the ancestor or the task itself
+    pub fn is_descendant_of(self: &Arc<Self>, ancestor: &Arc<Task>) -> bool {
+        let mut task = self.clone();
+        loop {
+            if Arc::ptr_eq(&task, ancestor) {
+                return true;
+            }
+            let parent = task.parent.borrow().clone();
+            match parent {
+                Some(parent) => task = parent,
+                None => return false,
             }
         }
     }
 
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    // The parent is assigned on the first poll of the child task
+    pub fn parent(&self) -> Option<Arc<Task>> {
+        self.parent.borrow().clone()
+    }
+
+    pub fn parent_id(&self) -> Option<TaskId> {
+        self.parent.borrow().as_ref().map(|parent| parent.id)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    pub fn cpu_slot(&self) -> &CpuSlot {
+        &self.cpu_slot
+    }
+
+    pub fn set_deadline(&self, deadline: Option<Instant>) {
+        self.deadline.set(deadline);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.get()
+    }
+
+    // If the task is aborted and its future is already dropped
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.get() && !self.is_frozen()
+    }
+
     // If future had poll with Poll::Ready
     pub fn is_completed(&self) -> bool {
         match self.future.try_borrow() {
@@ -176,9 +439,55 @@ impl Task {
     }
 }
 
-// This is how this runtime implement Waker
-impl Wake for Task {
-    fn wake(self: Arc<Self>) {
-        *(self.awoken_task.borrow_mut()) = Some(self.clone());
+// This is how this runtime implement Waker: the data is Arc<Task>. The vtable is made by hand
+// rather than with std::task::Wake, so the runtime can tell its wakers by the address of the
+// vtable and get the task without waking it, see Task::from_waker().
+static TASK_WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake_by_ref, waker_drop);
+
+unsafe fn waker_clone(data: *const ()) -> RawWaker {
+    Arc::increment_strong_count(data as *const Task);
+    RawWaker::new(data, &TASK_WAKER_VTABLE)
+}
+
+unsafe fn waker_wake(data: *const ()) {
+    Arc::from_raw(data as *const Task).wake();
+}
+
+unsafe fn waker_wake_by_ref(data: *const ()) {
+    let task = std::mem::ManuallyDrop::new(Arc::from_raw(data as *const Task));
+    task.wake();
+}
+
+unsafe fn waker_drop(data: *const ()) {
+    drop(Arc::from_raw(data as *const Task));
+}
+
+// Task future that keeps the output where GuardedTask can take it.
+#[pin_project]
+struct StoreOutput<FutT>
+where
+    FutT: Future,
+{
+    #[pin]
+    fut: FutT,
+    output: Rc<RefCell<Option<FutT::Output>>>,
+}
+
+impl<FutT> Future for StoreOutput<FutT>
+where
+    FutT: Future,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        match this.fut.poll(ctx) {
+            Poll::Ready(output) => {
+                *this.output.borrow_mut() = Some(output);
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
     }
 }
@@ -0,0 +1,223 @@
+use super::stream::Stream;
+use super::task::{GuardedTask, Task};
+use super::Runtime;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::panic::Location;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+// Set of tasks that can grow while it runs, e.g. a handler task per accepted connection. Awaiting
+// the set (by &mut, so more tasks can be pushed later) polls all its tasks like RtJoinAll and
+// completes when there are no tasks left. The completed tasks are removed from the set right away.
+//
+// The task gets its parent on the first poll, so the tasks pushed after the set has been polled
+// are children of the task that polls the set as well.
+pub struct RtTaskSet<'f> {
+    tasks: Vec<GuardedTask<()>>,
+
+    // Same as in RtJoin2: the set must not outlive the futures pushed into it
+    _lifetime: PhantomData<&'f ()>,
+}
+
+impl<'f> RtTaskSet<'f> {
+    pub fn new() -> Self {
+        Self {
+            tasks: Vec::new(),
+            _lifetime: PhantomData,
+        }
+    }
+
+    #[track_caller]
+    pub fn push<FutT>(&mut self, rt: &Rc<Runtime>, fut: FutT)
+    where
+        FutT: Future<Output = ()> + 'f,
+    {
+        self.push_at(rt, fut, Location::caller());
+    }
+
+    fn push_at<FutT>(&mut self, rt: &Rc<Runtime>, fut: FutT, location: &'static Location<'static>)
+    where
+        FutT: Future<Output = ()> + 'f,
+    {
+        self.tasks
+            .push(unsafe { Task::allocate_at(rt, fut, location) });
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
+
+impl Default for RtTaskSet<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Future for RtTaskSet<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Some of the tasks could have been completed by nested loops, they are not polled again
+        for guarded in self.tasks.iter() {
+            if !guarded.task.is_completed() {
+                guarded.task.poll_child(ctx);
+            }
+        }
+
+        // The completed task is not frozen, so it can be destroyed
+        self.tasks.retain(|guarded| !guarded.task.is_completed());
+        match self.tasks.is_empty() {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+}
+
+// Error of for_each_concurrent() with zero limit: no future would ever run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ZeroLimit;
+
+// Runs the futures as tasks, but no more than limit of them at a time: a new task is started each
+// time one completes, so there are no more than limit tasks (and their timers) alive. Completes
+// when all the futures are done. With the limit not less than the number of futures it is the
+// same as make_rt_join_all().
+#[track_caller]
+pub fn for_each_concurrent<'f, IterT>(
+    rt: &Rc<Runtime>,
+    limit: usize,
+    futures: IterT,
+) -> ForEachConcurrent<'f, IterT::IntoIter>
+where
+    IterT: IntoIterator,
+    IterT::Item: Future<Output = ()> + 'f,
+{
+    ForEachConcurrent {
+        rt: rt.clone(),
+        limit,
+        futures: futures.into_iter(),
+        set: RtTaskSet::new(),
+        location: Location::caller(),
+    }
+}
+
+pub struct ForEachConcurrent<'f, IterT>
+where
+    IterT: Iterator,
+{
+    rt: Rc<Runtime>,
+    limit: usize,
+    // The futures that have not been started yet
+    futures: IterT,
+    set: RtTaskSet<'f>,
+    // Where for_each_concurrent() is called, the location of all its tasks
+    location: &'static Location<'static>,
+}
+
+// The tasks are allocated in heap, the iterator is never pinned.
+impl<IterT> Unpin for ForEachConcurrent<'_, IterT> where IterT: Iterator {}
+
+impl<'f, IterT> Future for ForEachConcurrent<'f, IterT>
+where
+    IterT: Iterator,
+    IterT::Item: Future<Output = ()> + 'f,
+{
+    type Output = Result<(), ZeroLimit>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if this.limit == 0 {
+            return Poll::Ready(Err(ZeroLimit));
+        }
+
+        loop {
+            let mut started = false;
+            while this.set.len() < this.limit {
+                match this.futures.next() {
+                    Some(fut) => this.set.push_at(&this.rt, fut, this.location),
+                    None => break,
+                }
+                started = true;
+            }
+
+            // the new tasks have to be polled to register their events
+            let poll = Pin::new(&mut this.set).poll(ctx);
+            if !started || this.set.len() == this.limit {
+                return poll.map(Ok);
+            }
+        }
+    }
+}
+
+// Runs handler(item) as a task for every item of the stream, e.g. a task per accepted connection.
+// The stream is polled together with the handler tasks, so new tasks start while the others run.
+// Completes once the stream has ended and all the handler tasks are done.
+#[track_caller]
+pub fn join_stream<'f, StreamT, HandlerFn, FutT>(
+    rt: &Rc<Runtime>,
+    stream: StreamT,
+    handler: HandlerFn,
+) -> JoinStream<'f, StreamT, HandlerFn>
+where
+    StreamT: Stream,
+    HandlerFn: FnMut(StreamT::Item) -> FutT,
+    FutT: Future<Output = ()> + 'f,
+{
+    JoinStream {
+        rt: rt.clone(),
+        stream: Some(Box::pin(stream)),
+        handler,
+        set: RtTaskSet::new(),
+        location: Location::caller(),
+    }
+}
+
+pub struct JoinStream<'f, StreamT, HandlerFn> {
+    rt: Rc<Runtime>,
+    // None once the stream has ended
+    stream: Option<Pin<Box<StreamT>>>,
+    handler: HandlerFn,
+    set: RtTaskSet<'f>,
+    // Where join_stream() is called, the location of all its tasks
+    location: &'static Location<'static>,
+}
+
+// The stream and the tasks are allocated in heap, JoinStream does not need pinning.
+impl<StreamT, HandlerFn> Unpin for JoinStream<'_, StreamT, HandlerFn> {}
+
+impl<'f, StreamT, HandlerFn, FutT> Future for JoinStream<'f, StreamT, HandlerFn>
+where
+    StreamT: Stream,
+    HandlerFn: FnMut(StreamT::Item) -> FutT,
+    FutT: Future<Output = ()> + 'f,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        while let Some(stream) = this.stream.as_mut() {
+            match stream.as_mut().poll_next(ctx) {
+                Poll::Ready(Some(item)) => {
+                    let fut = (this.handler)(item);
+                    this.set.push_at(&this.rt, fut, this.location);
+                }
+                Poll::Ready(None) => this.stream = None,
+                Poll::Pending => break,
+            }
+        }
+
+        // The handler that runs a nested loop from this poll is not completed when the loop is
+        // done, it stays in the set, so the stream that has ended waits for it.
+        let set = Pin::new(&mut this.set).poll(ctx);
+        match this.stream {
+            Some(_) => Poll::Pending,
+            None => set,
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use super::async_drop::AsyncDrop;
+use super::contract::FuturePolledAfterCompletion;
+use super::sleep::Sleep;
+use super::task::{GuardedTask, Task};
+use super::Runtime;
+
+use pin_project::pin_project;
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// Error returned by Timeout when the inner future has not completed in time.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Elapsed;
+
+// Runs the future but gives up after the duration: the future is dropped with its pending timers.
+// Same as `fut.timeout(rt, duration)` of ToyFutureExt.
+pub async fn timeout<FutT>(
+    rt: &Rc<Runtime>,
+    duration: Duration,
+    fut: FutT,
+) -> Result<FutT::Output, Elapsed>
+where
+    FutT: Future,
+{
+    Timeout::new(fut, Sleep::new(rt, duration)).await
+}
+
+// Same as timeout() but with absolute deadline, see ToyFutureExt::timeout_at(). With the deadline
+// passed already the inner future is still polled once, so it can complete if it is ready.
+pub async fn timeout_at<FutT>(
+    rt: &Rc<Runtime>,
+    deadline: Instant,
+    fut: FutT,
+) -> Result<FutT::Output, Elapsed>
+where
+    FutT: Future,
+{
+    Timeout::new(fut, Sleep::until(rt, deadline)).await
+}
+
+// The overall time budget that is passed through the call chain, so the nested operations share
+// it: `toy::timeout_at(&rt, deadline.instant(), operation)`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub fn at(instant: Instant) -> Self {
+        Self(instant)
+    }
+
+    pub fn after(duration: Duration) -> Self {
+        Self(Instant::now() + duration)
+    }
+
+    pub fn instant(&self) -> Instant {
+        self.0
+    }
+
+    // None once the deadline has passed
+    pub fn remaining(&self) -> Option<Duration> {
+        self.0
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub fn has_passed(&self) -> bool {
+        self.0 <= Instant::now()
+    }
+}
+
+// Future that races the inner future against a sleep. It is made by ToyFutureExt::timeout() and
+// ToyFutureExt::timeout_at(). Inner future is polled first, so if both are ready it wins. On
+// timeout the inner future is dropped with Timeout, so its pending timers are cancelled.
+#[pin_project]
+pub struct Timeout<FutT>
+where
+    FutT: Future,
+{
+    #[pin]
+    fut: FutT,
+    #[pin]
+    sleep: Sleep,
+    completed: bool,
+}
+
+impl<FutT> Timeout<FutT>
+where
+    FutT: Future,
+{
+    pub(super) fn new(fut: FutT, sleep: Sleep) -> Self {
+        Self {
+            fut,
+            sleep,
+            completed: false,
+        }
+    }
+}
+
+impl<FutT> Future for Timeout<FutT>
+where
+    FutT: Future,
+{
+    type Output = Result<FutT::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        // the completed sleep stays Ready, so the misuse is caught here
+        if *this.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        let poll = match this.fut.poll(ctx) {
+            Poll::Ready(output) => Poll::Ready(Ok(output)),
+            Poll::Pending => this.sleep.poll(ctx).map(|()| Err(Elapsed)),
+        };
+        *this.completed = poll.is_ready();
+        poll
+    }
+}
+
+// Same as Timeout, but on timeout the inner future is not just dropped: its async drop is driven
+// to completion in a nested loop before Err(Elapsed) is returned. Made by
+// ToyFutureExt::timeout_with_cleanup().
+#[pin_project]
+pub struct TimeoutWithCleanup<FutT>
+where
+    FutT: Future + AsyncDrop,
+{
+    #[pin]
+    fut: FutT,
+    #[pin]
+    sleep: Sleep,
+    rt: Rc<Runtime>,
+    completed: bool,
+}
+
+impl<FutT> TimeoutWithCleanup<FutT>
+where
+    FutT: Future + AsyncDrop,
+{
+    pub(super) fn new(rt: &Rc<Runtime>, fut: FutT, sleep: Sleep) -> Self {
+        Self {
+            fut,
+            sleep,
+            rt: rt.clone(),
+            completed: false,
+        }
+    }
+}
+
+impl<FutT> Future for TimeoutWithCleanup<FutT>
+where
+    FutT: Future + AsyncDrop,
+{
+    type Output = Result<FutT::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+
+        if *this.completed {
+            FuturePolledAfterCompletion::panic::<Self>();
+        }
+
+        if let Poll::Ready(output) = this.fut.as_mut().poll(ctx) {
+            *this.completed = true;
+            return Poll::Ready(Ok(output));
+        }
+
+        match this.sleep.poll(ctx) {
+            Poll::Ready(()) => {
+                this.rt.nested_loop(this.fut.async_drop());
+                *this.completed = true;
+                Poll::Ready(Err(Elapsed))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Same as timeout(), but the future runs as a task: on timeout the task is destroyed, so the async
+// cleanup of the future (the nested loops of the drops in it) is completed before Err(Elapsed) is
+// returned. The deadline that passes while the task runs a nested loop from its poll is noticed
+// once that poll returns.
+#[track_caller]
+pub fn timeout_rt<'f, FutT>(rt: &Rc<Runtime>, duration: Duration, fut: FutT) -> RtTimeout<FutT>
+where
+    FutT: Future + 'f,
+{
+    RtTimeout {
+        task: Some(unsafe { Task::allocate(rt, fut) }),
+        sleep: Box::pin(Sleep::new(rt, duration)),
+        elapsed: false,
+        _lifetime: PhantomData,
+    }
+}
+
+pub struct RtTimeout<FutT>
+where
+    FutT: Future,
+{
+    // None once completed
+    task: Option<GuardedTask<FutT::Output>>,
+    sleep: Pin<Box<Sleep>>,
+    elapsed: bool,
+
+    // Same as in RtJoin2
+    _lifetime: PhantomData<FutT>,
+}
+
+// The task and the sleep are allocated in heap, RtTimeout does not need pinning.
+impl<FutT> Unpin for RtTimeout<FutT> where FutT: Future {}
+
+impl<FutT> RtTimeout<FutT>
+where
+    FutT: Future,
+{
+    // The task is frozen only while its own poll, made from the poll of RtTimeout, runs a nested
+    // loop, so it is never frozen here and is destroyed right away.
+    fn finish(&mut self) -> Poll<<Self as Future>::Output> {
+        self.task = None;
+        Poll::Ready(Err(Elapsed))
+    }
+}
+
+impl<FutT> Future for RtTimeout<FutT>
+where
+    FutT: Future,
+{
+    type Output = Result<FutT::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let task = match &this.task {
+            Some(guarded) => guarded.task.clone(),
+            None => FuturePolledAfterCompletion::panic::<Self>(),
+        };
+
+        if !this.elapsed {
+            // The sleep is polled first, so its timer is started before the task runs a nested
+            // loop from its poll. The task still wins if both are ready.
+            let expired = this.sleep.as_mut().poll(ctx).is_ready();
+            // the task could have been completed by a nested loop
+            if !task.is_completed() {
+                task.poll_child(ctx);
+            }
+            if let Some(output) = this.task.as_ref().and_then(GuardedTask::take_output) {
+                this.task = None;
+                return Poll::Ready(Ok(output));
+            }
+            this.elapsed = expired;
+        }
+
+        match this.elapsed {
+            true => this.finish(),
+            false => Poll::Pending,
+        }
+    }
+}
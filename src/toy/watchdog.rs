@@ -0,0 +1,136 @@
+use super::reactor::EventId;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// What to do with the nested loop the watchdog has reported.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WatchdogVerdict {
+    // Keep waiting for the cleanup to complete.
+    Continue,
+    // Destroy the cleanup future and return from nested_loop().
+    Cancel,
+}
+
+// The nested loop that has not completed its cleanup in time, see Runtime::set_watchdog().
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct WatchdogReport {
+    // Reactor events the nested loop has processed so far
+    pub(super) wakes: u32,
+    pub(super) elapsed: Duration,
+    // Nesting depth of the loop, 1 is the loop started from the task of block_on().
+    pub(super) depth: u32,
+    // Type of the cleanup future
+    pub(super) task_name: &'static str,
+    // Events the cleanup task and its child tasks are waiting for.
+    pub(super) waiting_on: Vec<EventId>,
+    // Tree of the tasks the runtime knows about at the moment, the frozen ones are marked.
+    pub(super) task_dump: String,
+    // Dump of the flight recorder: the last runtime events before the report.
+    pub(super) history: String,
+}
+
+impl WatchdogReport {
+    pub fn wakes(&self) -> u32 {
+        self.wakes
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn task_name(&self) -> &'static str {
+        self.task_name
+    }
+
+    pub fn waiting_on(&self) -> &[EventId] {
+        &self.waiting_on
+    }
+
+    pub fn task_dump(&self) -> &str {
+        &self.task_dump
+    }
+
+    pub fn history(&self) -> &str {
+        &self.history
+    }
+}
+
+type Callback = dyn Fn(&WatchdogReport) -> WatchdogVerdict;
+
+struct Settings {
+    max_wakes: u32,
+    max_duration: Duration,
+    callback: Box<Callback>,
+}
+
+pub(super) struct Watchdog {
+    settings: RefCell<Option<Rc<Settings>>>,
+}
+
+// Watchdog state for a single nested loop.
+pub(super) struct LoopWatch {
+    started_on: Instant,
+    wakes: u32,
+    reported: bool,
+}
+
+impl Watchdog {
+    pub(super) fn new() -> Self {
+        Self {
+            settings: RefCell::new(None),
+        }
+    }
+
+    pub(super) fn set<CallbackFn>(&self, max_wakes: u32, max_duration: Duration, cb: CallbackFn)
+    where
+        CallbackFn: Fn(&WatchdogReport) -> WatchdogVerdict + 'static,
+    {
+        *self.settings.borrow_mut() = Some(Rc::new(Settings {
+            max_wakes,
+            max_duration,
+            callback: Box::new(cb),
+        }));
+    }
+
+    pub(super) fn watch(&self) -> LoopWatch {
+        LoopWatch {
+            started_on: Instant::now(),
+            wakes: 0,
+            reported: false,
+        }
+    }
+
+    // Counts the reactor wake processed by the nested loop. Once the loop exceeds the limits
+    // the report is made and the callback decides the loop fate. A loop is reported only once.
+    pub(super) fn on_wake<ReportFn>(
+        &self,
+        watch: &mut LoopWatch,
+        report: ReportFn,
+    ) -> WatchdogVerdict
+    where
+        ReportFn: FnOnce(u32, Duration) -> WatchdogReport,
+    {
+        watch.wakes += 1;
+
+        let settings = self.settings.borrow().clone();
+        let settings = match settings {
+            Some(settings) if !watch.reported => settings,
+            _ => return WatchdogVerdict::Continue,
+        };
+
+        let elapsed = watch.started_on.elapsed();
+        if watch.wakes < settings.max_wakes && elapsed < settings.max_duration {
+            return WatchdogVerdict::Continue;
+        }
+
+        watch.reported = true;
+        (settings.callback)(&report(watch.wakes, elapsed))
+    }
+}